@@ -18,6 +18,8 @@ pub struct Disc {
     image: Box<Image>,
     /// Disc serial number
     serial: SerialNumber,
+    /// Absolute position of the lead-out
+    lead_out: Msf,
 }
 
 impl Disc {
@@ -31,14 +33,30 @@ impl Disc {
                 }
             };
 
+        let lead_out = find_lead_out(&mut *image);
+
         let disc = Disc {
             image: image,
             serial: serial,
+            lead_out: lead_out,
         };
 
         Ok(disc)
     }
 
+    /// Build a disc from `image` without looking for its serial
+    /// number. Used by the tests to insert synthetic images.
+    #[cfg(test)]
+    pub fn from_test_image(mut image: Box<Image>) -> Disc {
+        let lead_out = find_lead_out(&mut *image);
+
+        Disc {
+            image: image,
+            serial: SerialNumber::dummy(),
+            lead_out: lead_out,
+        }
+    }
+
     pub fn region(&self) -> Region {
         // For now I prefer to panic to catch potential issues with
         // the serial number handling code, alternatively we could
@@ -56,6 +74,23 @@ impl Disc {
     pub fn image(&mut self) -> &mut Image {
         &mut*self.image
     }
+
+    /// Return the absolute position of the first sector of `track`
+    pub fn track_start(&self, track: Bcd) -> Result<Msf, CdError> {
+        self.image.track_msf(track, Msf::zero())
+    }
+
+    /// Return the number of the last track on the disc. PlayStation
+    /// discs always start with track 01.
+    pub fn last_track(&self) -> Bcd {
+        last_track(&*self.image)
+    }
+
+    /// Return the absolute position of the lead-out, i.e. the first
+    /// sector past the end of the last track
+    pub fn lead_out(&self) -> Msf {
+        self.lead_out
+    }
 }
 
 impl Encodable for Disc {
@@ -73,10 +108,64 @@ impl Decodable for Disc {
         Ok(Disc {
             image: Box::new(MissingImage),
             serial: serial,
+            lead_out: Msf::zero(),
         })
     }
 }
 
+/// Return the number of the last track of `image`
+fn last_track(image: &Image) -> Bcd {
+    let mut last = Bcd::one();
+
+    for t in 2..100 {
+        let track = Bcd::from_binary(t).unwrap();
+
+        if image.track_msf(track, Msf::zero()).is_err() {
+            break;
+        }
+
+        last = track;
+    }
+
+    last
+}
+
+/// Find the lead-out of `image`. The image doesn't give us the track
+/// lengths so we look for the end of the last track with a binary
+/// search, assuming that reading past the end of the image fails.
+/// This takes a couple dozen reads so it's only done once when the
+/// disc is loaded.
+fn find_lead_out(image: &mut Image) -> Msf {
+    let last = last_track(image);
+
+    let mut sector = Sector::empty();
+
+    // Last sector known to be readable
+    let mut first =
+        match image.track_msf(last, Msf::zero()) {
+            Ok(m) => m.sector_index(),
+            Err(_) => 0,
+        };
+
+    // First sector known to be past the end (99:59:74 is the
+    // highest MSF value)
+    let mut end = 100 * 60 * 75 - 1;
+
+    while end - first > 1 {
+        let mid = first + (end - first) / 2;
+
+        let msf = Msf::from_sector_index(mid).unwrap();
+
+        if image.read_sector(&mut sector, msf).is_ok() {
+            first = mid;
+        } else {
+            end = mid;
+        }
+    }
+
+    Msf::from_sector_index(end).unwrap()
+}
+
 /// Dummy Image implemementation used when deserializing a Disc. Since
 /// we don't want to store the entire disc in the image it will be
 /// missing after a load, it's up to the frontend to make sure to
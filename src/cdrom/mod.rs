@@ -15,6 +15,8 @@
 //! based on No$'s specs, mednafen's source code and some educated
 //! guesses.
 
+use std::collections::VecDeque;
+
 use memory::Addressable;
 use timekeeper::{Peripheral, Cycles};
 use interrupt::Interrupt;
@@ -22,12 +24,15 @@ use shared::SharedState;
 use arrayvec::ArrayVec;
 use cdimage::sector::Sector;
 use cdimage::msf::Msf;
+use cdimage::bcd::Bcd;
 
 use self::disc::{Disc, Region};
 use self::simple_rand::SimpleRand;
+use self::xa::XaDecoder;
 
 pub mod disc;
 pub mod iso9660;
+pub mod xa;
 
 mod simple_rand;
 
@@ -68,6 +73,11 @@ pub struct CdRom {
     read_state: ReadState,
     /// True if a sector has been read but not yet notified
     read_pending: bool,
+    /// CD-DA playback position report waiting to be notified
+    report_pending: Option<[u8; 8]>,
+    /// True if the end of an audio track or of the disc has been
+    /// reached while playing but not yet notified
+    data_end_pending: bool,
     /// Currently loaded disc or None if no disc is present
     disc: Option<Disc>,
     /// Target of the next seek command
@@ -76,6 +86,16 @@ pub struct CdRom {
     seek_target_pending: bool,
     /// Current read position
     position: Msf,
+    /// True if the drive motor is spinning
+    motor_on: bool,
+    /// Fast forward/rewind state while playing audio tracks
+    scan: Scan,
+    /// Track being played (BCD), used for autopause. Set when the
+    /// first sector is played.
+    play_track: Option<u8>,
+    /// High digit of the absolute frame number of the last CD-DA
+    /// position report. Reports are sent every 10 frames.
+    last_report_frame: u8,
     /// If true the drive is in double speed mode (2x, 150 sectors per
     /// second), otherwise we're in the default 1x (75 sectors per
     /// second).
@@ -93,6 +113,8 @@ pub struct CdRom {
     cdda_mode: bool,
     /// If true automatically pause at the end of the track
     autopause: bool,
+    /// If true CD audio output is muted
+    mute: bool,
     /// If true the controller will generate interrupts for each
     /// sector while playing CD-DA tracks. The response will contain
     /// the current location amongst other things.
@@ -106,6 +128,10 @@ pub struct CdRom {
     /// number are processed
     filter_channel: u8,
 
+    /// XA-ADPCM audio decoder
+    xa_decoder: XaDecoder,
+    /// 44.1kHz stereo audio samples waiting to be sent to the SPU
+    audio_fifo: VecDeque<(i16, i16)>,
     /// CDROM audio mixer connected to the SPU
     mixer: Mixer,
     /// PRNG to simulate the pseudo-random CD controller timings (from
@@ -130,20 +156,29 @@ impl CdRom {
             rx_len: 0,
             read_state: ReadState::Idle,
             read_pending: false,
+            report_pending: None,
+            data_end_pending: false,
             disc: disc,
             seek_target: Msf::zero(),
             seek_target_pending: false,
             position: Msf::zero(),
+            motor_on: true,
+            scan: Scan::Off,
+            play_track: None,
+            last_report_frame: 0xff,
             double_speed: false,
             xa_adpcm_to_spu: false,
             read_whole_sector: true,
             sector_size_override: false,
             cdda_mode: false,
             autopause: false,
+            mute: false,
             report_interrupts: false,
             filter_enabled: false,
             filter_file: 0,
             filter_channel: 0,
+            xa_decoder: XaDecoder::new(),
+            audio_fifo: VecDeque::new(),
             mixer: Mixer::new(),
             rand: SimpleRand::new(),
         }
@@ -206,6 +241,26 @@ impl CdRom {
                 }
             }
 
+            // Check for audio playback
+            if let ReadState::Playing(delay) = self.read_state {
+                if delay > elapsed {
+                    self.read_state = ReadState::Playing(delay - elapsed);
+                } else {
+                    let leftover = elapsed - delay;
+
+                    self.play_sector();
+                    self.maybe_notify_read(shared);
+
+                    // Playback might have stopped if we reached the
+                    // end of the track
+                    if self.read_state.is_playing() {
+                        let next = self.cycles_per_sector() - leftover;
+
+                        self.read_state = ReadState::Playing(next);
+                    }
+                }
+            }
+
             remaining_cycles -= elapsed;
         }
 
@@ -247,9 +302,11 @@ impl CdRom {
             }
         }
 
-        if let ReadState::Reading(delay) = self.read_state {
-            shared.tk().maybe_set_next_sync_delta(Peripheral::CdRom,
-                                                  delay as Cycles);
+        match self.read_state {
+            ReadState::Reading(delay) | ReadState::Playing(delay) =>
+                shared.tk().maybe_set_next_sync_delta(Peripheral::CdRom,
+                                                      delay as Cycles),
+            ReadState::Idle => (),
         }
     }
 
@@ -371,6 +428,47 @@ impl CdRom {
         }
     }
 
+    /// Return the next 44.1kHz stereo audio sample generated by the
+    /// CD controller, mixed using the CD volume registers. Returns
+    /// silence if no audio is being played.
+    pub fn next_audio_sample(&mut self) -> (i16, i16) {
+        let (l, r) =
+            match self.audio_fifo.pop_front() {
+                Some(s) => (s.0 as i32, s.1 as i32),
+                None => return (0, 0),
+            };
+
+        if self.mute {
+            return (0, 0);
+        }
+
+        let mixer = &self.mixer;
+
+        // 0x80 is 100% volume
+        let mix = |a: i32, vol_a: u8, b: i32, vol_b: u8| {
+            let v = (a * vol_a as i32 + b * vol_b as i32) >> 7;
+
+            if v < -0x8000 {
+                -0x8000
+            } else if v > 0x7fff {
+                0x7fff
+            } else {
+                v as i16
+            }
+        };
+
+        (mix(l, mixer.cd_left_to_spu_left, r, mixer.cd_right_to_spu_left),
+         mix(l, mixer.cd_left_to_spu_right, r, mixer.cd_right_to_spu_right))
+    }
+
+    /// If nobody consumes the audio samples we don't want the FIFO
+    /// to grow forever, drop the oldest ones.
+    fn trim_audio_fifo(&mut self) {
+        while self.audio_fifo.len() > AUDIO_FIFO_MAX {
+            self.audio_fifo.pop_front();
+        }
+    }
+
     /// The DMA can read the RX buffer one word at a time
     pub fn dma_read_word(&mut self) -> u32 {
         let b0 = self.read_byte() as u32;
@@ -544,29 +642,51 @@ impl CdRom {
         }
     }
 
-    /// Start the async read notification sequence if a sector read is
-    /// pending and the preconditions are met
+    /// Start the async read notification sequence if a sector read,
+    /// CD-DA report or end of track is pending and the preconditions
+    /// are met
     fn maybe_notify_read(&mut self, shared: &mut SharedState) {
-        if self.read_pending {
-            if self.irq_flags == 0 && !self.sub_cpu.in_command() {
-                self.sub_cpu.response.clear();
+        if self.irq_flags != 0 || self.sub_cpu.in_command() {
+            return;
+        }
 
-                self.sub_cpu.irq_code = IrqCode::SectorReady;
+        if self.read_pending {
+            let status = self.drive_status();
 
-                let status = self.drive_status();
+            self.notify(shared, IrqCode::SectorReady, &[status]);
 
-                self.sub_cpu.response.push(status);
+            self.read_pending = false;
+        } else if let Some(report) = self.report_pending {
+            self.notify(shared, IrqCode::SectorReady, &report);
 
-                self.sub_cpu.sequence = SubCpuSequence::AsyncRxPush;
-                self.sub_cpu.timer = timings::READ_RX_PUSH;
+            self.report_pending = None;
+        } else if self.data_end_pending {
+            let status = self.drive_status();
 
-                self.read_pending = false;
+            self.notify(shared, IrqCode::DataEnd, &[status]);
 
-                self.predict_next_sync(shared);
-            }
+            self.data_end_pending = false;
         }
     }
 
+    /// Start an async notification sequence with the given IRQ code
+    /// and response
+    fn notify(&mut self,
+              shared: &mut SharedState,
+              irq_code: IrqCode,
+              response: &[u8]) {
+        self.sub_cpu.response.clear();
+
+        self.sub_cpu.irq_code = irq_code;
+
+        self.sub_cpu.response.push_slice(response);
+
+        self.sub_cpu.sequence = SubCpuSequence::AsyncRxPush;
+        self.sub_cpu.timer = timings::READ_RX_PUSH;
+
+        self.predict_next_sync(shared);
+    }
+
     /// Called when it's time to advance in the sub-CPU execution
     /// sequence
     fn next_sub_cpu_step(&mut self, shared: &mut SharedState) {
@@ -704,15 +824,10 @@ impl CdRom {
         self.seek_target_pending = false;
     }
 
-    /// Called when a new sector must be read
-    fn read_sector(&mut self) {
-        if self.read_pending {
-            panic!("Sector read while previous one is still pending");
-        }
-
+    /// Load the sector at the current position into `self.sector`
+    fn fetch_sector(&mut self) {
         let position = self.position;
 
-        // Read the sector at `position`
         match self.disc {
             Some(ref mut d) =>
                 if let Err(e) = d.image().read_sector(&mut self.sector,
@@ -721,6 +836,31 @@ impl CdRom {
                 },
             None => panic!("Sector read without a disc"),
         }
+    }
+
+    /// Called when a new sector must be read
+    fn read_sector(&mut self) {
+        if self.read_pending {
+            panic!("Sector read while previous one is still pending");
+        }
+
+        let position = self.position;
+
+        // Read the sector at `position`
+        self.fetch_sector();
+
+        // Move on to the next segment.
+        // XXX what happens when we're at the last one?
+        self.position =
+            match self.position.next() {
+                Some(m) => m,
+                None => panic!("MSF overflow!"),
+            };
+
+        if self.maybe_decode_xa_sector() {
+            // ADPCM sectors sent to the SPU are not seen by the CPU
+            return;
+        }
 
         {
             // Extract the data we need from the sector.
@@ -767,15 +907,210 @@ impl CdRom {
             self.rx_len = data.len() as u16;
         }
 
-        // Move on to the next segment.
-        // XXX what happens when we're at the last one?
+        self.read_pending = true;
+    }
+
+    /// If XA-ADPCM playback is enabled and the current sector is an
+    /// audio sector decode it and return true. Returns false if the
+    /// sector must be handled as a normal data sector.
+    fn maybe_decode_xa_sector(&mut self) -> bool {
+        if !self.xa_adpcm_to_spu {
+            return false;
+        }
+
+        {
+            let data =
+                match self.sector.data_2352() {
+                    Ok(d) => d,
+                    Err(_) => return false,
+                };
+
+            let mode = data[15];
+            let file = data[16];
+            let channel = data[17];
+            let submode = data[18];
+
+            // We're looking for Mode 2 sectors with the "audio"
+            // submode bit set
+            if mode != 2 || submode & 4 == 0 {
+                return false;
+            }
+
+            if self.filter_enabled &&
+                (file != self.filter_file || channel != self.filter_channel) {
+                // Audio sector for an other stream, ignore it
+                return true;
+            }
+
+            self.xa_decoder.decode_sector(data, &mut self.audio_fifo);
+        }
+
+        self.trim_audio_fifo();
+
+        true
+    }
+
+    /// Called when a new sector must be played back in CD-DA mode
+    fn play_sector(&mut self) {
+        let position = self.position;
+
+        let read =
+            match self.disc {
+                Some(ref mut d) => d.image().read_sector(&mut self.sector,
+                                                         position),
+                None => panic!("CD-DA playback without a disc"),
+            };
+
+        if read.is_err() {
+            // We've reached the end of the disc
+            self.data_end_pending = true;
+            self.stop_playback();
+            return;
+        }
+
+        let track = self.sector.metadata().track.bcd();
+
+        match self.play_track {
+            None => self.play_track = Some(track),
+            Some(t) if t != track && self.autopause => {
+                // End of track reached. The DataEnd status is only
+                // built when the notification goes out so it reports
+                // that we're no longer playing.
+                self.data_end_pending = true;
+                self.stop_playback();
+                return;
+            }
+            _ => (),
+        }
+
+        self.queue_cdda_sector();
+
+        if self.report_interrupts {
+            self.maybe_report_position();
+        }
+
+        let index = self.position.sector_index();
+
+        // When fast forwarding or rewinding we skip some sectors
+        let index =
+            match self.scan {
+                Scan::Off => index + 1,
+                Scan::Forward => index + timings::SCAN_SECTORS,
+                Scan::Backward => {
+                    // Don't rewind past the beginning of track 01
+                    let track1 = 2 * 75;
+
+                    if index > track1 + timings::SCAN_SECTORS {
+                        index - timings::SCAN_SECTORS
+                    } else {
+                        track1
+                    }
+                }
+            };
+
+        // XXX what happens when we're at the end of the disc?
         self.position =
-            match self.position.next() {
+            match Msf::from_sector_index(index) {
                 Some(m) => m,
                 None => panic!("MSF overflow!"),
             };
+    }
 
-        self.read_pending = true;
+    /// Stop CD-DA playback, leaving the head at the current position
+    fn stop_playback(&mut self) {
+        self.read_state = ReadState::Idle;
+        self.scan = Scan::Off;
+    }
+
+    /// Send the audio samples of the current sector to the SPU
+    fn queue_cdda_sector(&mut self) {
+        {
+            let data =
+                match self.sector.data_2352() {
+                    Ok(d) => d,
+                    Err(e) => {
+                        warn!("Can't play CD-DA sector {}: {}",
+                              self.position, e);
+                        return;
+                    }
+                };
+
+            // Red book audio sectors contain 588 16bit little endian
+            // stereo samples at 44.1kHz
+            let samples = data.chunks(4).map(|s| {
+                let l = s[0] as u16 | ((s[1] as u16) << 8);
+                let r = s[2] as u16 | ((s[3] as u16) << 8);
+
+                (l as i16, r as i16)
+            });
+
+            self.audio_fifo.extend(samples);
+        }
+
+        self.trim_audio_fifo();
+    }
+
+    /// Generate a position report for the current sector if
+    /// necessary. Reports are sent every 10 frames and alternate
+    /// between absolute and track-relative positions.
+    fn maybe_report_position(&mut self) {
+        let (abs_m, abs_s, abs_f) = self.sector.metadata().msf.into_bcd();
+
+        let abs_f = abs_f.bcd();
+
+        // In scan mode we report every sector
+        if abs_f >> 4 == self.last_report_frame && self.scan == Scan::Off {
+            return;
+        }
+
+        self.last_report_frame = abs_f >> 4;
+
+        let (m, s, f) =
+            if abs_f & 0x10 != 0 {
+                let (m, s, f) = self.sector.metadata().track_msf.into_bcd();
+
+                // Bit 7 of the second byte is set for relative
+                // positions
+                (m.bcd(), s.bcd() | 0x80, f.bcd())
+            } else {
+                (abs_m.bcd(), abs_s.bcd(), abs_f)
+            };
+
+        let peak = self.cdda_peak();
+
+        let metadata = self.sector.metadata();
+
+        self.report_pending = Some([self.drive_status(),
+                                    metadata.track.bcd(),
+                                    metadata.index.bcd(),
+                                    m,
+                                    s,
+                                    f,
+                                    peak as u8,
+                                    (peak >> 8) as u8]);
+    }
+
+    /// Return the peak amplitude of the current CD-DA sector, used
+    /// in position reports
+    fn cdda_peak(&self) -> u16 {
+        let data =
+            match self.sector.data_2352() {
+                Ok(d) => d,
+                Err(_) => return 0,
+            };
+
+        // XXX On the real hardware bit 15 says whether the peak
+        // value is for the left or right channel, we just return the
+        // peak of both channels.
+        data.chunks(2)
+            .map(|s| {
+                let v = (s[0] as u16 | ((s[1] as u16) << 8)) as i16;
+
+                (v as i32).abs()
+            })
+            .max()
+            .map(|p| ::std::cmp::min(p, 0x7fff) as u16)
+            .unwrap_or(0)
     }
 
     /// Assembles the first status byte returned by many commands
@@ -788,11 +1123,12 @@ impl CdRom {
             Some(_) => {
                 let mut r = 0;
 
-                let reading = !self.read_state.is_idle();
+                let reading = self.read_state.is_reading();
+                let playing = self.read_state.is_playing();
 
-                // Motor on
-                r |= 1 << 1;
+                r |= (self.motor_on as u8) << 1;
                 r |= (reading as u8) << 5;
+                r |= (playing as u8) << 7;
 
                 r
             }
@@ -809,8 +1145,13 @@ impl CdRom {
             match self.command.unwrap() {
                 0x01 => (0, 0, CdRom::cmd_get_stat),
                 0x02 => (3, 3, CdRom::cmd_set_loc),
+                0x03 => (0, 1, CdRom::cmd_play),
+                0x04 => (0, 0, CdRom::cmd_forward),
+                0x05 => (0, 0, CdRom::cmd_backward),
                 // ReadN
                 0x06 => (0, 0, CdRom::cmd_read),
+                0x07 => (0, 0, CdRom::cmd_standby),
+                0x08 => (0, 0, CdRom::cmd_stop),
                 0x09 => (0, 0, CdRom::cmd_pause),
                 0x0a => (0, 0, CdRom::cmd_init),
                 0x0b => (0, 0, CdRom::cmd_mute),
@@ -818,27 +1159,53 @@ impl CdRom {
                 0x0d => (2, 2, CdRom::cmd_set_filter),
                 0x0e => (1, 1, CdRom::cmd_set_mode),
                 0x0f => (0, 0, CdRom::cmd_get_param),
+                0x10 => (0, 0, CdRom::cmd_get_loc_l),
                 0x11 => (0, 0, CdRom::cmd_get_loc_p),
+                0x12 => (1, 1, CdRom::cmd_set_session),
+                0x13 => (0, 0, CdRom::cmd_get_tn),
+                0x14 => (1, 1, CdRom::cmd_get_td),
                 0x15 => (0, 0, CdRom::cmd_seek_l),
+                0x16 => (0, 0, CdRom::cmd_seek_p),
                 0x19 => (1, 1, CdRom::cmd_test),
                 0x1a => (0, 0, CdRom::cmd_get_id),
                 // ReadS
                 0x1b => (0, 0, CdRom::cmd_read),
+                // Reset ignores its parameters, if any
+                0x1c => (0, 0x1f, CdRom::cmd_reset),
                 0x1e => (0, 0, CdRom::cmd_read_toc),
-                c => panic!("Unhandled CDROM command 0x{:02x} {:?}",
-                            c, self.sub_cpu.params),
+                c => {
+                    warn!("Unhandled CDROM command 0x{:02x} {:?}",
+                          c, self.sub_cpu.params);
+
+                    self.error_response(error::INVALID_COMMAND);
+                    return;
+                }
             };
 
         let nparams = self.sub_cpu.params.len();
 
         if nparams < min_param || nparams > max_param {
-            panic!("Wrong number of parameters for command {:02x} ({})",
-                   self.command.unwrap(), nparams);
+            warn!("Wrong number of parameters for command {:02x} ({})",
+                  self.command.unwrap(), nparams);
+
+            self.error_response(error::WRONG_PARAMETERS);
+            return;
         }
 
         handler(self);
     }
 
+    /// Push an error response for the current command or async
+    /// event: the status byte with the error bit set followed by the
+    /// error `code`.
+    fn error_response(&mut self, code: u8) {
+        let status = self.drive_status() | 1;
+
+        self.sub_cpu.response.push_slice(&[status, code]);
+
+        self.sub_cpu.irq_code = IrqCode::Error;
+    }
+
     /// Read the drive's status byte
     fn cmd_get_stat(&mut self) {
         let status = self.drive_status();
@@ -888,13 +1255,152 @@ impl CdRom {
 
         let read_delay = self.cycles_per_sector();
 
+        self.motor_on = true;
         self.read_state = ReadState::Reading(read_delay);
+        self.xa_decoder.reset();
+
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+    }
+
+    /// Start CD-DA playback. The optional parameter is the track
+    /// number to play, otherwise we play from the current seek
+    /// target.
+    fn cmd_play(&mut self) {
+        if self.disc.is_none() {
+            self.error_response(error::NO_DISC);
+            return;
+        }
+
+        let track =
+            if self.sub_cpu.params.is_empty() {
+                0
+            } else {
+                self.sub_cpu.params.pop()
+            };
+
+        // Track 00 means "current track"
+        if track != 0 {
+            let start =
+                Bcd::from_bcd(track)
+                .and_then(|t| self.disc.as_ref().unwrap().track_start(t).ok());
+
+            match start {
+                Some(msf) => {
+                    self.seek_target = msf;
+                    self.seek_target_pending = true;
+                }
+                None => warn!("CDROM: play invalid track {:02x}", track),
+            }
+        }
+
+        if self.seek_target_pending {
+            self.do_seek();
+        }
+
+        let play_delay = self.cycles_per_sector();
+
+        self.motor_on = true;
+        self.scan = Scan::Off;
+        self.play_track = None;
+        self.last_report_frame = 0xff;
+        self.read_state = ReadState::Playing(play_delay);
+
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+    }
+
+    /// Fast forward while playing CD-DA
+    fn cmd_forward(&mut self) {
+        self.start_scan(Scan::Forward);
+    }
+
+    /// Rewind while playing CD-DA
+    fn cmd_backward(&mut self) {
+        self.start_scan(Scan::Backward);
+    }
+
+    fn start_scan(&mut self, scan: Scan) {
+        if !self.read_state.is_playing() {
+            // Only works while playing
+            self.error_response(error::NOT_READY);
+            return;
+        }
+
+        self.scan = scan;
 
         let status = self.drive_status();
 
         self.sub_cpu.response.push(status);
     }
 
+    /// Spin up the drive motor
+    fn cmd_standby(&mut self) {
+        if self.disc.is_none() {
+            self.error_response(error::NO_DISC);
+            return;
+        }
+
+        if self.motor_on {
+            // Motor already spinning
+            self.error_response(error::WRONG_PARAMETERS);
+            return;
+        }
+
+        self.motor_on = true;
+
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        self.sub_cpu.schedule_async_response(timings::STANDBY_ASYNC,
+                                             CdRom::async_standby);
+    }
+
+    fn async_standby(&mut self) -> u32 {
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        timings::STANDBY_RX_PUSH
+    }
+
+    /// Stop reading or playing and spin down the drive motor
+    fn cmd_stop(&mut self) {
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        // The delay depends on how fast the disc was spinning
+        let async_delay =
+            if !self.motor_on {
+                timings::STOP_ASYNC_STOPPED
+            } else if self.double_speed {
+                timings::STOP_ASYNC_2X
+            } else {
+                timings::STOP_ASYNC_1X
+            };
+
+        self.read_state = ReadState::Idle;
+        self.read_pending = false;
+        self.report_pending = None;
+        self.data_end_pending = false;
+        self.scan = Scan::Off;
+        self.motor_on = false;
+
+        self.sub_cpu.schedule_async_response(async_delay, CdRom::async_stop);
+    }
+
+    fn async_stop(&mut self) -> u32 {
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        timings::STOP_RX_PUSH
+    }
+
     /// Stop reading sectors but remain at the same position on the
     /// disc
     fn cmd_pause(&mut self) {
@@ -935,6 +1441,8 @@ impl CdRom {
         // XXX I think? Needs testing
         self.read_state = ReadState::Idle;
         self.read_pending = false;
+        self.report_pending = None;
+        self.data_end_pending = false;
 
         self.sub_cpu.schedule_async_response(900_000,
                                              CdRom::async_init);
@@ -945,9 +1453,18 @@ impl CdRom {
 
         self.sub_cpu.response.push(status);
 
+        self.reset_drive();
+
+        timings::INIT_RX_PUSH
+    }
+
+    /// Reset the drive position and mode to their default values
+    fn reset_drive(&mut self) {
         self.position = Msf::zero();
         self.seek_target = Msf::zero();
         self.read_state = ReadState::Idle;
+        self.motor_on = true;
+        self.scan = Scan::Off;
         self.double_speed = false;
         self.xa_adpcm_to_spu = false;
         self.read_whole_sector = true;
@@ -956,12 +1473,31 @@ impl CdRom {
         self.report_interrupts = false;
         self.autopause = false;
         self.cdda_mode = false;
+    }
 
-        timings::INIT_RX_PUSH
+    /// Reset the drive controller. According to No$ it's like opening
+    /// and closing the drive door. There's no second response to
+    /// tell when the reset is complete.
+    fn cmd_reset(&mut self) {
+        // Parameters are ignored
+        self.sub_cpu.params.clear();
+
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        self.read_pending = false;
+        self.report_pending = None;
+        self.data_end_pending = false;
+        self.seek_target_pending = false;
+
+        self.reset_drive();
     }
 
     /// Mute CDROM audio playback
     fn cmd_mute(&mut self) {
+        self.mute = true;
+
         let status = self.drive_status();
 
         self.sub_cpu.response.push(status);
@@ -969,6 +1505,8 @@ impl CdRom {
 
     /// Demute CDROM audio playback
     fn cmd_demute(&mut self) {
+        self.mute = false;
+
         let status = self.drive_status();
 
         self.sub_cpu.response.push(status);
@@ -999,11 +1537,12 @@ impl CdRom {
         self.autopause = (mode >> 1) & 1 != 0;
         self.cdda_mode = (mode >> 0) & 1 != 0;
 
-        if self.cdda_mode ||
-           self.autopause ||
-           self.report_interrupts ||
-           self.sector_size_override {
-            panic!("CDROM: unhandled mode: {:02x}", mode);
+        if self.sector_size_override {
+            // Supposedly selects 0x918 byte sectors when
+            // `read_whole_sector` is clear. Some CD-DA games set it
+            // along with the audio bits, it doesn't matter for
+            // playback so we ignore it.
+            warn!("CDROM: ignoring sector size override: {:02x}", mode);
         }
 
         let status = self.drive_status();
@@ -1033,6 +1572,34 @@ impl CdRom {
         self.sub_cpu.response.push_slice(&response);
     }
 
+    /// Return the header and subheader of the last data sector read
+    fn cmd_get_loc_l(&mut self) {
+        if self.read_state.is_playing() {
+            // No data sector to report
+            self.error_response(error::NOT_READY);
+            return;
+        }
+
+        let header =
+            match self.sector.data_2352() {
+                // 4 bytes of header (MSF + mode) followed by the 4
+                // bytes of the first copy of the XA subheader
+                Ok(d) => {
+                    let mut h = [0; 8];
+
+                    h.copy_from_slice(&d[12..20]);
+
+                    h
+                }
+                Err(_) => {
+                    self.error_response(error::NOT_READY);
+                    return;
+                }
+            };
+
+        self.sub_cpu.response.push_slice(&header);
+    }
+
     /// Get the current position of the drive head by returning the
     /// contents of the Q subchannel
     fn cmd_get_loc_p(&mut self) {
@@ -1081,6 +1648,8 @@ impl CdRom {
     fn cmd_seek_l(&mut self) {
         self.do_seek();
 
+        self.motor_on = true;
+
         let status = self.drive_status();
 
         self.sub_cpu.response.push(status);
@@ -1102,17 +1671,126 @@ impl CdRom {
         timings::SEEK_L_RX_PUSH
     }
 
+    /// Execute seek in audio mode. SeekL uses the data sector headers
+    /// to find its target while SeekP uses the Q subchannel, since
+    /// we don't emulate the seek itself they behave the same.
+    fn cmd_seek_p(&mut self) {
+        self.cmd_seek_l();
+    }
+
+    /// Seek to the beginning of a session. We only support single
+    /// session discs.
+    fn cmd_set_session(&mut self) {
+        if self.disc.is_none() {
+            self.error_response(error::NO_DISC);
+            return;
+        }
+
+        let session = self.sub_cpu.params.pop();
+
+        if session == 0 {
+            self.error_response(error::WRONG_PARAMETERS);
+            return;
+        }
+
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        self.read_state = ReadState::Idle;
+
+        let handler =
+            if session == 1 {
+                self.seek_target = Msf::zero();
+                self.position = Msf::zero();
+
+                CdRom::async_set_session
+            } else {
+                // The drive will attempt to find the session before
+                // giving up
+                CdRom::async_set_session_error
+            };
+
+        self.sub_cpu.schedule_async_response(timings::SET_SESSION_ASYNC,
+                                             handler);
+    }
+
+    fn async_set_session(&mut self) -> u32 {
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        timings::SET_SESSION_RX_PUSH
+    }
+
+    fn async_set_session_error(&mut self) -> u32 {
+        self.error_response(error::INVALID_COMMAND);
+
+        timings::SET_SESSION_RX_PUSH
+    }
+
+    /// Return the numbers of the first and last tracks on the disc
+    fn cmd_get_tn(&mut self) {
+        let last =
+            match self.disc {
+                Some(ref d) => d.last_track(),
+                None => {
+                    self.error_response(error::NO_DISC);
+                    return;
+                }
+            };
+
+        let response = [self.drive_status(),
+                        Bcd::one().bcd(),
+                        last.bcd()];
+
+        self.sub_cpu.response.push_slice(&response);
+    }
+
+    /// Return the start position of the track given in parameter or
+    /// the end of the disc for track 00
+    fn cmd_get_td(&mut self) {
+        let track = self.sub_cpu.params.pop();
+
+        let start =
+            match self.disc {
+                Some(ref d) =>
+                    match Bcd::from_bcd(track) {
+                        Some(t) if t.bcd() == 0 => Some(d.lead_out()),
+                        Some(t) if t.bcd() <= d.last_track().bcd() =>
+                            d.track_start(t).ok(),
+                        _ => None,
+                    },
+                None => {
+                    self.error_response(error::NO_DISC);
+                    return;
+                }
+            };
+
+        match start {
+            Some(msf) => {
+                let (m, s, _) = msf.into_bcd();
+
+                let response = [self.drive_status(), m.bcd(), s.bcd()];
+
+                self.sub_cpu.response.push_slice(&response);
+            }
+            None => self.error_response(error::WRONG_PARAMETERS),
+        }
+    }
+
     /// The test command can do a whole bunch of stuff, the first
     /// parameter says what
     fn cmd_test(&mut self) {
-        if self.sub_cpu.params.len() != 1 {
-            panic!("Unexpected number of parameters for CDROM test command: {}",
-                   self.sub_cpu.params.len());
-        }
-
+        // The parameter count has already been checked by
+        // `execute_command`
         match self.sub_cpu.params.pop() {
-             0x20 => self.test_version(),
-             n    => panic!("Unhandled CDROM test subcommand 0x{:02x}", n),
+            0x20 => self.test_version(),
+            n => {
+                warn!("Unhandled CDROM test subcommand 0x{:02x}", n);
+
+                self.error_response(error::INVALID_COMMAND);
+            }
         }
     }
 
@@ -1281,7 +1959,10 @@ buffer!(struct RxBuffer([u8; 2352]));
 enum ReadState {
     Idle,
     /// We're expecting a sector
-    Reading(u32)
+    Reading(u32),
+    /// We're playing an audio track, the value is the delay until
+    /// the next sector
+    Playing(u32),
 }
 
 impl ReadState {
@@ -1291,6 +1972,31 @@ impl ReadState {
             _ => false,
         }
     }
+
+    fn is_reading(&self) -> bool {
+        match *self {
+            ReadState::Reading(_) => true,
+            _ => false,
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        match *self {
+            ReadState::Playing(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Fast forward/rewind state while playing audio tracks
+#[derive(Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+enum Scan {
+    /// Normal playback
+    Off,
+    /// Fast forward
+    Forward,
+    /// Rewind
+    Backward,
 }
 
 /// Description of the sub-CPU processing sequence
@@ -1351,13 +2057,12 @@ impl SubCpu {
     fn start_command(&mut self, pending_delay: u32) {
         assert!(self.in_command() == false);
 
-        if self.async_command_pending() {
-            // Not sure what's supposed to happen here, might be
-            // command dependant. Can't really see why anybody would
-            // want to start a new command without waiting for the
-            // response to the previous one though.
-            panic!("New CD command while still waiting for an async response");
-        }
+        // If an async response is pending it keeps counting down and
+        // will be sent once this command's IRQ has been
+        // acknowledged. Games commonly poll GetStat while waiting for
+        // a slow command (Stop, Standby...) to complete. If the new
+        // command schedules its own async response it replaces the
+        // pending one, like mednafen does.
 
         self.sequence = SubCpuSequence::CommandPending;
         self.timer = pending_delay;
@@ -1372,7 +2077,9 @@ impl SubCpu {
     fn schedule_async_response(&mut self,
                                delay: u32,
                                handler: fn (&mut CdRom) -> u32) {
-        assert!(self.async_response.is_none());
+        if self.async_command_pending() {
+            debug!("CD async response replaced by a new command");
+        }
 
         self.async_response = Some((delay, AsyncResponse(handler)));
     }
@@ -1409,6 +2116,10 @@ callback!(struct AsyncResponse(fn (&mut CdRom) -> u32) {
     CdRom::async_seek_l,
     CdRom::async_read_toc,
     CdRom::async_get_id,
+    CdRom::async_standby,
+    CdRom::async_stop,
+    CdRom::async_set_session,
+    CdRom::async_set_session_error,
 });
 
 /// Various IRQ codes used by the sub-CPU
@@ -1420,11 +2131,35 @@ enum IrqCode {
     AsyncOk = 2,
     /// Command succesful, used for the 1st response.
     Ok = 3,
+    /// End of track reached while playing CD-DA with autopause
+    /// enabled, or end of disc.
+    DataEnd = 4,
     /// Error: invalid command, disc command while do disc is present
     /// etc...
     Error = 5,
 }
 
+mod error {
+    //! Error codes returned in the second response byte when a
+    //! command fails
+
+    /// Invalid parameter value or count
+    pub const WRONG_PARAMETERS: u8 = 0x20;
+    /// Unknown command or session not found
+    pub const INVALID_COMMAND: u8 = 0x40;
+    /// Command can't be executed in the current state
+    pub const NOT_READY: u8 = 0x80;
+    /// No disc in the drive (same code as NOT_READY)
+    pub const NO_DISC: u8 = 0x80;
+}
+
+/// Maximum number of audio samples buffered in `CdRom::audio_fifo`.
+/// The worst case is 18.9kHz mono 4bit XA audio: each sector
+/// contains 18 * 8 * 28 samples which are output twice and
+/// resampled to 44.1kHz, that's 9408 samples per sector. We keep
+/// room for four of those.
+const AUDIO_FIFO_MAX: usize = 4 * (18 * 8 * 28 * 2 * 7 / 6);
+
 /// CD-DA Audio playback mixer. The CDROM's audio stereo output can be
 /// mixed arbitrarily before reaching the SPU stereo input.
 #[derive(RustcDecodable, RustcEncodable)]
@@ -1527,4 +2262,180 @@ mod timings {
     /// Delay between the asynchronous RX_CLEAR and first param push
     /// for the asynchronous Init response
     pub const INIT_RX_PUSH: u32 = 1_700;
+
+    /// Rough estimate of the time taken by the motor to spin up
+    /// after a Standby command.
+    pub const STANDBY_ASYNC: u32 = 4_000_000;
+
+    /// Delay between the asynchronous RX_CLEAR and first param push
+    /// for the asynchronous Standby response
+    pub const STANDBY_RX_PUSH: u32 = 1_700;
+
+    /// Delay before the Stop async response when the motor was
+    /// already stopped
+    pub const STOP_ASYNC_STOPPED: u32 = 7_500;
+
+    /// Time taken by the motor to spin down from 1x speed (values
+    /// taken from mednafen)
+    pub const STOP_ASYNC_1X: u32 = 13_900_000;
+
+    /// Time taken by the motor to spin down from 2x speed
+    pub const STOP_ASYNC_2X: u32 = 25_800_000;
+
+    /// Delay between the asynchronous RX_CLEAR and first param push
+    /// for the asynchronous Stop response
+    pub const STOP_RX_PUSH: u32 = 1_700;
+
+    /// Very rough estimate of the time taken to seek to a session
+    pub const SET_SESSION_ASYNC: u32 = 1_000_000;
+
+    /// Delay between the asynchronous RX_CLEAR and first param push
+    /// for the asynchronous SetSession response
+    pub const SET_SESSION_RX_PUSH: u32 = 1_700;
+
+    /// Number of sectors skipped for every sector played while fast
+    /// forwarding or rewinding. This is a guess.
+    pub const SCAN_SECTORS: u32 = 10;
+}
+
+/// Send command `cmd` with parameters `params` through the host
+/// interface
+#[cfg(test)]
+fn test_command(cdrom: &mut CdRom,
+                shared: &mut SharedState,
+                cmd: u8,
+                params: &[u8]) {
+    use memory::Byte;
+
+    for &p in params {
+        cdrom.store::<Byte>(shared, 2, p as u32);
+    }
+
+    cdrom.store::<Byte>(shared, 1, cmd as u32);
+}
+
+/// Run until the controller triggers an IRQ, acknowledge it and
+/// return the IRQ code and the response
+#[cfg(test)]
+fn test_wait_irq(cdrom: &mut CdRom,
+                 shared: &mut SharedState) -> (u8, Vec<u8>) {
+    use memory::Byte;
+
+    for _ in 0..10_000 {
+        shared.tk().tick(10_000);
+        cdrom.sync(shared);
+
+        if cdrom.irq_flags != 0 {
+            let code = cdrom.irq_flags;
+            let mut response = Vec::new();
+
+            while cdrom.load::<Byte>(shared, 0) & 0x20 != 0 {
+                response.push(cdrom.load::<Byte>(shared, 1) as u8);
+            }
+
+            cdrom.store::<Byte>(shared, 0, 1);
+            cdrom.store::<Byte>(shared, 3, 0x1f);
+            cdrom.store::<Byte>(shared, 0, 0);
+
+            return (code, response);
+        }
+    }
+
+    panic!("No CDROM IRQ");
+}
+
+#[test]
+fn async_response_while_polling() {
+    let mut shared = SharedState::new();
+    let mut cdrom = CdRom::new(None);
+
+    // Stop
+    test_command(&mut cdrom, &mut shared, 0x08, &[]);
+    assert!(test_wait_irq(&mut cdrom, &mut shared) == (3, vec![0x10]));
+
+    // The second response takes a while since the motor was spinning,
+    // poll GetStat in the meantime
+    for _ in 0..3 {
+        test_command(&mut cdrom, &mut shared, 0x01, &[]);
+        assert!(test_wait_irq(&mut cdrom, &mut shared) == (3, vec![0x10]));
+    }
+
+    assert!(cdrom.sub_cpu.async_command_pending());
+    assert!(test_wait_irq(&mut cdrom, &mut shared) == (2, vec![0x10]));
+
+    // Standby and SetSession need a disc
+    test_command(&mut cdrom, &mut shared, 0x07, &[]);
+    assert!(test_wait_irq(&mut cdrom, &mut shared) ==
+            (5, vec![0x11, 0x80]));
+
+    test_command(&mut cdrom, &mut shared, 0x12, &[1]);
+    assert!(test_wait_irq(&mut cdrom, &mut shared) ==
+            (5, vec![0x11, 0x80]));
+
+    // Unknown test subcommand and missing subcommand
+    test_command(&mut cdrom, &mut shared, 0x19, &[0x99]);
+    assert!(test_wait_irq(&mut cdrom, &mut shared) ==
+            (5, vec![0x11, 0x40]));
+
+    test_command(&mut cdrom, &mut shared, 0x19, &[]);
+    assert!(test_wait_irq(&mut cdrom, &mut shared) ==
+            (5, vec![0x11, 0x20]));
+
+    assert!(!cdrom.sub_cpu.async_command_pending());
+}
+
+#[test]
+fn cdda_report_and_autopause() {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use cdimage::cue::Cue;
+    use self::disc::Disc;
+
+    // Two 30 sector audio tracks starting at 00:02:00
+    let dir = ::std::env::temp_dir().join("rustation-cdda-test");
+
+    fs::create_dir_all(&dir).unwrap();
+
+    File::create(dir.join("tracks.bin")).unwrap()
+        .write_all(&[0; 60 * 2352]).unwrap();
+
+    File::create(dir.join("tracks.cue")).unwrap()
+        .write_all(b"FILE \"tracks.bin\" BINARY\n\
+                     TRACK 01 AUDIO\n\
+                     INDEX 01 00:00:00\n\
+                     TRACK 02 AUDIO\n\
+                     INDEX 01 00:00:30\n").unwrap();
+
+    let image = Cue::new(&dir.join("tracks.cue")).unwrap();
+
+    let mut shared = SharedState::new();
+    let disc = Disc::from_test_image(Box::new(image));
+
+    assert!(disc.lead_out() == Msf::from_bcd(0x00, 0x02, 0x60).unwrap());
+    let mut cdrom = CdRom::new(Some(disc));
+
+    // CD-DA, report, autopause. Bit 4 is ignored.
+    test_command(&mut cdrom, &mut shared, 0x0e, &[0x17]);
+    assert!(test_wait_irq(&mut cdrom, &mut shared) == (3, vec![0x02]));
+
+    // Play track 01
+    test_command(&mut cdrom, &mut shared, 0x03, &[0x01]);
+    assert!(test_wait_irq(&mut cdrom, &mut shared) == (3, vec![0x82]));
+
+    // Reports are sent every 10 sectors, alternating between
+    // absolute and relative positions
+    let reports = [[0x00, 0x02, 0x00],
+                   [0x00, 0x80, 0x10],
+                   [0x00, 0x02, 0x20]];
+
+    for r in &reports {
+        let expected = vec![0x82, 0x01, 0x01, r[0], r[1], r[2], 0, 0];
+
+        assert!(test_wait_irq(&mut cdrom, &mut shared) == (1, expected));
+    }
+
+    // Autopause at the end of the track
+    assert!(test_wait_irq(&mut cdrom, &mut shared) == (4, vec![0x02]));
+    assert!(cdrom.read_state.is_idle());
+    assert!(cdrom.position == Msf::from_bcd(0x00, 0x02, 0x30).unwrap());
 }
@@ -0,0 +1,348 @@
+//! XA-ADPCM audio decoder
+//!
+//! XA audio is stored in Mode 2 Form 2 sectors. Each sector contains
+//! 18 "sound groups" of 128 bytes, each sound group is made of 8
+//! (4bit samples) or 4 (8bit samples) "sound units" of 28 samples.
+//! The samples are encoded using the same kind of ADPCM as the SPU
+//! and can be mono or stereo, at 37.8kHz or 18.9kHz. They're
+//! resampled to 44.1kHz before being sent to the SPU.
+
+use std::collections::VecDeque;
+
+/// XA-ADPCM decoder state
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct XaDecoder {
+    /// Last two decoded samples for each channel (left and right,
+    /// mono streams only use the left one), used by the ADPCM
+    /// prediction filters.
+    history: [(i32, i32); 2],
+    /// 37.8kHz to 44.1kHz resampler
+    resampler: Resampler,
+}
+
+impl XaDecoder {
+    pub fn new() -> XaDecoder {
+        XaDecoder {
+            history: [(0, 0); 2],
+            resampler: Resampler::new(),
+        }
+    }
+
+    /// Reset the decoder state, should be called when a new stream
+    /// starts.
+    pub fn reset(&mut self) {
+        self.history = [(0, 0); 2];
+        self.resampler = Resampler::new();
+    }
+
+    /// Decode the XA-ADPCM audio sector `sector` (including the sync
+    /// pattern and header) and push the resulting 44.1kHz stereo
+    /// samples at the back of `output`.
+    pub fn decode_sector(&mut self,
+                         sector: &[u8],
+                         output: &mut VecDeque<(i16, i16)>) {
+        // Coding info byte in the subheader
+        let coding = sector[16 + 3];
+
+        let stereo =
+            match coding & 3 {
+                0 => false,
+                1 => true,
+                _ => {
+                    warn!("Invalid XA stereo mode: {:02x}", coding);
+                    false
+                }
+            };
+
+        let half_rate =
+            match (coding >> 2) & 3 {
+                0 => false,
+                1 => true,
+                _ => {
+                    warn!("Invalid XA sample rate: {:02x}", coding);
+                    false
+                }
+            };
+
+        let eight_bit =
+            match (coding >> 4) & 3 {
+                0 => false,
+                1 => true,
+                _ => {
+                    warn!("Invalid XA sample depth: {:02x}", coding);
+                    false
+                }
+            };
+
+        // Sound groups start right after the subheader
+        let data = &sector[24..];
+
+        let mut samples = Vec::with_capacity(18 * 8 * 28);
+
+        for group in data.chunks(128).take(18) {
+            self.decode_sound_group(group,
+                                    stereo,
+                                    eight_bit,
+                                    &mut samples);
+        }
+
+        // 18.9kHz samples are simply output twice
+        let repeat =
+            if half_rate {
+                2
+            } else {
+                1
+            };
+
+        if stereo {
+            for frame in samples.chunks(2) {
+                for _ in 0..repeat {
+                    self.resampler.push((frame[0], frame[1]), output);
+                }
+            }
+        } else {
+            for &s in &samples {
+                for _ in 0..repeat {
+                    self.resampler.push((s, s), output);
+                }
+            }
+        }
+    }
+
+    /// Decode a single 128 byte sound group. For stereo streams the
+    /// samples are interleaved in `output` (left first).
+    fn decode_sound_group(&mut self,
+                          group: &[u8],
+                          stereo: bool,
+                          eight_bit: bool,
+                          output: &mut Vec<i16>) {
+        let units =
+            if eight_bit {
+                4
+            } else {
+                8
+            };
+
+        if stereo {
+            // Even units contain the left samples, odd units the
+            // right samples
+            let mut left = [0i16; 28];
+            let mut right = [0i16; 28];
+
+            for unit in 0..units / 2 {
+                self.decode_sound_unit(group, unit * 2, eight_bit,
+                                       0, &mut left);
+                self.decode_sound_unit(group, unit * 2 + 1, eight_bit,
+                                       1, &mut right);
+
+                for i in 0..28 {
+                    output.push(left[i]);
+                    output.push(right[i]);
+                }
+            }
+        } else {
+            let mut samples = [0i16; 28];
+
+            for unit in 0..units {
+                self.decode_sound_unit(group, unit, eight_bit,
+                                       0, &mut samples);
+
+                output.extend_from_slice(&samples);
+            }
+        }
+    }
+
+    /// Decode the 28 samples of sound unit `unit` within `group`
+    /// using the filter history of `channel`
+    fn decode_sound_unit(&mut self,
+                         group: &[u8],
+                         unit: usize,
+                         eight_bit: bool,
+                         channel: usize,
+                         samples: &mut [i16; 28]) {
+        // The sound unit parameters are stored at offset 4. The first
+        // 4 and last 4 bytes of the 16 byte header are just copies.
+        let param = group[4 + unit];
+
+        let shift =
+            match param & 0xf {
+                // Values above 12 behave like 9 according to No$
+                s @ 0...12 => s,
+                _ => 9,
+            };
+
+        let filter = ((param >> 4) & 3) as usize;
+
+        let (pos, neg) = ADPCM_FILTERS[filter];
+
+        let (mut old, mut older) = self.history[channel];
+
+        for (i, s) in samples.iter_mut().enumerate() {
+            // The samples are stored in 28 little endian words
+            // following the header
+            let word = &group[16 + i * 4..16 + i * 4 + 4];
+
+            // Put the encoded value in the high bits of a 16bit word
+            // and shift it right to sign-extend it
+            let raw =
+                if eight_bit {
+                    (word[unit] as u16) << 8
+                } else {
+                    let b = word[unit / 2] >> ((unit & 1) * 4);
+
+                    ((b & 0xf) as u16) << 12
+                };
+
+            let sample = ((raw as i16) >> shift) as i32;
+
+            let sample = sample + ((old * pos + older * neg + 32) >> 6);
+
+            let sample =
+                if sample < -0x8000 {
+                    -0x8000
+                } else if sample > 0x7fff {
+                    0x7fff
+                } else {
+                    sample
+                };
+
+            older = old;
+            old = sample;
+
+            *s = sample as i16;
+        }
+
+        self.history[channel] = (old, older);
+    }
+}
+
+/// ADPCM prediction filter coefficients (positive and negative)
+const ADPCM_FILTERS: [(i32, i32); 4] = [
+    (0, 0),
+    (60, 0),
+    (115, -52),
+    (98, -55),
+];
+
+/// 37.8kHz to 44.1kHz stereo resampler. The real hardware uses a
+/// zigzag interpolation filter, we use a simple linear interpolation
+/// instead. 44.1 / 37.8 = 7 / 6 so we output 7 samples for every 6
+/// input samples.
+#[derive(RustcDecodable, RustcEncodable)]
+struct Resampler {
+    /// Previous input sample
+    prev: (i16, i16),
+    /// Position of the next output sample between `prev` and the
+    /// next input sample, in 1/7th of an input period.
+    phase: u8,
+}
+
+impl Resampler {
+    fn new() -> Resampler {
+        Resampler {
+            prev: (0, 0),
+            phase: 0,
+        }
+    }
+
+    /// Push a new 37.8kHz input sample and output the corresponding
+    /// 44.1kHz samples (if any) at the back of `output`
+    fn push(&mut self,
+            sample: (i16, i16),
+            output: &mut VecDeque<(i16, i16)>) {
+        let lerp = |a: i16, b: i16, phase: u8| {
+            let a = a as i32;
+            let b = b as i32;
+
+            (a + (b - a) * phase as i32 / 7) as i16
+        };
+
+        while self.phase < 7 {
+            let (pl, pr) = self.prev;
+            let (l, r) = sample;
+
+            output.push_back((lerp(pl, l, self.phase),
+                              lerp(pr, r, self.phase)));
+
+            self.phase += 6;
+        }
+
+        self.phase -= 7;
+        self.prev = sample;
+    }
+}
+
+#[test]
+fn mono_4bit_sector() {
+    let mut sector = [0u8; 2352];
+
+    // Mode 2, mono, 37.8kHz, 4bit
+    sector[15] = 2;
+    sector[16 + 2] = 0x64;
+    sector[16 + 3] = 0;
+
+    for group in 0..18 {
+        let g = 24 + group * 128;
+
+        // Shift 12, filter 0 for all units
+        for i in 0..16 {
+            sector[g + i] = 12;
+        }
+
+        // All samples set to 1
+        for i in 16..128 {
+            sector[g + i] = 0x11;
+        }
+    }
+
+    let mut decoder = XaDecoder::new();
+    let mut output = VecDeque::new();
+
+    decoder.decode_sector(&sector, &mut output);
+
+    // 18 sound groups of 8 units of 28 samples, resampled at 44.1kHz
+    assert!(output.len() == 18 * 8 * 28 * 7 / 6);
+
+    // The first two samples are interpolated with the initial 0
+    assert!(output[0] == (0, 0));
+    assert!(output[1] == (0, 0));
+    assert!(output.iter().skip(2).all(|&s| s == (1, 1)));
+}
+
+#[test]
+fn adpcm_filters() {
+    let mut group = [0u8; 128];
+
+    // Unit 0: filter 1, shift 0. Unit 1: filter 2, shift 4
+    group[4] = 0x10;
+    group[5] = 0x24;
+
+    // Unit 0 samples are 1, unit 1 samples are -8
+    for i in 16..128 {
+        group[i] = 0x81;
+    }
+
+    let mut decoder = XaDecoder::new();
+    let mut samples = [0i16; 28];
+
+    decoder.decode_sound_unit(&group, 0, false, 0, &mut samples);
+
+    // 0x1000 + ((0x1000 * 60 + 32) >> 6) = 7936
+    // 0x1000 + ((7936 * 60 + 32) >> 6) = 11536
+    assert!(samples[0..3] == [4096, 7936, 11536]);
+    // Saturates after 10 samples
+    assert!(samples[9] == 31164);
+    assert!(samples[10..].iter().all(|&s| s == 0x7fff));
+
+    decoder.decode_sound_unit(&group, 1, false, 1, &mut samples);
+
+    // -0x8000 >> 4 = -2048
+    // -2048 + ((-2048 * 115 + 32) >> 6) = -5728
+    // -2048 + ((-5728 * 115 - 2048 * -52 + 32) >> 6) = -10676
+    assert!(samples[0..3] == [-2048, -5728, -10676]);
+    assert!(samples[6..].iter().all(|&s| s == -0x8000));
+
+    // Each channel keeps its own history
+    assert!(decoder.history[0] == (0x7fff, 0x7fff));
+    assert!(decoder.history[1] == (-0x8000, -0x8000));
+}
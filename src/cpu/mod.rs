@@ -10,6 +10,7 @@ use std::default::Default;
 use memory::{Interconnect, Addressable, Byte, HalfWord, Word};
 use shared::SharedState;
 use gpu::renderer::Renderer;
+use spu::sink::AudioSink;
 use interrupt::InterruptState;
 use debugger::Debugger;
 use tracer::module_tracer;
@@ -101,17 +102,35 @@ impl Cpu {
         &mut self.inter
     }
 
-    /// Run the emulator until the start of the next frame
+    /// Run the emulator until the start of the next frame. The audio
+    /// samples generated during the frame are discarded, use
+    /// `run_until_next_frame_with_audio` to get them.
     pub fn run_until_next_frame<D>(&mut self,
                                    debugger: &mut D,
                                    shared: &mut SharedState,
                                    renderer: &mut Renderer)
         where D: Debugger {
+        self.run_until_next_frame_with_audio(debugger,
+                                             shared,
+                                             renderer,
+                                             &mut ());
+    }
+
+    /// Run the emulator until the start of the next frame. The audio
+    /// samples generated during the frame are sent to `audio`.
+    pub fn run_until_next_frame_with_audio<D>(&mut self,
+                                              debugger: &mut D,
+                                              shared: &mut SharedState,
+                                              renderer: &mut Renderer,
+                                              audio: &mut AudioSink)
+        where D: Debugger {
         let frame = shared.counters().frame.get();
 
         while frame == shared.counters().frame.get() {
             self.run_next_instruction(debugger, shared, renderer);
         }
+
+        self.inter.flush_audio(shared, audio);
     }
 
     /// Run a single CPU instruction and return
@@ -4,7 +4,7 @@
 //! /!\ DO NOT EDIT DIRECTLY /!\
 
 use gpu::{Gpu, VideoClock};
-use gpu::renderer::{Renderer, PrimitiveAttributes, Vertex};
+use gpu::renderer::DummyRenderer;
 use memory::{Interconnect, Addressable};
 use memory;
 use shared::SharedState;
@@ -12,44 +12,6 @@ use bios::Bios;
 
 use super::{Cpu, RegisterIndex};
 
-/// Dummy GPU renderer to run the tests
-struct DummyRenderer;
-
-impl Renderer for DummyRenderer {
-    fn set_draw_offset(&mut self, _: i16, _: i16) {
-    }
-
-    fn set_draw_area(&mut self, _: (u16, u16), _: (u16, u16)) {
-    }
-
-    fn set_display_mode(&mut self,
-                        _: (u16, u16),
-                        _: (u16, u16),
-                        _: bool) {
-    }
-
-    fn push_line(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 2]) {
-    }
-
-    fn push_triangle(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 3]) {
-    }
-
-    fn push_quad(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 4]) {
-    }
-
-    fn fill_rect(&mut self,
-                 _: [u8; 3],
-                 _: (u16, u16),
-                 _: (u16, u16)) {
-    }
-
-    fn load_image(&mut self,
-                  _: (u16, u16),
-                  _: (u16, u16),
-                  _: &[u16]) {
-    }
-}
-
 fn write_blob(cpu: &mut Cpu,
              address: u32,
              blob: &[u32]) {
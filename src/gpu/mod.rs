@@ -6,10 +6,12 @@ use shared::SharedState;
 use interrupt::Interrupt;
 use timekeeper::{Peripheral, Cycles, FracCycles};
 
-use self::renderer::{Renderer, Vertex, PrimitiveAttributes};
+use self::renderer::{Renderer, Vertex, PrimitiveAttributes, MaskSettings};
+use self::renderer::TextureWindow;
 use self::renderer::{BlendMode, SemiTransparencyMode, TextureDepth};
 
 pub mod renderer;
+pub mod software;
 
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct Gpu {
@@ -105,6 +107,11 @@ pub struct Gpu {
     polyline_prev: ([i16; 2], [u8; 3]),
     /// Image buffer for texture uploads
     load_buffer: ImageBuffer,
+    /// Image buffer for VRAM to CPU transfers
+    store_buffer: ImageBuffer,
+    /// Number of 32bit words remaining to be read from
+    /// `store_buffer` through GPUREAD
+    store_words_remaining: u32,
 }
 
 impl Gpu {
@@ -156,6 +163,8 @@ impl Gpu {
             read_word: 0,
             polyline_prev: ([0; 2], [0; 3]),
             load_buffer: ImageBuffer::new(),
+            store_buffer: ImageBuffer::new(),
+            store_words_remaining: 0,
         }
     }
 
@@ -176,12 +185,7 @@ impl Gpu {
     /// CLOCK_RATIO_FRAC to get a precise fixed point value.
     fn gpu_to_cpu_clock_ratio(&self) -> FracCycles {
         // First we convert the delta into GPU clock periods.
-        // GPU clock in Hz
-        let gpu_clock =
-            match self.standard {
-                VideoClock::Ntsc => 53_690_000.,
-                VideoClock::Pal  => 53_222_000.,
-            };
+        let gpu_clock = self.gpu_clock_hz() as f32;
 
         // CPU clock in Hz
         let cpu_clock = ::cpu::CPU_FREQ_HZ as f32;
@@ -190,6 +194,27 @@ impl Gpu {
         FracCycles::from_f32(gpu_clock / cpu_clock)
     }
 
+    /// Return the GPU clock frequency in Hz
+    fn gpu_clock_hz(&self) -> u32 {
+        match self.standard {
+            VideoClock::Ntsc => 53_690_000,
+            VideoClock::Pal  => 53_222_000,
+        }
+    }
+
+    /// Return the position and timings of the displayed picture,
+    /// used by light guns to compute the beam position
+    pub fn display_timing(&self) -> DisplayTiming {
+        DisplayTiming {
+            horiz_start: self.display_horiz_start,
+            horiz_end: self.display_horiz_end,
+            line_start: self.display_line_start,
+            line_end: self.display_line_end,
+            gpu_clock_hz: self.gpu_clock_hz(),
+            dotclock_divider: self.hres.dotclock_divider(),
+        }
+    }
+
     /// Return the period of the dotclock expressed in CPU clock
     /// periods
     pub fn dotclock_period(&self) -> FracCycles {
@@ -447,7 +472,7 @@ impl Gpu {
         // Ready to receive command
         r |= 1 << 26;
         // Ready to send VRAM to CPU
-        r |= 1 << 27;
+        r |= ((self.store_words_remaining > 0) as u32) << 27;
         // Ready to receive DMA block
         r |= 1 << 28;
 
@@ -481,12 +506,30 @@ impl Gpu {
     }
 
     /// Retrieve value of the "read" register
-    fn read(&self) -> u32 {
-        debug!("GPUREAD");
-        // XXX framebuffer read not supported
+    fn read(&mut self) -> u32 {
+        if self.store_words_remaining > 0 {
+            let index = self.store_buffer.len_words() -
+                self.store_words_remaining;
+
+            // The last word read remains latched in the register
+            // once the transfer is over
+            self.read_word = self.store_buffer.word(index);
+            self.store_words_remaining -= 1;
+
+            if self.store_words_remaining == 0 {
+                // Save a bit of space in the savestate
+                self.store_buffer.clear();
+            }
+        }
+
         self.read_word
     }
 
+    /// Called by the DMA when it wants to read from the GPU
+    pub fn dma_read_word(&mut self) -> u32 {
+        self.read()
+    }
+
     /// GP0 handler method: handle a command word
     fn gp0_handle_command(&mut self, renderer: &mut Renderer, val: u32) {
         let (len, attributes) = self.gp0_parse_command(val);
@@ -676,12 +719,15 @@ impl Gpu {
 
         let semi_transparent = opcode & 2 != 0;
 
-        let attr =
+        let mut attr =
             Gp0Attributes::new(cback,
                                semi_transparent,
                                blend_mode,
                                dither);
 
+        attr.set_texture_window(self.texture_window());
+        attr.set_mask(self.mask_settings());
+
         (len, attr)
     }
 
@@ -689,6 +735,24 @@ impl Gpu {
         (self.draw_mode >> 9) & 1 != 0
     }
 
+    /// Return the current texture window configuration
+    fn texture_window(&self) -> TextureWindow {
+        TextureWindow {
+            x_mask: self.texture_window_x_mask,
+            y_mask: self.texture_window_y_mask,
+            x_offset: self.texture_window_x_offset,
+            y_offset: self.texture_window_y_offset,
+        }
+    }
+
+    /// Return the current mask bit configuration
+    fn mask_settings(&self) -> MaskSettings {
+        MaskSettings {
+            force_set_mask_bit: self.force_set_mask_bit,
+            preserve_masked_pixels: self.preserve_masked_pixels,
+        }
+    }
+
     /// GP0(0x00): No operation
     fn gp0_nop(&mut self, _: &mut Renderer) {
         // NOP
@@ -747,14 +811,22 @@ impl Gpu {
     }
 
     /// Gp0(0x80): Copy rectangle
-    fn gp0_copy_rect(&mut self, _: &mut Renderer) {
-        let size = gp0_position(self.gp0_command[3]);
-        let src_top_left = gp0_position(self.gp0_command[1]);
-        let dst_top_left = gp0_position(self.gp0_command[2]);
+    fn gp0_copy_rect(&mut self, renderer: &mut Renderer) {
+        let src = self.gp0_command[1];
+        let dst = self.gp0_command[2];
+        let size = self.gp0_command[3];
+
+        let src_top_left = ((src & 0x3ff) as u16, ((src >> 16) & 0x1ff) as u16);
+        let dst_top_left = ((dst & 0x3ff) as u16, ((dst >> 16) & 0x1ff) as u16);
 
-        // XXX Implement me
-        debug!("Copy Rectangle {:?} {:?} {:?}",
-               size, src_top_left, dst_top_left);
+        // A size of 0 is treated like the maximum value
+        let width = ((size & 0xffff).wrapping_sub(1) & 0x3ff) + 1;
+        let height = ((size >> 16).wrapping_sub(1) & 0x1ff) + 1;
+
+        renderer.copy_rect(src_top_left,
+                           dst_top_left,
+                           (width as u16, height as u16),
+                           self.mask_settings());
     }
 
     /// Draw an untextured unshaded triangle
@@ -1134,7 +1206,8 @@ impl Gpu {
         if self.gp0_words_remaining == 0 {
             renderer.load_image(self.load_buffer.top_left(),
                                 self.load_buffer.resolution(),
-                                self.load_buffer.buffer());
+                                self.load_buffer.buffer(),
+                                self.mask_settings());
 
             // Empty the load buffer, not strictly necessary but it'll
             // save a bit of space in the savestate.
@@ -1146,14 +1219,27 @@ impl Gpu {
     }
 
     /// GP0(0xC0): Image Store
-    fn gp0_image_store(&mut self, _: &mut Renderer) {
-        // Parameter 2 contains the image resolution
+    fn gp0_image_store(&mut self, renderer: &mut Renderer) {
+        // Parameter 1 contains the location of the source
+        // rectangle's top-left corner in VRAM
+        let pos = self.gp0_command[1];
+
+        let x = (pos & 0x3ff) as u16;
+        let y = ((pos >> 16) & 0x1ff) as u16;
+
+        // Parameter 2 contains the image resolution. A size of 0 is
+        // treated like the maximum value.
         let res = self.gp0_command[2];
 
-        let width  = res & 0xffff;
-        let height = res >> 16;
+        let width  = ((res & 0xffff).wrapping_sub(1) & 0x3ff) + 1;
+        let height = ((res >> 16).wrapping_sub(1) & 0x1ff) + 1;
+
+        // We copy the entire rectangle right away, the words are
+        // then returned one at a time through GPUREAD.
+        self.store_buffer.reset(x, y, width as u16, height as u16);
+        self.store_buffer.store_vram(renderer);
 
-        warn!("Unhandled image store: {}x{}", width, height);
+        self.store_words_remaining = self.store_buffer.len_words();
     }
 
     /// GP0(0xE1): Draw Mode
@@ -1324,6 +1410,8 @@ impl Gpu {
     fn gp1_reset_command_buffer(&mut self) {
         self.gp0_command.clear();
         self.gp0_words_remaining = 0;
+        self.store_words_remaining = 0;
+        self.store_buffer.clear();
         *self.gp0_handler = Gpu::gp0_handle_command;
         // XXX should also clear the command FIFO when we implement it
     }
@@ -1458,6 +1546,38 @@ callback!(struct Gp0Handler(fn (&mut Gpu, &mut Renderer, u32)) {
     Gpu::gp0_handle_image_load,
 });
 
+/// Position and timings of the displayed picture
+#[derive(Clone, Copy, Debug, RustcDecodable, RustcEncodable)]
+pub struct DisplayTiming {
+    /// First GPU clock tick of the picture relative to HSYNC
+    pub horiz_start: u16,
+    /// Last GPU clock tick of the picture relative to HSYNC
+    pub horiz_end: u16,
+    /// First line of the picture relative to VSYNC
+    pub line_start: u16,
+    /// Last line of the picture relative to VSYNC
+    pub line_end: u16,
+    /// GPU clock frequency in Hz
+    pub gpu_clock_hz: u32,
+    /// Number of GPU clock ticks per dotclock tick (i.e. per
+    /// horizontal pixel)
+    pub dotclock_divider: u8,
+}
+
+impl DisplayTiming {
+    /// Default NTSC timings
+    pub fn new() -> DisplayTiming {
+        DisplayTiming {
+            horiz_start: 0x200,
+            horiz_end: 0xc00,
+            line_start: 0x10,
+            line_end: 0x100,
+            gpu_clock_hz: 53_690_000,
+            dotclock_divider: 10,
+        }
+    }
+}
+
 /// Interlaced output splits each frame in two fields
 #[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
 enum Field {
@@ -1661,10 +1781,20 @@ impl Gp0Attributes {
                 texture_depth: TextureDepth::T4Bpp,
                 clut: [0, 0],
                 dither: dither,
+                texture_window: TextureWindow::none(),
+                mask: MaskSettings::none(),
             }
         }
     }
 
+    fn set_texture_window(&mut self, texture_window: TextureWindow) {
+        self.primitive_attributes.texture_window = texture_window;
+    }
+
+    fn set_mask(&mut self, mask: MaskSettings) {
+        self.primitive_attributes.mask = mask;
+    }
+
     fn primitive_attributes(&self) -> &PrimitiveAttributes {
         &self.primitive_attributes
     }
@@ -1844,6 +1974,39 @@ impl ImageBuffer {
         self.buffer[self.index as usize] = (word >> 16) as u16;
         self.index += 1;
     }
+
+    /// Fill the buffer with the contents of the VRAM rectangle set
+    /// by the last call to `reset`.
+    fn store_vram(&mut self, renderer: &mut Renderer) {
+        let len = self.resolution.0 as usize * self.resolution.1 as usize;
+
+        renderer.store_image(self.top_left,
+                             self.resolution,
+                             &mut self.buffer[0..len]);
+
+        // If we have an odd number of pixels the last word is padded
+        // with 16bits of garbage.
+        if len & 1 != 0 {
+            self.buffer[len] = 0;
+        }
+
+        self.index = ((len + 1) & !1) as u32;
+    }
+
+    /// Number of 32bit words in the buffer
+    fn len_words(&self) -> u32 {
+        self.index / 2
+    }
+
+    /// Return the 32bit word at `index` in the buffer
+    fn word(&self, index: u32) -> u32 {
+        let i = index as usize * 2;
+
+        let lo = self.buffer[i] as u32;
+        let hi = self.buffer[i + 1] as u32;
+
+        lo | (hi << 16)
+    }
 }
 
 impl Encodable for ImageBuffer {
@@ -1897,7 +2060,7 @@ impl Decodable for ImageBuffer {
                     2,
                     |d| {
                         d.read_seq(|d, len| {
-                            if len >= VRAM_SIZE_PIXELS {
+                            if len > VRAM_SIZE_PIXELS {
                                 return Err(
                                     d.error("wrong image buffer length"));
                             }
@@ -1934,3 +2097,53 @@ pub enum VideoClock {
     Ntsc,
     Pal,
 }
+
+#[test]
+fn image_store() {
+    use self::software::SoftwareRenderer;
+
+    let mut gpu = Gpu::new(VideoClock::Ntsc);
+    let mut renderer = SoftwareRenderer::new();
+
+    // Upload a 3x1 image at (16, 8)
+    for &w in &[0xa0000000, 0x00080010, 0x00010003,
+                0x22221111, 0x00003333] {
+        gpu.gp0(&mut renderer, w);
+    }
+
+    assert!(renderer.pixel(18, 8) == 0x3333);
+    assert!(gpu.status() & (1 << 27) == 0);
+
+    // Read it back
+    for &w in &[0xc0000000, 0x00080010, 0x00010003] {
+        gpu.gp0(&mut renderer, w);
+    }
+
+    assert!(gpu.status() & (1 << 27) != 0);
+
+    // The DMA uses the same path as GPUREAD
+    assert!(gpu.dma_read_word() == 0x22221111);
+    assert!(gpu.status() & (1 << 27) != 0);
+
+    // Odd number of pixels, the last word is padded
+    assert!(gpu.read() == 0x00003333);
+    assert!(gpu.status() & (1 << 27) == 0);
+
+    // The last word remains latched
+    assert!(gpu.read() == 0x00003333);
+}
+
+#[test]
+fn full_vram_image_buffer_savestate() {
+    use rustc_serialize::json;
+
+    // GP0(0xC0) of the whole VRAM fills the store buffer completely
+    let mut ib = ImageBuffer::new();
+
+    ib.reset(0, 0, 1024, 512);
+    ib.index = VRAM_SIZE_PIXELS as u32;
+
+    let ib: ImageBuffer = json::decode(&json::encode(&ib).unwrap()).unwrap();
+
+    assert!(ib.index as usize == VRAM_SIZE_PIXELS);
+}
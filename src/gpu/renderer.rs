@@ -11,15 +11,41 @@ pub trait Renderer {
     fn push_triangle(&mut self, &PrimitiveAttributes, &[Vertex; 3]);
     fn push_quad(&mut self, &PrimitiveAttributes, &[Vertex; 4]);
 
+    /// Fill a rectangle in VRAM with a solid color. Unlike the other
+    /// drawing commands it's not affected by the mask bit settings:
+    /// masked pixels are overwritten and the mask bit is always
+    /// cleared.
     fn fill_rect(&mut self,
                  color: [u8; 3],
                  top_left: (u16, u16),
                  dimensions: (u16, u16));
 
+    /// Upload a rectangle of pixels to the VRAM (used for CPU to
+    /// VRAM transfers). The pixels are stored line by line,
+    /// coordinates wrap around the VRAM.
     fn load_image(&mut self,
                   top_left: (u16, u16),
                   dimensions: (u16, u16),
-                  pixel_buffer: &[u16]);
+                  pixel_buffer: &[u16],
+                  mask: MaskSettings);
+
+    /// Read back a rectangle of VRAM pixels into `pixel_buffer`
+    /// (used for VRAM to CPU transfers). The pixels are stored line
+    /// by line, coordinates wrap around the VRAM.
+    fn store_image(&mut self,
+                   top_left: (u16, u16),
+                   dimensions: (u16, u16),
+                   pixel_buffer: &mut [u16]);
+
+    /// Copy a rectangle of VRAM pixels from `src_top_left` to
+    /// `dst_top_left`. The two areas can overlap, in which case the
+    /// destination must end up with the original contents of the
+    /// source. Coordinates wrap around the VRAM.
+    fn copy_rect(&mut self,
+                 src_top_left: (u16, u16),
+                 dst_top_left: (u16, u16),
+                 dimensions: (u16, u16),
+                 mask: MaskSettings);
 }
 
 pub struct Vertex {
@@ -76,6 +102,84 @@ pub struct PrimitiveAttributes {
     pub clut: [u16; 2],
     /// True if the primitive is dithered.
     pub dither: bool,
+    /// Texture window applied to the texture coordinates of textured
+    /// primitives.
+    pub texture_window: TextureWindow,
+    /// Mask bit settings used when writing the primitive's pixels to
+    /// the VRAM.
+    pub mask: MaskSettings,
+}
+
+/// Texture window configuration, set by GP0(0xE2). All the values
+/// are expressed in 8 pixel steps.
+#[derive(Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct TextureWindow {
+    /// Horizontal mask, bits set here are replaced by `x_offset`
+    pub x_mask: u8,
+    /// Vertical mask, bits set here are replaced by `y_offset`
+    pub y_mask: u8,
+    /// Horizontal offset
+    pub x_offset: u8,
+    /// Vertical offset
+    pub y_offset: u8,
+}
+
+impl TextureWindow {
+    /// Texture window that leaves the texture coordinates untouched
+    pub fn none() -> TextureWindow {
+        TextureWindow {
+            x_mask: 0,
+            y_mask: 0,
+            x_offset: 0,
+            y_offset: 0,
+        }
+    }
+
+    /// Apply the texture window to the texture coordinates `u` and
+    /// `v` (within the 256x256 texture page)
+    pub fn apply(&self, u: u8, v: u8) -> (u8, u8) {
+        let x_mask = self.x_mask << 3;
+        let y_mask = self.y_mask << 3;
+        let x_offset = (self.x_offset << 3) & x_mask;
+        let y_offset = (self.y_offset << 3) & y_mask;
+
+        ((u & !x_mask) | x_offset, (v & !y_mask) | y_offset)
+    }
+}
+
+/// Mask bit configuration, set by GP0(0xE6)
+#[derive(Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct MaskSettings {
+    /// Force the "mask" bit (MSB) of the pixels written to VRAM to 1
+    pub force_set_mask_bit: bool,
+    /// Don't overwrite pixels which have the "mask" bit set
+    pub preserve_masked_pixels: bool,
+}
+
+impl MaskSettings {
+    /// Mask settings that leave the VRAM's mask bits alone
+    pub fn none() -> MaskSettings {
+        MaskSettings {
+            force_set_mask_bit: false,
+            preserve_masked_pixels: false,
+        }
+    }
+
+    /// Return true if the pixel `vram_pixel` currently in VRAM can
+    /// be overwritten
+    pub fn can_write(&self, vram_pixel: u16) -> bool {
+        !self.preserve_masked_pixels || vram_pixel & 0x8000 == 0
+    }
+
+    /// Return the value of the mask bit (as bit 15) for a new pixel
+    /// whose own mask bit is `pixel_mask`
+    pub fn mask_bit(&self, pixel_mask: u16) -> u16 {
+        if self.force_set_mask_bit {
+            0x8000
+        } else {
+            pixel_mask & 0x8000
+        }
+    }
 }
 
 /// Primitive texturing methods
@@ -112,3 +216,57 @@ pub enum TextureDepth {
     /// 16 bits per pixel, truecolor
     T16Bpp = 2,
 }
+
+/// Renderer discarding all the commands, used to run the tests
+#[cfg(test)]
+pub struct DummyRenderer;
+
+#[cfg(test)]
+impl Renderer for DummyRenderer {
+    fn set_draw_offset(&mut self, _: i16, _: i16) {
+    }
+
+    fn set_draw_area(&mut self, _: (u16, u16), _: (u16, u16)) {
+    }
+
+    fn set_display_mode(&mut self,
+                        _: (u16, u16),
+                        _: (u16, u16),
+                        _: bool) {
+    }
+
+    fn push_line(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 2]) {
+    }
+
+    fn push_triangle(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 3]) {
+    }
+
+    fn push_quad(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 4]) {
+    }
+
+    fn fill_rect(&mut self,
+                 _: [u8; 3],
+                 _: (u16, u16),
+                 _: (u16, u16)) {
+    }
+
+    fn load_image(&mut self,
+                  _: (u16, u16),
+                  _: (u16, u16),
+                  _: &[u16],
+                  _: MaskSettings) {
+    }
+
+    fn store_image(&mut self,
+                   _: (u16, u16),
+                   _: (u16, u16),
+                   _: &mut [u16]) {
+    }
+
+    fn copy_rect(&mut self,
+                 _: (u16, u16),
+                 _: (u16, u16),
+                 _: (u16, u16),
+                 _: MaskSettings) {
+    }
+}
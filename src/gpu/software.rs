@@ -0,0 +1,848 @@
+//! Software implementation of the `Renderer` trait.
+//!
+//! This renderer doesn't need any external graphics API: it owns an
+//! emulated copy of the 1MB VRAM and rasterizes the primitives
+//! directly into it, following the GPU's 15bit RGB pixel format. It's
+//! not meant to be fast but it's useful to run the emulator headless
+//! and as a reference to compare the output of other renderers.
+
+use super::renderer::{Renderer, PrimitiveAttributes, Vertex, MaskSettings};
+use super::renderer::{BlendMode, SemiTransparencyMode, TextureDepth};
+use super::{VRAM_WIDTH_PIXELS, VRAM_HEIGHT, VRAM_SIZE_PIXELS};
+
+pub struct SoftwareRenderer {
+    /// Emulated VRAM: 1024x512 16bit pixels
+    vram: Box<[u16; VRAM_SIZE_PIXELS]>,
+    /// Offset added to the coordinates of every vertex
+    draw_offset: (i16, i16),
+    /// Top-left corner of the drawing area (inclusive)
+    draw_area_top_left: (u16, u16),
+    /// Bottom-right corner of the drawing area (inclusive)
+    draw_area_bottom_right: (u16, u16),
+    /// Coordinates of the top-left corner of the displayed area in
+    /// VRAM
+    display_top_left: (u16, u16),
+    /// Resolution of the displayed area
+    display_resolution: (u16, u16),
+    /// True if the display is configured in 24bpp mode
+    display_24bpp: bool,
+}
+
+impl SoftwareRenderer {
+    pub fn new() -> SoftwareRenderer {
+        SoftwareRenderer {
+            vram: box_array![0; VRAM_SIZE_PIXELS],
+            draw_offset: (0, 0),
+            draw_area_top_left: (0, 0),
+            draw_area_bottom_right: (0, 0),
+            display_top_left: (0, 0),
+            display_resolution: (256, 240),
+            display_24bpp: false,
+        }
+    }
+
+    /// Return the raw contents of the VRAM, line by line
+    pub fn vram(&self) -> &[u16] {
+        &*self.vram
+    }
+
+    /// Return the value of the VRAM pixel at `(x, y)`. Coordinates
+    /// wrap around the VRAM.
+    pub fn pixel(&self, x: u16, y: u16) -> u16 {
+        self.vram[vram_index(x, y)]
+    }
+
+    /// Return the resolution of the displayed area
+    pub fn display_resolution(&self) -> (u16, u16) {
+        self.display_resolution
+    }
+
+    /// Convert the currently displayed portion of the VRAM into 32bit
+    /// `0x00RRGGBB` pixels, line by line. Handles both 15 and 24bpp
+    /// display modes.
+    pub fn display_buffer(&self) -> Vec<u32> {
+        let (left, top) = self.display_top_left;
+        let (width, height) = self.display_resolution;
+
+        let mut buffer = Vec::with_capacity(width as usize * height as usize);
+
+        for y in 0..height {
+            let y = top.wrapping_add(y);
+
+            for x in 0..width {
+                let p =
+                    if self.display_24bpp {
+                        // In 24bpp mode the pixels are packed as 3
+                        // bytes in VRAM, we have to fetch them
+                        // halfword by halfword.
+                        let offset = x as u32 * 3;
+
+                        let byte = |o: u32| {
+                            let hx = left.wrapping_add((o / 2) as u16);
+                            let h = self.pixel(hx, y);
+
+                            ((h >> ((o & 1) * 8)) & 0xff) as u32
+                        };
+
+                        let r = byte(offset);
+                        let g = byte(offset + 1);
+                        let b = byte(offset + 2);
+
+                        (r << 16) | (g << 8) | b
+                    } else {
+                        let p = self.pixel(left.wrapping_add(x), y);
+
+                        let (r, g, b) = rgb555_components(p);
+
+                        let r = (r << 3) | (r >> 2);
+                        let g = (g << 3) | (g >> 2);
+                        let b = (b << 3) | (b >> 2);
+
+                        ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+                    };
+
+                buffer.push(p);
+            }
+        }
+
+        buffer
+    }
+
+    /// Return true if `(x, y)` is within the drawing area
+    fn in_draw_area(&self, x: i32, y: i32) -> bool {
+        let (left, top) = self.draw_area_top_left;
+        let (right, bottom) = self.draw_area_bottom_right;
+
+        x >= left as i32 && x <= right as i32 &&
+        y >= top as i32 && y <= bottom as i32
+    }
+
+    /// Rasterize a single triangle. Pixels are drawn if their center
+    /// is within the triangle, pixels right on an edge are only
+    /// drawn for top and left edges so that adjacent triangles never
+    /// overlap.
+    fn rasterize_triangle(&mut self,
+                          attributes: &PrimitiveAttributes,
+                          vertices: [&Vertex; 3]) {
+        let (off_x, off_y) = self.draw_offset;
+
+        let position = |v: &Vertex| {
+            (v.position[0] as i32 + off_x as i32,
+             v.position[1] as i32 + off_y as i32)
+        };
+
+        let mut p = [position(vertices[0]),
+                     position(vertices[1]),
+                     position(vertices[2])];
+        let mut v = vertices;
+
+        let min_x = p.iter().map(|p| p.0).min().unwrap();
+        let max_x = p.iter().map(|p| p.0).max().unwrap();
+        let min_y = p.iter().map(|p| p.1).min().unwrap();
+        let max_y = p.iter().map(|p| p.1).max().unwrap();
+
+        // The GPU doesn't draw primitives that are too large
+        if max_x - min_x >= VRAM_WIDTH_PIXELS as i32 ||
+           max_y - min_y >= VRAM_HEIGHT as i32 {
+            return;
+        }
+
+        let mut area = edge(p[0], p[1], p[2]);
+
+        if area == 0 {
+            // Degenerate triangle, nothing to draw
+            return;
+        }
+
+        if area < 0 {
+            // Make sure the vertices are always in the same order so
+            // that the edge functions are positive inside the
+            // triangle
+            p.swap(1, 2);
+            v.swap(1, 2);
+            area = -area;
+        }
+
+        let (left, top) = self.draw_area_top_left;
+        let (right, bottom) = self.draw_area_bottom_right;
+
+        let x_start = ::std::cmp::max(min_x, left as i32);
+        let x_end = ::std::cmp::min(max_x, right as i32);
+        let y_start = ::std::cmp::max(min_y, top as i32);
+        let y_end = ::std::cmp::min(max_y, bottom as i32);
+
+        let area = area as i64;
+
+        for y in y_start..(y_end + 1) {
+            for x in x_start..(x_end + 1) {
+                let w0 = edge(p[1], p[2], (x, y));
+                let w1 = edge(p[2], p[0], (x, y));
+                let w2 = edge(p[0], p[1], (x, y));
+
+                if !edge_covers(w0, p[1], p[2]) ||
+                   !edge_covers(w1, p[2], p[0]) ||
+                   !edge_covers(w2, p[0], p[1]) {
+                    continue;
+                }
+
+                let w = [w0 as i64, w1 as i64, w2 as i64];
+
+                let interpolate = |a: [i64; 3]| {
+                    (w[0] * a[0] + w[1] * a[1] + w[2] * a[2]) / area
+                };
+
+                let color = [
+                    interpolate([v[0].color[0] as i64,
+                                 v[1].color[0] as i64,
+                                 v[2].color[0] as i64]),
+                    interpolate([v[0].color[1] as i64,
+                                 v[1].color[1] as i64,
+                                 v[2].color[1] as i64]),
+                    interpolate([v[0].color[2] as i64,
+                                 v[1].color[2] as i64,
+                                 v[2].color[2] as i64]),
+                    ];
+
+                let u = interpolate([v[0].texture_coord[0] as i64,
+                                     v[1].texture_coord[0] as i64,
+                                     v[2].texture_coord[0] as i64]);
+                let t = interpolate([v[0].texture_coord[1] as i64,
+                                     v[1].texture_coord[1] as i64,
+                                     v[2].texture_coord[1] as i64]);
+
+                let color = [color[0] as i32, color[1] as i32, color[2] as i32];
+
+                self.shade_pixel(attributes, x, y, color, (u as u16, t as u16));
+            }
+        }
+    }
+
+    /// Compute the final value of a pixel and write it to the VRAM
+    fn shade_pixel(&mut self,
+                   attributes: &PrimitiveAttributes,
+                   x: i32,
+                   y: i32,
+                   color: [i32; 3],
+                   texture_coord: (u16, u16)) {
+
+        let (color, texel_mask, semi_transparent) =
+            match attributes.blend_mode {
+                BlendMode::None => (color, 0, attributes.semi_transparent),
+                mode => {
+                    let texel = self.texel(attributes, texture_coord);
+
+                    if texel == 0 {
+                        // Fully transparent texel
+                        return;
+                    }
+
+                    let (tr, tg, tb) = rgb555_components(texel);
+
+                    let texel_color = [tr as i32, tg as i32, tb as i32];
+
+                    let color =
+                        match mode {
+                            BlendMode::Raw => [
+                                texel_color[0] << 3,
+                                texel_color[1] << 3,
+                                texel_color[2] << 3,
+                                ],
+                            // The vertex color is a multiplier where
+                            // 0x80 leaves the texel untouched
+                            _ => [
+                                ((texel_color[0] << 3) * color[0]) >> 7,
+                                ((texel_color[1] << 3) * color[1]) >> 7,
+                                ((texel_color[2] << 3) * color[2]) >> 7,
+                                ],
+                        };
+
+                    // For textured primitives only the texels with
+                    // the MSB set are semi-transparent
+                    let mask = texel & 0x8000;
+                    let semi_transparent =
+                        attributes.semi_transparent && mask != 0;
+
+                    (color, mask, semi_transparent)
+                }
+            };
+
+        let dither =
+            attributes.dither && attributes.blend_mode != BlendMode::Raw;
+
+        let dither_offset =
+            if dither {
+                DITHER_TABLE[(y & 3) as usize][(x & 3) as usize]
+            } else {
+                0
+            };
+
+        let to_5bits = |c: i32| {
+            let c = c + dither_offset;
+
+            let c =
+                if c < 0 {
+                    0
+                } else if c > 0xff {
+                    0xff
+                } else {
+                    c
+                };
+
+            (c >> 3) as u16
+        };
+
+        let mut r = to_5bits(color[0]);
+        let mut g = to_5bits(color[1]);
+        let mut b = to_5bits(color[2]);
+
+        let index = vram_index(x as u16, y as u16);
+
+        let mask = attributes.mask;
+
+        if !mask.can_write(self.vram[index]) {
+            return;
+        }
+
+        if semi_transparent {
+            let (br, bg, bb) = rgb555_components(self.vram[index]);
+
+            let mode = attributes.semi_transparency_mode;
+
+            r = blend(mode, br, r);
+            g = blend(mode, bg, g);
+            b = blend(mode, bb, b);
+        }
+
+        self.vram[index] =
+            r | (g << 5) | (b << 10) | mask.mask_bit(texel_mask);
+    }
+
+    /// Fetch the texel at `texture_coord` within the current texture
+    /// page, going through the CLUT for paletted textures
+    fn texel(&self,
+             attributes: &PrimitiveAttributes,
+             texture_coord: (u16, u16)) -> u16 {
+        let page_x = attributes.texture_page[0];
+        let page_y = attributes.texture_page[1];
+        let clut_x = attributes.clut[0];
+        let clut_y = attributes.clut[1];
+
+        // Texture pages are 256x256 texels big and wrap around
+        let (u, v) =
+            attributes.texture_window.apply(texture_coord.0 as u8,
+                                            texture_coord.1 as u8);
+
+        let u = u as u16;
+        let v = v as u16;
+
+        let y = page_y + v;
+
+        match attributes.texture_depth {
+            TextureDepth::T4Bpp => {
+                let word = self.pixel(page_x + u / 4, y);
+                let index = (word >> ((u & 3) * 4)) & 0xf;
+
+                self.pixel(clut_x + index, clut_y)
+            }
+            TextureDepth::T8Bpp => {
+                let word = self.pixel(page_x + u / 2, y);
+                let index = (word >> ((u & 1) * 8)) & 0xff;
+
+                self.pixel(clut_x + index, clut_y)
+            }
+            TextureDepth::T16Bpp => self.pixel(page_x + u, y),
+        }
+    }
+}
+
+impl Renderer for SoftwareRenderer {
+    fn set_draw_offset(&mut self, x: i16, y: i16) {
+        self.draw_offset = (x, y);
+    }
+
+    fn set_draw_area(&mut self, top_left: (u16, u16), dimensions: (u16, u16)) {
+        // The GPU gives us the coordinates of the bottom-right corner
+        // of the drawing area, not its size.
+        self.draw_area_top_left = top_left;
+        self.draw_area_bottom_right = dimensions;
+    }
+
+    fn set_display_mode(&mut self,
+                        top_left: (u16, u16),
+                        resolution: (u16, u16),
+                        depth_24bpp: bool) {
+        self.display_top_left = top_left;
+        self.display_resolution = resolution;
+        self.display_24bpp = depth_24bpp;
+    }
+
+    fn push_line(&mut self,
+                 attributes: &PrimitiveAttributes,
+                 vertices: &[Vertex; 2]) {
+        let (off_x, off_y) = self.draw_offset;
+
+        let x0 = vertices[0].position[0] as i32 + off_x as i32;
+        let y0 = vertices[0].position[1] as i32 + off_y as i32;
+        let x1 = vertices[1].position[0] as i32 + off_x as i32;
+        let y1 = vertices[1].position[1] as i32 + off_y as i32;
+
+        let dx = x1 - x0;
+        let dy = y1 - y0;
+
+        if dx.abs() >= VRAM_WIDTH_PIXELS as i32 ||
+           dy.abs() >= VRAM_HEIGHT as i32 {
+            return;
+        }
+
+        // Lines are drawn one pixel per step along the major axis,
+        // both ends included.
+        let steps = ::std::cmp::max(dx.abs(), dy.abs());
+
+        for i in 0..(steps + 1) {
+            let (x, y, color) =
+                if steps == 0 {
+                    let c = vertices[0].color;
+
+                    (x0, y0, [c[0] as i32, c[1] as i32, c[2] as i32])
+                } else {
+                    let lerp = |a: i32, b: i32| {
+                        a + ((b - a) * i * 2 + steps) / (steps * 2)
+                    };
+
+                    let c0 = vertices[0].color;
+                    let c1 = vertices[1].color;
+
+                    (lerp(x0, x1),
+                     lerp(y0, y1),
+                     [lerp(c0[0] as i32, c1[0] as i32),
+                      lerp(c0[1] as i32, c1[1] as i32),
+                      lerp(c0[2] as i32, c1[2] as i32)])
+                };
+
+            if self.in_draw_area(x, y) {
+                self.shade_pixel(attributes, x, y, color, (0, 0));
+            }
+        }
+    }
+
+    fn push_triangle(&mut self,
+                     attributes: &PrimitiveAttributes,
+                     vertices: &[Vertex; 3]) {
+        self.rasterize_triangle(attributes,
+                                [&vertices[0], &vertices[1], &vertices[2]]);
+    }
+
+    fn push_quad(&mut self,
+                 attributes: &PrimitiveAttributes,
+                 vertices: &[Vertex; 4]) {
+        // Quads are drawn as two triangles sharing the 2nd and 3rd
+        // vertices
+        self.rasterize_triangle(attributes,
+                                [&vertices[0], &vertices[1], &vertices[2]]);
+        self.rasterize_triangle(attributes,
+                                [&vertices[1], &vertices[2], &vertices[3]]);
+    }
+
+    fn fill_rect(&mut self,
+                 color: [u8; 3],
+                 top_left: (u16, u16),
+                 dimensions: (u16, u16)) {
+        let r = (color[0] >> 3) as u16;
+        let g = (color[1] >> 3) as u16;
+        let b = (color[2] >> 3) as u16;
+
+        let pixel = r | (g << 5) | (b << 10);
+
+        let (left, top) = top_left;
+        let (width, height) = dimensions;
+
+        // Fill rect ignores the drawing area and offset
+        for y in 0..height {
+            for x in 0..width {
+                let index = vram_index(left + x, top + y);
+
+                self.vram[index] = pixel;
+            }
+        }
+    }
+
+    fn load_image(&mut self,
+                  top_left: (u16, u16),
+                  dimensions: (u16, u16),
+                  pixel_buffer: &[u16],
+                  mask: MaskSettings) {
+        let (left, top) = top_left;
+        let (width, height) = dimensions;
+
+        let mut pixels = pixel_buffer.iter();
+
+        for y in 0..height {
+            for x in 0..width {
+                let index = vram_index(left.wrapping_add(x),
+                                       top.wrapping_add(y));
+
+                let p =
+                    match pixels.next() {
+                        Some(&p) => p,
+                        None => return,
+                    };
+
+                if mask.can_write(self.vram[index]) {
+                    self.vram[index] = (p & 0x7fff) | mask.mask_bit(p);
+                }
+            }
+        }
+    }
+
+    fn store_image(&mut self,
+                   top_left: (u16, u16),
+                   dimensions: (u16, u16),
+                   pixel_buffer: &mut [u16]) {
+        let (left, top) = top_left;
+        let (width, height) = dimensions;
+
+        let mut pixels = pixel_buffer.iter_mut();
+
+        for y in 0..height {
+            for x in 0..width {
+                match pixels.next() {
+                    Some(p) => *p = self.pixel(left.wrapping_add(x),
+                                               top.wrapping_add(y)),
+                    None => return,
+                }
+            }
+        }
+    }
+
+    fn copy_rect(&mut self,
+                 src_top_left: (u16, u16),
+                 dst_top_left: (u16, u16),
+                 dimensions: (u16, u16),
+                 mask: MaskSettings) {
+        let (src_x, src_y) = src_top_left;
+        let (dst_x, dst_y) = dst_top_left;
+        let (width, height) = dimensions;
+
+        // Copy the source to a temporary buffer first in order to
+        // handle overlapping areas
+        let mut buffer = Vec::with_capacity(width as usize * height as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                buffer.push(self.pixel(src_x.wrapping_add(x),
+                                       src_y.wrapping_add(y)));
+            }
+        }
+
+        let mut pixels = buffer.iter();
+
+        for y in 0..height {
+            for x in 0..width {
+                let index = vram_index(dst_x.wrapping_add(x),
+                                       dst_y.wrapping_add(y));
+
+                let p = *pixels.next().unwrap();
+
+                if mask.can_write(self.vram[index]) {
+                    self.vram[index] = (p & 0x7fff) | mask.mask_bit(p);
+                }
+            }
+        }
+    }
+}
+
+/// Return the index of pixel `(x, y)` in the VRAM buffer. The
+/// coordinates wrap around.
+fn vram_index(x: u16, y: u16) -> usize {
+    let x = (x % VRAM_WIDTH_PIXELS) as usize;
+    let y = (y % VRAM_HEIGHT) as usize;
+
+    y * VRAM_WIDTH_PIXELS as usize + x
+}
+
+/// Split a 1555 pixel into its three 5bit color components
+fn rgb555_components(pixel: u16) -> (u16, u16, u16) {
+    (pixel & 0x1f, (pixel >> 5) & 0x1f, (pixel >> 10) & 0x1f)
+}
+
+/// Edge function: positive if `p` is on the inner side of the edge
+/// `a -> b` (for a triangle with a positive area).
+fn edge(a: (i32, i32), b: (i32, i32), p: (i32, i32)) -> i32 {
+    (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+}
+
+/// Return true if a pixel with edge function value `w` for the edge
+/// `a -> b` should be drawn. Pixels sitting right on the edge are
+/// only drawn for top and left edges.
+fn edge_covers(w: i32, a: (i32, i32), b: (i32, i32)) -> bool {
+    if w != 0 {
+        return w > 0;
+    }
+
+    let dx = b.0 - a.0;
+    let dy = b.1 - a.1;
+
+    dy < 0 || (dy == 0 && dx > 0)
+}
+
+/// Apply a semi-transparency equation on a single 5bit color
+/// component. `back` is the current value in the framebuffer,
+/// `front` the value of the primitive.
+fn blend(mode: SemiTransparencyMode, back: u16, front: u16) -> u16 {
+    let back = back as i32;
+    let front = front as i32;
+
+    let v =
+        match mode {
+            SemiTransparencyMode::Average => (back + front) >> 1,
+            SemiTransparencyMode::Add => back + front,
+            SemiTransparencyMode::SubstractSource => back - front,
+            SemiTransparencyMode::AddQuarterSource => back + (front >> 2),
+        };
+
+    if v < 0 {
+        0
+    } else if v > 0x1f {
+        0x1f
+    } else {
+        v as u16
+    }
+}
+
+/// Dithering offsets added to the 8bit color components before
+/// they're truncated to 5 bits, indexed by `[y & 3][x & 3]`
+const DITHER_TABLE: [[i32; 4]; 4] = [
+    [-4,  0, -3,  1],
+    [ 2, -2,  3, -1],
+    [-3,  1, -4,  0],
+    [ 3, -1,  2, -2],
+    ];
+
+#[cfg(test)]
+fn test_attributes(blend_mode: BlendMode) -> PrimitiveAttributes {
+    PrimitiveAttributes {
+        semi_transparent: false,
+        semi_transparency_mode: SemiTransparencyMode::Average,
+        blend_mode: blend_mode,
+        texture_page: [0, 0],
+        texture_depth: TextureDepth::T4Bpp,
+        clut: [0, 0],
+        dither: false,
+        texture_window: super::renderer::TextureWindow::none(),
+        mask: MaskSettings::none(),
+    }
+}
+
+#[test]
+fn monochrome_quad() {
+    let mut renderer = SoftwareRenderer::new();
+
+    renderer.set_draw_area((0, 0), (1023, 511));
+    renderer.set_draw_offset(10, 20);
+
+    let red = [0xff, 0, 0];
+
+    let vertices = [
+        Vertex::new([0, 0], red),
+        Vertex::new([4, 0], red),
+        Vertex::new([0, 4], red),
+        Vertex::new([4, 4], red),
+        ];
+
+    renderer.push_quad(&test_attributes(BlendMode::None), &vertices);
+
+    // Right and bottom edges are excluded
+    for y in 18..26 {
+        for x in 8..16 {
+            let inside = x >= 10 && x < 14 && y >= 20 && y < 24;
+
+            let expected = if inside { 0x1f } else { 0 };
+
+            assert!(renderer.pixel(x, y) == expected);
+        }
+    }
+}
+
+#[test]
+fn semi_transparent_average() {
+    let mut renderer = SoftwareRenderer::new();
+
+    renderer.set_draw_area((0, 0), (1023, 511));
+    renderer.fill_rect([0xf8, 0, 0], (0, 0), (16, 1));
+
+    let mut attributes = test_attributes(BlendMode::None);
+    attributes.semi_transparent = true;
+
+    let blue = [0, 0, 0xf8];
+
+    let vertices = [
+        Vertex::new([0, 0], blue),
+        Vertex::new([1, 0], blue),
+        Vertex::new([0, 1], blue),
+        Vertex::new([1, 1], blue),
+        ];
+
+    renderer.push_quad(&attributes, &vertices);
+
+    assert!(renderer.pixel(0, 0) == (0xf | (0xf << 10)));
+    assert!(renderer.pixel(1, 0) == 0x1f);
+}
+
+#[test]
+fn copy_rect_overlap() {
+    let mut renderer = SoftwareRenderer::new();
+
+    renderer.load_image((0, 0), (4, 1), &[1, 2, 0x8003, 4],
+                        MaskSettings::none());
+
+    let mask = MaskSettings {
+        force_set_mask_bit: false,
+        preserve_masked_pixels: true,
+    };
+
+    // Overlapping copy, one pixel to the right. The masked pixel at
+    // x = 2 must not be overwritten.
+    renderer.copy_rect((0, 0), (1, 0), (4, 1), mask);
+
+    assert!(renderer.pixel(0, 0) == 1);
+    assert!(renderer.pixel(1, 0) == 1);
+    assert!(renderer.pixel(2, 0) == 0x8003);
+    assert!(renderer.pixel(3, 0) == 0x8003);
+    assert!(renderer.pixel(4, 0) == 4);
+}
+
+#[test]
+fn mask_bit() {
+    let mut renderer = SoftwareRenderer::new();
+
+    renderer.set_draw_area((0, 0), (1023, 511));
+
+    let set_mask = MaskSettings {
+        force_set_mask_bit: true,
+        preserve_masked_pixels: false,
+    };
+
+    let check_mask = MaskSettings {
+        force_set_mask_bit: false,
+        preserve_masked_pixels: true,
+    };
+
+    renderer.load_image((0, 0), (2, 1), &[0x001f, 0x03e0], set_mask);
+
+    assert!(renderer.pixel(0, 0) == 0x801f);
+    assert!(renderer.pixel(1, 0) == 0x83e0);
+
+    renderer.fill_rect([0, 0, 0xff], (1, 0), (1, 1));
+
+    // Fill rect ignores the mask bit
+    assert!(renderer.pixel(1, 0) == 0x7c00);
+
+    let mut attributes = test_attributes(BlendMode::None);
+    attributes.mask = check_mask;
+
+    let white = [0xff, 0xff, 0xff];
+
+    renderer.push_quad(&attributes,
+                       &[Vertex::new([0, 0], white),
+                         Vertex::new([2, 0], white),
+                         Vertex::new([0, 1], white),
+                         Vertex::new([2, 1], white)]);
+
+    // The masked pixel is preserved
+    assert!(renderer.pixel(0, 0) == 0x801f);
+    assert!(renderer.pixel(1, 0) == 0x7fff);
+}
+
+#[test]
+fn textured_4bpp_clut() {
+    let mut renderer = SoftwareRenderer::new();
+
+    renderer.set_draw_area((0, 0), (1023, 511));
+
+    // CLUT at (0, 256): entry 0 is transparent, entry 1 is green
+    // with the mask bit set, entry 2 is white
+    renderer.load_image((0, 256), (3, 1), &[0x0000, 0x83e0, 0x7fff],
+                        MaskSettings::none());
+    // Texture page at (64, 0): texels 1, 2, 0, 2
+    renderer.load_image((64, 0), (1, 1), &[0x2021], MaskSettings::none());
+
+    let mut attributes = test_attributes(BlendMode::Raw);
+    attributes.texture_page = [64, 0];
+    attributes.clut = [0, 256];
+
+    let color = [0x80, 0x80, 0x80];
+
+    let vertices = [
+        Vertex::new_textured([100, 100], color, [0, 0]),
+        Vertex::new_textured([104, 100], color, [4, 0]),
+        Vertex::new_textured([100, 101], color, [0, 1]),
+        Vertex::new_textured([104, 101], color, [4, 1]),
+        ];
+
+    renderer.push_quad(&attributes, &vertices);
+
+    assert!(renderer.pixel(100, 100) == 0x83e0);
+    assert!(renderer.pixel(101, 100) == 0x7fff);
+    assert!(renderer.pixel(102, 100) == 0);
+    assert!(renderer.pixel(103, 100) == 0x7fff);
+}
+
+#[test]
+fn texture_window() {
+    use super::renderer::TextureWindow;
+
+    // Mask bit 3 of U and V, replace it by 1 for U and 0 for V
+    let window = TextureWindow {
+        x_mask: 1,
+        y_mask: 1,
+        x_offset: 1,
+        y_offset: 0,
+    };
+
+    assert!(window.apply(0x02, 0x0a) == (0x0a, 0x02));
+    assert!(window.apply(0xf1, 0xff) == (0xf9, 0xf7));
+
+    // Offset bits outside of the mask are ignored
+    let window = TextureWindow {
+        x_mask: 0x02,
+        y_mask: 0,
+        x_offset: 0x03,
+        y_offset: 0x1f,
+    };
+
+    assert!(window.apply(0x00, 0x05) == (0x10, 0x05));
+
+    let mut renderer = SoftwareRenderer::new();
+
+    renderer.set_draw_area((0, 0), (1023, 511));
+
+    // 16bpp texture page at (64, 0): texel (u, 0) is 0x100 + u
+    let row: Vec<u16> = (0..16).map(|u| 0x100 + u).collect();
+    renderer.load_image((64, 0), (16, 1), &row, MaskSettings::none());
+
+    let mut attributes = test_attributes(BlendMode::Raw);
+    attributes.texture_page = [64, 0];
+    attributes.texture_depth = TextureDepth::T16Bpp;
+    attributes.texture_window = TextureWindow {
+        x_mask: 1,
+        y_mask: 1,
+        x_offset: 1,
+        y_offset: 0,
+    };
+
+    let color = [0x80, 0x80, 0x80];
+
+    // Sample (0, 8) to (4, 9), the window moves it to (8, 0)
+    let vertices = [
+        Vertex::new_textured([100, 100], color, [0, 8]),
+        Vertex::new_textured([104, 100], color, [4, 8]),
+        Vertex::new_textured([100, 101], color, [0, 9]),
+        Vertex::new_textured([104, 101], color, [4, 9]),
+        ];
+
+    renderer.push_quad(&attributes, &vertices);
+
+    for x in 0..4 {
+        assert!(renderer.pixel(100 + x, 100) == 0x108 + x);
+    }
+}
@@ -16,6 +16,10 @@ pub enum Interrupt {
     Timer2 = 6,
     /// Gamepad and Memory Card controller interrupt
     PadMemCard = 7,
+    /// Serial port (SIO1)
+    Sio = 8,
+    /// Sound Processing Unit
+    Spu = 9,
 }
 
 #[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
@@ -63,7 +67,9 @@ impl InterruptState {
                           Interrupt::Timer0,
                           Interrupt::Timer1,
                           Interrupt::Timer2,
-                          Interrupt::PadMemCard];
+                          Interrupt::PadMemCard,
+                          Interrupt::Sio,
+                          Interrupt::Spu];
 
         let rem = supported.iter().fold(mask,
                                         |mask, &it| mask & !(1 << it as u16));
@@ -26,12 +26,13 @@ pub mod padmemcard;
 pub mod debugger;
 pub mod assembler;
 pub mod parallel_io;
+pub mod sio1;
 pub mod debug_uart;
+pub mod spu;
+pub mod mdec;
 
 mod interrupt;
 mod timekeeper;
-mod spu;
-mod mdec;
 
 mod version {
     // VERSION and VERSION_CSTR are generated by build.rs
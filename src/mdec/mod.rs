@@ -1,7 +1,11 @@
+use std::collections::VecDeque;
+
 use memory::Addressable;
 use shared::SharedState;
 use tracer::module_tracer;
 
+pub mod movie;
+
 /// Motion Decoder (sometimes called macroblock or movie decoder).
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct MDec {
@@ -26,6 +30,21 @@ pub struct MDec {
     command_handler: CommandHandler,
     /// Remaining words expected for this command
     command_remaining: u16,
+    /// Coefficients of the block currently being decoded
+    block: Block,
+    /// Index (in zigzag order) of the last coefficient decoded in
+    /// `block`, `None` if we're waiting for the DC coefficient of a
+    /// new block.
+    block_index: Option<u8>,
+    /// Quantization scale of the current block
+    q_scale: u16,
+    /// Decoded Cr and Cb blocks of the current macroblock
+    chroma_blocks: [Block; 2],
+    /// RGB pixels of the current 16x16 macroblock, waiting for the
+    /// 4 luma blocks to be decoded
+    macroblock: Macroblock,
+    /// Decoded pixel data, waiting to be read
+    output_fifo: VecDeque<u32>,
 }
 
 impl MDec {
@@ -42,6 +61,12 @@ impl MDec {
             idct_matrix: IdctMatrix::new(),
             command_handler: CommandHandler(MDec::handle_command),
             command_remaining: 1,
+            block: Block::new(),
+            block_index: None,
+            q_scale: 0,
+            chroma_blocks: [Block::new(), Block::new()],
+            macroblock: Macroblock::new(),
+            output_fifo: VecDeque::new(),
         }
     }
 
@@ -84,13 +109,17 @@ impl MDec {
         // minus 1, or 0xffff if no parameter is expected.
         r |= self.command_remaining.wrapping_sub(1) as u32;
 
-        // XXX Implement bits [18:16]: current block
+        // Bits [18:16]: current block
+        r |= (self.current_block as u32) << 16;
 
         r |= (self.output_bit15 as u32) << 23;
         r |= (self.output_signed as u32) << 24;
         r |= (self.output_depth as u32) << 25;
 
-        // XXX Implement bits 27 and 28: DMA data in/out request
+        // Bit 27: data out request
+        r |= (self.dma_out_request() as u32) << 27;
+        // Bit 28: data in request
+        r |= (self.dma_in_request() as u32) << 28;
 
         // Command busy flag. XXX Probably set for a little while
         // after the last parameter is received whilet he command is
@@ -100,14 +129,48 @@ impl MDec {
 
         r |= (command_pending as u32) << 29;
 
-        // XXX Implement bit 30: data in FIFO full
+        // Bit 30: data in FIFO full. The input is processed as soon
+        // as it's received so the FIFO is never full.
         r |= 0 << 30;
-        // XXX Implement bit 31: data out FIFO empty
-        r |= 1 << 31;
+        // Bit 31: data out FIFO empty
+        r |= (self.output_fifo.is_empty() as u32) << 31;
 
         r
     }
 
+    /// True if the MDEC requests data through DMA channel 0
+    fn dma_in_request(&self) -> bool {
+        self.dma_in_enable
+    }
+
+    /// True if the MDEC requests to send data through DMA channel 1
+    fn dma_out_request(&self) -> bool {
+        self.dma_out_enable && !self.output_fifo.is_empty()
+    }
+
+    /// Return true if a DMA transfer to the MDEC can proceed
+    pub fn dma_in_ready(&self) -> bool {
+        self.dma_in_request()
+    }
+
+    /// Return true if a DMA transfer of `words` words from the MDEC
+    /// can proceed. We don't emulate the DMA block by block so we
+    /// wait until the output FIFO contains the entire transfer.
+    pub fn dma_out_ready(&self, words: u32) -> bool {
+        self.dma_out_request() && self.output_fifo.len() >= words as usize
+    }
+
+    /// DMA read from the output FIFO
+    pub fn dma_read_word(&mut self) -> u32 {
+        match self.output_fifo.pop_front() {
+            Some(w) => w,
+            None => {
+                warn!("MDEC output FIFO read while empty");
+                0
+            }
+        }
+    }
+
     /// Handle writes to the command register
     pub fn command(&mut self, shared: &mut SharedState, cmd: u32) {
 
@@ -153,6 +216,16 @@ impl MDec {
                     true => (32, MDec::handle_color_quant_matrices),
                     false => (16, MDec::handle_monochrome_quant_matrix),
                 },
+                // Decode macroblocks, the 16 LSBs contain the number
+                // of parameter words
+                1 => {
+                    // Color macroblocks start with Cr, monochrome
+                    // ones only contain luma blocks
+                    self.block_index = None;
+                    self.current_block = BlockType::CrLuma;
+
+                    (cmd as u16, MDec::handle_macroblock_data)
+                }
                 3 => (32, MDec::handle_idct_matrix),
                 n => {
                     warn!("Unsupported MDEC opcode {} ({:08x})", n, cmd);
@@ -198,6 +271,319 @@ impl MDec {
         self.idct_matrix[index + 1] = (cmd >> 16) as i16;
     }
 
+    fn handle_macroblock_data(&mut self, cmd: u32) {
+        // The data is made of 16bit run-length encoded coefficients
+        self.decode_rle(cmd as u16);
+        self.decode_rle((cmd >> 16) as u16);
+    }
+
+    /// True if the current output depth is 15 or 24bpp
+    fn is_color(&self) -> bool {
+        match self.output_depth {
+            OutputDepth::D4Bpp | OutputDepth::D8Bpp => false,
+            OutputDepth::D15Bpp | OutputDepth::D24Bpp => true,
+        }
+    }
+
+    /// Decode one run-length encoded coefficient
+    fn decode_rle(&mut self, rle: u16) {
+        // Chroma blocks use the 2nd quantization matrix
+        let quant =
+            match self.current_block {
+                BlockType::CrLuma | BlockType::Cb if self.is_color() => 1,
+                _ => 0,
+            };
+
+        let coef = ((rle << 6) as i16 >> 6) as i32;
+
+        let (index, val) =
+            match self.block_index {
+                None => {
+                    // 0xfe00 is used as padding between blocks
+                    if rle == 0xfe00 {
+                        return;
+                    }
+
+                    // DC coefficient
+                    *self.block = [0; 64];
+                    self.q_scale = rle >> 10;
+
+                    let q = self.quant_matrices[quant][0] as i32;
+
+                    (0, coef * q)
+                }
+                Some(index) => {
+                    // The 6 MSBs contain the number of zero
+                    // coefficients to skip
+                    let index = index as u16 + (rle >> 10) + 1;
+
+                    if index > 63 {
+                        // End of block (usually 0xfe00)
+                        self.block_index = None;
+                        self.end_of_block();
+                        return;
+                    }
+
+                    let q = self.quant_matrices[quant][index as usize] as i32;
+
+                    (index, (coef * q * self.q_scale as i32 + 4) / 8)
+                }
+            };
+
+        let val =
+            if self.q_scale == 0 {
+                coef * 2
+            } else {
+                val
+            };
+
+        let val =
+            if val < -0x400 {
+                -0x400
+            } else if val > 0x3ff {
+                0x3ff
+            } else {
+                val
+            };
+
+        // With a scale of 0 the coefficients are stored in linear
+        // order
+        let pos =
+            if self.q_scale == 0 {
+                index as usize
+            } else {
+                ZIGZAG[index as usize]
+            };
+
+        self.block[pos] = val as i16;
+        self.block_index = Some(index as u8);
+    }
+
+    /// Called when all the coefficients of the current block have
+    /// been received
+    fn end_of_block(&mut self) {
+        let block = self.idct();
+
+        if !self.is_color() {
+            self.output_monochrome(&block);
+            return;
+        }
+
+        self.current_block =
+            match self.current_block {
+                BlockType::CrLuma => {
+                    *self.chroma_blocks[0] = block;
+                    BlockType::Cb
+                }
+                BlockType::Cb => {
+                    *self.chroma_blocks[1] = block;
+                    BlockType::Y1
+                }
+                BlockType::Y1 => {
+                    self.yuv_to_rgb(&block, 0, 0);
+                    BlockType::Y2
+                }
+                BlockType::Y2 => {
+                    self.yuv_to_rgb(&block, 8, 0);
+                    BlockType::Y3
+                }
+                BlockType::Y3 => {
+                    self.yuv_to_rgb(&block, 0, 8);
+                    BlockType::Y4
+                }
+                BlockType::Y4 => {
+                    self.yuv_to_rgb(&block, 8, 8);
+                    self.output_macroblock();
+                    BlockType::CrLuma
+                }
+            };
+    }
+
+    /// Run the inverse discrete cosine transform on the current
+    /// block using the IDCT matrix and return the result.
+    fn idct(&self) -> [i16; 64] {
+        let m = &self.idct_matrix;
+
+        // The matrix coefficients are scaled by 2^15. We keep the
+        // full precision between the two passes and round at the end.
+        let mut tmp = [0i64; 64];
+
+        for x in 0..8 {
+            for y in 0..8 {
+                let mut sum = 0;
+
+                for z in 0..8 {
+                    sum += self.block[y + z * 8] as i64 * m[x + z * 8] as i64;
+                }
+
+                tmp[x + y * 8] = sum;
+            }
+        }
+
+        let mut out = [0; 64];
+
+        for x in 0..8 {
+            for y in 0..8 {
+                let mut sum = 0;
+
+                for z in 0..8 {
+                    sum += tmp[y + z * 8] * m[x + z * 8] as i64;
+                }
+
+                let v = (sum + (1 << 31)) >> 32;
+
+                out[x + y * 8] =
+                    if v < -0x8000 {
+                        -0x8000
+                    } else if v > 0x7fff {
+                        0x7fff
+                    } else {
+                        v as i16
+                    };
+            }
+        }
+
+        out
+    }
+
+    /// Convert the luma block `y` located at `xx`, `yy` in the
+    /// macroblock to RGB using the current chroma blocks
+    fn yuv_to_rgb(&mut self, y: &[i16; 64], xx: usize, yy: usize) {
+        let clamp = |v: i32| {
+            if v < -128 {
+                -128
+            } else if v > 127 {
+                127
+            } else {
+                v
+            }
+        };
+
+        // Unsigned output is obtained by flipping the sign bit
+        let sign_flip =
+            if self.output_signed {
+                0
+            } else {
+                0x80
+            };
+
+        for py in 0..8 {
+            for px in 0..8 {
+                let mx = px + xx;
+                let my = py + yy;
+
+                // Chroma is subsampled 2x2
+                let c = (mx / 2) + (my / 2) * 8;
+
+                let cr = self.chroma_blocks[0][c] as i32;
+                let cb = self.chroma_blocks[1][c] as i32;
+
+                let r = (1436 * cr) >> 10;
+                let g = (-352 * cb - 731 * cr) >> 10;
+                let b = (1815 * cb) >> 10;
+
+                let luma = y[px + py * 8] as i32;
+
+                let r = (clamp(luma + r) as u8 ^ sign_flip) as u32;
+                let g = (clamp(luma + g) as u8 ^ sign_flip) as u32;
+                let b = (clamp(luma + b) as u8 ^ sign_flip) as u32;
+
+                self.macroblock[mx + my * 16] = r | (g << 8) | (b << 16);
+            }
+        }
+    }
+
+    /// Push the current color macroblock to the output FIFO
+    fn output_macroblock(&mut self) {
+        match self.output_depth {
+            OutputDepth::D24Bpp => {
+                // Pixels are packed as RGB byte triplets
+                let mut bytes = Vec::with_capacity(16 * 16 * 3);
+
+                for &p in self.macroblock.iter() {
+                    bytes.push(p as u8);
+                    bytes.push((p >> 8) as u8);
+                    bytes.push((p >> 16) as u8);
+                }
+
+                for b in bytes.chunks(4) {
+                    let w = (b[0] as u32)
+                        | ((b[1] as u32) << 8)
+                        | ((b[2] as u32) << 16)
+                        | ((b[3] as u32) << 24);
+
+                    self.output_fifo.push_back(w);
+                }
+            }
+            OutputDepth::D15Bpp => {
+                let bit15 = (self.output_bit15 as u32) << 15;
+
+                let to_15bpp = |p: u32| {
+                    let r = (p >> 3) & 0x1f;
+                    let g = (p >> 11) & 0x1f;
+                    let b = (p >> 19) & 0x1f;
+
+                    r | (g << 5) | (b << 10) | bit15
+                };
+
+                for pair in self.macroblock.chunks(2) {
+                    let w = to_15bpp(pair[0]) | (to_15bpp(pair[1]) << 16);
+
+                    self.output_fifo.push_back(w);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Push the monochrome block `block` to the output FIFO
+    fn output_monochrome(&mut self, block: &[i16; 64]) {
+        let sign_flip =
+            if self.output_signed {
+                0
+            } else {
+                0x80
+            };
+
+        let pixels: Vec<u8> = block.iter().map(|&y| {
+            let y =
+                if y < -128 {
+                    -128
+                } else if y > 127 {
+                    127
+                } else {
+                    y
+                };
+
+            y as u8 ^ sign_flip
+        }).collect();
+
+        match self.output_depth {
+            OutputDepth::D8Bpp => {
+                for p in pixels.chunks(4) {
+                    let w = (p[0] as u32)
+                        | ((p[1] as u32) << 8)
+                        | ((p[2] as u32) << 16)
+                        | ((p[3] as u32) << 24);
+
+                    self.output_fifo.push_back(w);
+                }
+            }
+            OutputDepth::D4Bpp => {
+                for p in pixels.chunks(8) {
+                    let mut w = 0;
+
+                    for (i, &y) in p.iter().enumerate() {
+                        w |= ((y >> 4) as u32) << (i * 4);
+                    }
+
+                    self.output_fifo.push_back(w);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
     /// Set the value of the control register
     fn set_control(&mut self, val: u32) {
         let reset = val & (1 << 31) != 0;
@@ -216,6 +602,8 @@ impl MDec {
             self.current_block = BlockType::CrLuma;
             *self.command_handler = MDec::handle_command;
             self.command_remaining = 1;
+            self.block_index = None;
+            self.output_fifo.clear();
         }
     }
 }
@@ -224,6 +612,8 @@ callback!(struct CommandHandler (fn(&mut MDec, u32)) {
     MDec::handle_command,
     MDec::handle_color_quant_matrices,
     MDec::handle_monochrome_quant_matrix,
+    MDec::handle_idct_matrix,
+    MDec::handle_macroblock_data,
 });
 
 /// Serializable container for the quantization matrices
@@ -232,6 +622,12 @@ buffer!(struct QuantMatrix([u8; 64]));
 /// Serializable container for the IDCT matrix
 buffer!(struct IdctMatrix([i16; 64]));
 
+/// Serializable container for an 8x8 block of coefficients
+buffer!(struct Block([i16; 64]));
+
+/// Serializable container for the 16x16 RGB pixels of a macroblock
+buffer!(struct Macroblock([u32; 256]));
+
 /// Pixel color depths supported by the MDEC
 #[derive(Copy, Clone, PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
 enum OutputDepth {
@@ -241,8 +637,7 @@ enum OutputDepth {
     D24Bpp = 2,
 }
 
-#[allow(dead_code)]
-#[derive(RustcDecodable, RustcEncodable)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
 enum BlockType {
     Y1 = 0,
     Y2 = 1,
@@ -252,3 +647,77 @@ enum BlockType {
     CrLuma = 4,
     Cb = 5,
 }
+
+/// Position of the coefficients in the block, in the order they're
+/// received
+const ZIGZAG: [usize; 64] = [
+    0,  1,  8,  16, 9,  2,  3,  10,
+    17, 24, 32, 25, 18, 11, 4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6,  7,  14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Standard IDCT matrix used by the PsyQ libraries, to be uploaded
+/// with command 3 (GP word 0x60000000). Row `z` contains the scaled
+/// `cos((2x + 1) * z * pi / 16)` coefficients. Negative values are
+/// the one's complement of the positive ones, as in the PsyQ table.
+const IDCT_MATRIX: [i16; 64] = [
+    0x5a82,  0x5a82,  0x5a82,  0x5a82,  0x5a82,  0x5a82,  0x5a82,  0x5a82,
+    0x7d8a,  0x6a6d,  0x471c,  0x18f8, -0x18f9, -0x471d, -0x6a6e, -0x7d8b,
+    0x7641,  0x30fb, -0x30fc, -0x7642, -0x7642, -0x30fc,  0x30fb,  0x7641,
+    0x6a6d, -0x18f9, -0x7d8b, -0x471d,  0x471c,  0x7d8a,  0x18f8, -0x6a6e,
+    0x5a82, -0x5a83, -0x5a83,  0x5a82,  0x5a82, -0x5a83, -0x5a83,  0x5a82,
+    0x471c, -0x7d8b,  0x18f8,  0x6a6d, -0x6a6e, -0x18f9,  0x7d8a, -0x471d,
+    0x30fb, -0x7642,  0x7641, -0x30fc, -0x30fc,  0x7641, -0x7642,  0x30fb,
+    0x18f8, -0x471d,  0x6a6d, -0x7d8b,  0x7d8a, -0x6a6e,  0x471c, -0x18f9,
+];
+
+#[test]
+fn decode_monochrome_dc() {
+    let mut shared = SharedState::new();
+    let mut mdec = MDec::new();
+
+    // Standard IDCT matrix
+    mdec.command(&mut shared, 0x60000000);
+
+    for c in IDCT_MATRIX.chunks(2) {
+        let w = (c[0] as u16 as u32) | ((c[1] as u16 as u32) << 16);
+
+        mdec.command(&mut shared, w);
+    }
+
+    // Luma quantization matrix, DC quantizer is 8
+    mdec.command(&mut shared, 0x40000000);
+    for _ in 0..16 {
+        mdec.command(&mut shared, 0x08080808);
+    }
+
+    // Decode one 8bpp unsigned block containing only a DC
+    // coefficient, followed by the end of block code
+    mdec.command(&mut shared, 0x28000001);
+    mdec.command(&mut shared, 0xfe000000 | (1 << 10) | 10);
+
+    assert!(mdec.output_fifo.len() == 16);
+    // DC of 80 gives 10 for every pixel, plus 128 for unsigned
+    // output
+    assert!(mdec.output_fifo.iter().all(|&w| w == 0x8a8a8a8a));
+
+    // Enable DMA out
+    mdec.set_control(1 << 29);
+
+    assert!(mdec.status() & (1 << 27) != 0);
+    assert!(mdec.status() & (1 << 31) == 0);
+    assert!(mdec.dma_out_ready(16));
+    assert!(!mdec.dma_out_ready(17));
+
+    for _ in 0..16 {
+        assert!(mdec.dma_read_word() == 0x8a8a8a8a);
+    }
+
+    assert!(mdec.status() & (1 << 27) == 0);
+    assert!(mdec.status() & (1 << 31) != 0);
+}
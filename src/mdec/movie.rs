@@ -0,0 +1,789 @@
+//! STR movie support: demux the video frames and XA audio of `.STR`
+//! files and decode them in software without running the emulator.
+//!
+//! STR files are made of Mode 2 sectors. Video sectors are data
+//! sectors starting with a 32 byte header, each compressed frame is
+//! split into several "chunks" stored in consecutive video
+//! sectors. XA-ADPCM audio sectors are interleaved with the video.
+//!
+//! The frames use the "bitstream" compression: the MDEC run-length
+//! codes are compressed further using the MPEG-1 variable length
+//! codes. We expand them back into MDEC commands and feed them to an
+//! `MDec` instance to get the RGB pixels.
+
+use std::collections::VecDeque;
+
+use cdimage::{Image, CdError};
+use cdimage::sector::Sector;
+use cdimage::msf::Msf;
+use cdimage::bcd::Bcd;
+
+use cdrom::iso9660::Entry;
+use cdrom::xa::XaDecoder;
+use shared::SharedState;
+
+use super::{MDec, ZIGZAG, IDCT_MATRIX};
+
+/// Number of bytes of frame data in each video sector
+const CHUNK_SIZE: usize = 2016;
+
+/// STR file demuxer
+pub struct Demuxer<'a> {
+    image: &'a mut Image,
+    /// Position of the next sector
+    msf: Msf,
+    /// Number of sectors left in the file
+    remaining: u32,
+    sector: Sector,
+    /// Frame currently being reassembled
+    frame: Option<PartialFrame>,
+    xa_decoder: XaDecoder,
+    /// File and channel numbers of the audio stream we decode. A
+    /// movie can have several interleaved audio streams (different
+    /// languages for instance), we only decode the first one we
+    /// encounter.
+    audio_stream: Option<(u8, u8)>,
+}
+
+impl<'a> Demuxer<'a> {
+    /// Demux the STR file `entry` stored in `image`
+    pub fn new(image: &'a mut Image,
+               entry: &Entry) -> Result<Demuxer<'a>, Error> {
+        if entry.is_dir() {
+            return Err(Error::NotAFile);
+        }
+
+        let extent_location = entry.extent_location();
+
+        let track_msf =
+            match Msf::from_sector_index(extent_location) {
+                Some(m) => m,
+                None => return Err(Error::BadExtent(extent_location)),
+            };
+
+        let msf = try!(image.track_msf(Bcd::one(), track_msf));
+
+        Ok(Demuxer {
+            image: image,
+            msf: msf,
+            remaining: (entry.extent_len() + 2047) / 2048,
+            sector: Sector::empty(),
+            frame: None,
+            xa_decoder: XaDecoder::new(),
+            audio_stream: None,
+        })
+    }
+
+    /// Return the next packet in the file or `None` when we reach the
+    /// end of the file
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, Error> {
+        while self.remaining > 0 {
+            try!(self.image.read_sector(&mut self.sector, self.msf));
+
+            self.remaining -= 1;
+            self.msf = self.msf.next().unwrap();
+
+            if let Some(packet) = try!(self.demux_sector()) {
+                return Ok(Some(packet));
+            }
+        }
+
+        if let Some(frame) = self.frame.take() {
+            warn!("STR file ends with incomplete frame {}", frame.number);
+        }
+
+        Ok(None)
+    }
+
+    /// Handle the sector we just read, returning a packet if it
+    /// completes one
+    fn demux_sector(&mut self) -> Result<Option<Packet>, Error> {
+        let data = try!(self.sector.data_2352());
+
+        let mode = data[15];
+        let file = data[16];
+        let channel = data[17];
+        let submode = data[18];
+
+        if mode != 2 {
+            // Not an XA sector, ignore it
+            return Ok(None);
+        }
+
+        if submode & 4 != 0 {
+            // Audio sector
+            let stream = (file, channel);
+
+            if *self.audio_stream.get_or_insert(stream) != stream {
+                return Ok(None);
+            }
+
+            let mut samples = VecDeque::new();
+
+            self.xa_decoder.decode_sector(data, &mut samples);
+
+            return Ok(Some(Packet::Audio(samples.into_iter().collect())));
+        }
+
+        let payload = &data[24..24 + 2048];
+
+        let read_u16 = |off: usize| {
+            (payload[off] as u16) | ((payload[off + 1] as u16) << 8)
+        };
+
+        let read_u32 = |off: usize| {
+            (read_u16(off) as u32) | ((read_u16(off + 2) as u32) << 16)
+        };
+
+        if read_u16(0) != 0x0160 || read_u16(2) != 0x8001 {
+            // Not a video sector
+            return Ok(None);
+        }
+
+        let chunk = read_u16(4) as usize;
+        let chunk_count = read_u16(6) as usize;
+        let number = read_u32(8);
+        let size = read_u32(12) as usize;
+        let width = read_u16(16);
+        let height = read_u16(18);
+
+        if chunk >= chunk_count {
+            return Err(Error::BadVideoSector);
+        }
+
+        let restart =
+            match self.frame {
+                Some(ref f) => f.number != number,
+                None => true,
+            };
+
+        if restart {
+            if let Some(ref f) = self.frame {
+                warn!("STR frame {} is incomplete, dropping it", f.number);
+            }
+
+            self.frame = Some(PartialFrame {
+                number: number,
+                width: width,
+                height: height,
+                size: size,
+                data: vec![0; chunk_count * CHUNK_SIZE],
+                received: vec![false; chunk_count],
+            });
+        }
+
+        let complete = {
+            let frame = self.frame.as_mut().unwrap();
+
+            if chunk >= frame.received.len() {
+                return Err(Error::BadVideoSector);
+            }
+
+            let start = chunk * CHUNK_SIZE;
+
+            frame.data[start..start + CHUNK_SIZE]
+                .copy_from_slice(&payload[32..]);
+            frame.received[chunk] = true;
+
+            frame.received.iter().all(|&r| r)
+        };
+
+        if !complete {
+            return Ok(None);
+        }
+
+        let frame = self.frame.take().unwrap();
+
+        let mut data = frame.data;
+
+        if frame.size < data.len() {
+            data.truncate(frame.size);
+        }
+
+        Ok(Some(Packet::Video(CompressedFrame {
+            number: frame.number,
+            width: frame.width,
+            height: frame.height,
+            data: data,
+        })))
+    }
+}
+
+/// Frame being reassembled from its chunks
+struct PartialFrame {
+    number: u32,
+    width: u16,
+    height: u16,
+    /// Size of the frame data in bytes
+    size: usize,
+    data: Vec<u8>,
+    /// Chunks received so far
+    received: Vec<bool>,
+}
+
+/// Packet returned by the demuxer
+pub enum Packet {
+    /// Compressed video frame
+    Video(CompressedFrame),
+    /// Decoded audio samples, 44.1kHz stereo (left, right)
+    Audio(Vec<(i16, i16)>),
+}
+
+/// Bitstream compressed video frame
+pub struct CompressedFrame {
+    /// Frame number, starting at 1
+    pub number: u32,
+    pub width: u16,
+    pub height: u16,
+    /// Bitstream data, including the 8 byte header
+    pub data: Vec<u8>,
+}
+
+/// Decoded video frame
+pub struct Frame {
+    pub number: u32,
+    pub width: u16,
+    pub height: u16,
+    /// RGB pixels, 3 bytes per pixel, line by line starting from the
+    /// top left corner
+    pub pixels: Vec<u8>,
+}
+
+/// Software frame decoder
+pub struct Decoder {
+    mdec: MDec,
+    /// The MDEC needs a `SharedState` for tracing
+    shared: SharedState,
+}
+
+impl Decoder {
+    /// Create a decoder using the quantization and IDCT tables
+    /// used by the official libraries
+    pub fn new() -> Decoder {
+        let mut decoder = Decoder {
+            mdec: MDec::new(),
+            shared: SharedState::new(),
+        };
+
+        decoder.upload_tables();
+
+        decoder
+    }
+
+    fn upload_tables(&mut self) {
+        let mdec = &mut self.mdec;
+        let shared = &mut self.shared;
+
+        // Luma and chroma quantization matrices. The MDEC expects
+        // them in zigzag order.
+        mdec.command(shared, 0x40000001);
+
+        for _ in 0..2 {
+            for i in 0..16 {
+                let mut w = 0;
+
+                for j in 0..4 {
+                    let q = QUANT_MATRIX[ZIGZAG[i * 4 + j]] as u32;
+
+                    w |= q << (j * 8);
+                }
+
+                mdec.command(shared, w);
+            }
+        }
+
+        // IDCT matrix
+        mdec.command(shared, 0x60000000);
+
+        for c in IDCT_MATRIX.chunks(2) {
+            let w = (c[0] as u16 as u32) | ((c[1] as u16 as u32) << 16);
+
+            mdec.command(shared, w);
+        }
+    }
+
+    /// Decode `frame` into RGB pixels
+    pub fn decode(&mut self, frame: &CompressedFrame) -> Result<Frame, Error> {
+        let width = frame.width as usize;
+        let height = frame.height as usize;
+
+        let mb_columns = (width + 15) / 16;
+        let mb_rows = (height + 15) / 16;
+
+        let macroblocks = try!(expand_bitstream(&frame.data,
+                                                mb_columns * mb_rows));
+
+        let mut pixels = vec![0; width * height * 3];
+
+        // Macroblocks are stored column by column
+        for (i, rle) in macroblocks.iter().enumerate() {
+            let mb_x = (i / mb_rows) * 16;
+            let mb_y = (i % mb_rows) * 16;
+
+            let rgb = self.decode_macroblock(rle);
+
+            for y in 0..16 {
+                let py = mb_y + y;
+
+                if py >= height {
+                    break;
+                }
+
+                for x in 0..16 {
+                    let px = mb_x + x;
+
+                    if px >= width {
+                        break;
+                    }
+
+                    let src = (y * 16 + x) * 3;
+                    let dst = (py * width + px) * 3;
+
+                    pixels[dst..dst + 3].copy_from_slice(&rgb[src..src + 3]);
+                }
+            }
+        }
+
+        Ok(Frame {
+            number: frame.number,
+            width: frame.width,
+            height: frame.height,
+            pixels: pixels,
+        })
+    }
+
+    /// Run the MDEC on the run-length codes of one macroblock and
+    /// return the 16x16 RGB pixels
+    fn decode_macroblock(&mut self, rle: &[u16]) -> Vec<u8> {
+        let words = (rle.len() + 1) / 2;
+
+        // Decode macroblock, 24bpp unsigned
+        self.mdec.command(&mut self.shared,
+                          0x30000000 | (words as u32));
+
+        for pair in rle.chunks(2) {
+            let low = pair[0] as u32;
+            // Pad the last word with the end of block code
+            let high = *pair.get(1).unwrap_or(&0xfe00) as u32;
+
+            self.mdec.command(&mut self.shared, low | (high << 16));
+        }
+
+        let mut rgb = Vec::with_capacity(16 * 16 * 3);
+
+        for _ in 0..(16 * 16 * 3 / 4) {
+            let w = self.mdec.dma_read_word();
+
+            for i in 0..4 {
+                rgb.push((w >> (i * 8)) as u8);
+            }
+        }
+
+        rgb
+    }
+}
+
+/// Expand the bitstream frame `data` into MDEC run-length codes,
+/// returns one vector of codes per macroblock
+fn expand_bitstream(data: &[u8],
+                    macroblocks: usize) -> Result<Vec<Vec<u16>>, Error> {
+    if data.len() < 8 {
+        return Err(Error::BadBitstream("Frame header too short".into()));
+    }
+
+    let read_u16 = |off: usize| {
+        (data[off] as u16) | ((data[off + 1] as u16) << 8)
+    };
+
+    if read_u16(2) != 0x3800 {
+        return Err(Error::BadBitstream("Bad frame header magic".into()));
+    }
+
+    let q_scale = read_u16(4) & 0x3f;
+    let version = read_u16(6);
+
+    // Version 3 uses a different encoding for the DC coefficients
+    // which isn't supported
+    if version != 1 && version != 2 {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let mut reader = BitReader::new(&data[8..]);
+
+    let mut out = Vec::with_capacity(macroblocks);
+
+    for _ in 0..macroblocks {
+        let mut rle = Vec::new();
+
+        // Cr, Cb, Y1, Y2, Y3, Y4
+        for _ in 0..6 {
+            try!(expand_block(&mut reader, q_scale, &mut rle));
+        }
+
+        out.push(rle);
+    }
+
+    if reader.overrun() {
+        return Err(Error::BadBitstream("Truncated frame".into()));
+    }
+
+    Ok(out)
+}
+
+/// Expand the codes for a single 8x8 block
+fn expand_block(reader: &mut BitReader,
+                q_scale: u16,
+                out: &mut Vec<u16>) -> Result<(), Error> {
+    // 10bit DC coefficient
+    let dc = reader.read(10) as u16;
+
+    out.push((q_scale << 10) | dc);
+
+    // Index of the last coefficient
+    let mut index = 0;
+
+    loop {
+        let (run, level) =
+            if reader.peek(2) == 0b10 {
+                // End of block
+                reader.skip(2);
+                out.push(0xfe00);
+                return Ok(());
+            } else if reader.peek(6) == 0b000001 {
+                // Escape code: 6bit run followed by a 10bit level
+                reader.skip(6);
+
+                let run = reader.read(6) as u16;
+                let level = reader.read(10) as u16;
+
+                (run, level)
+            } else {
+                let &(len, _, run, level) =
+                    match AC_CODES.iter().find(|&&(len, code, _, _)| {
+                        reader.peek(len) == code as u32
+                    }) {
+                        Some(c) => c,
+                        None => return Err(Error::BadBitstream(
+                            "Invalid AC code".into())),
+                    };
+
+                reader.skip(len);
+
+                let level = level as i16;
+
+                let level =
+                    if reader.read(1) != 0 {
+                        -level
+                    } else {
+                        level
+                    };
+
+                (run as u16, (level as u16) & 0x3ff)
+            };
+
+        index += run + 1;
+
+        if index > 63 || reader.overrun() {
+            return Err(Error::BadBitstream("Block overflow".into()));
+        }
+
+        out.push((run << 10) | level);
+    }
+}
+
+/// Bitstream reader. The data is read as a sequence of little endian
+/// halfwords, most significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    /// Return the bit at `pos`, 0 past the end of the data
+    fn bit(&self, pos: usize) -> u32 {
+        let halfword = pos / 16;
+        let bit = 15 - (pos % 16);
+
+        let byte = halfword * 2 + bit / 8;
+
+        match self.data.get(byte) {
+            Some(&b) => ((b >> (bit % 8)) & 1) as u32,
+            None => 0,
+        }
+    }
+
+    /// Return the next `n` bits without consuming them
+    fn peek(&self, n: u8) -> u32 {
+        (0..n as usize).fold(0, |v, i| (v << 1) | self.bit(self.pos + i))
+    }
+
+    fn skip(&mut self, n: u8) {
+        self.pos += n as usize;
+    }
+
+    fn read(&mut self, n: u8) -> u32 {
+        let v = self.peek(n);
+
+        self.skip(n);
+
+        v
+    }
+
+    /// True if we've read past the end of the data
+    fn overrun(&self) -> bool {
+        self.pos > self.data.len() * 8
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cdimage access error
+    CdError(CdError),
+    /// Encountered an invalid extent location
+    BadExtent(u32),
+    /// We expected a file and got a directory
+    NotAFile,
+    /// Video sector with an invalid header
+    BadVideoSector,
+    /// Unsupported bitstream version
+    UnsupportedVersion(u16),
+    /// Invalid bitstream data containing a description of the
+    /// problem
+    BadBitstream(String),
+}
+
+impl From<CdError> for Error {
+    fn from(e: CdError) -> Error {
+        Error::CdError(e)
+    }
+}
+
+/// Default quantization matrix (in natural order), it's the MPEG-1
+/// intra matrix with a DC quantizer of 2.
+const QUANT_MATRIX: [u8; 64] = [
+    2,  16, 19, 22, 26, 27, 29, 34,
+    16, 16, 22, 24, 27, 29, 34, 37,
+    19, 22, 26, 27, 29, 34, 34, 38,
+    22, 22, 26, 27, 29, 34, 37, 40,
+    22, 26, 27, 29, 32, 35, 40, 48,
+    26, 27, 29, 32, 35, 40, 48, 58,
+    26, 27, 29, 34, 38, 46, 56, 69,
+    27, 29, 35, 38, 46, 56, 69, 83,
+];
+
+/// MPEG-1 AC coefficient variable length codes: (code length, code,
+/// run, level). Each code is followed by a sign bit. The end of
+/// block (`10`) and escape (`000001`) codes are handled separately.
+const AC_CODES: [(u8, u16, u8, u8); 111] = [
+    (2, 0b11, 0, 1),
+    (3, 0b011, 1, 1),
+    (4, 0b0100, 0, 2),
+    (4, 0b0101, 2, 1),
+    (5, 0b00101, 0, 3),
+    (5, 0b00111, 3, 1),
+    (5, 0b00110, 4, 1),
+    (6, 0b000110, 1, 2),
+    (6, 0b000111, 5, 1),
+    (6, 0b000101, 6, 1),
+    (6, 0b000100, 7, 1),
+    (7, 0b0000110, 0, 4),
+    (7, 0b0000100, 2, 2),
+    (7, 0b0000111, 8, 1),
+    (7, 0b0000101, 9, 1),
+    (8, 0b00100110, 0, 5),
+    (8, 0b00100001, 0, 6),
+    (8, 0b00100101, 1, 3),
+    (8, 0b00100100, 3, 2),
+    (8, 0b00100111, 10, 1),
+    (8, 0b00100011, 11, 1),
+    (8, 0b00100010, 12, 1),
+    (8, 0b00100000, 13, 1),
+    (10, 0b0000001010, 0, 7),
+    (10, 0b0000001100, 1, 4),
+    (10, 0b0000001011, 2, 3),
+    (10, 0b0000001111, 4, 2),
+    (10, 0b0000001001, 5, 2),
+    (10, 0b0000001110, 14, 1),
+    (10, 0b0000001101, 15, 1),
+    (10, 0b0000001000, 16, 1),
+    (12, 0b000000011101, 0, 8),
+    (12, 0b000000011000, 0, 9),
+    (12, 0b000000010011, 0, 10),
+    (12, 0b000000010000, 0, 11),
+    (12, 0b000000011011, 1, 5),
+    (12, 0b000000010100, 2, 4),
+    (12, 0b000000011100, 3, 3),
+    (12, 0b000000010010, 4, 3),
+    (12, 0b000000011110, 6, 2),
+    (12, 0b000000010101, 7, 2),
+    (12, 0b000000010001, 8, 2),
+    (12, 0b000000011111, 17, 1),
+    (12, 0b000000011010, 18, 1),
+    (12, 0b000000011001, 19, 1),
+    (12, 0b000000010111, 20, 1),
+    (12, 0b000000010110, 21, 1),
+    (13, 0b0000000011010, 0, 12),
+    (13, 0b0000000011001, 0, 13),
+    (13, 0b0000000011000, 0, 14),
+    (13, 0b0000000010111, 0, 15),
+    (13, 0b0000000010110, 1, 6),
+    (13, 0b0000000010101, 1, 7),
+    (13, 0b0000000010100, 2, 5),
+    (13, 0b0000000010011, 3, 4),
+    (13, 0b0000000010010, 5, 3),
+    (13, 0b0000000010001, 9, 2),
+    (13, 0b0000000010000, 10, 2),
+    (13, 0b0000000011111, 22, 1),
+    (13, 0b0000000011110, 23, 1),
+    (13, 0b0000000011101, 24, 1),
+    (13, 0b0000000011100, 25, 1),
+    (13, 0b0000000011011, 26, 1),
+    (14, 0b00000000011111, 0, 16),
+    (14, 0b00000000011110, 0, 17),
+    (14, 0b00000000011101, 0, 18),
+    (14, 0b00000000011100, 0, 19),
+    (14, 0b00000000011011, 0, 20),
+    (14, 0b00000000011010, 0, 21),
+    (14, 0b00000000011001, 0, 22),
+    (14, 0b00000000011000, 0, 23),
+    (14, 0b00000000010111, 0, 24),
+    (14, 0b00000000010110, 0, 25),
+    (14, 0b00000000010101, 0, 26),
+    (14, 0b00000000010100, 0, 27),
+    (14, 0b00000000010011, 0, 28),
+    (14, 0b00000000010010, 0, 29),
+    (14, 0b00000000010001, 0, 30),
+    (14, 0b00000000010000, 0, 31),
+    (15, 0b000000000011000, 0, 32),
+    (15, 0b000000000010111, 0, 33),
+    (15, 0b000000000010110, 0, 34),
+    (15, 0b000000000010101, 0, 35),
+    (15, 0b000000000010100, 0, 36),
+    (15, 0b000000000010011, 0, 37),
+    (15, 0b000000000010010, 0, 38),
+    (15, 0b000000000010001, 0, 39),
+    (15, 0b000000000010000, 0, 40),
+    (15, 0b000000000011111, 1, 8),
+    (15, 0b000000000011110, 1, 9),
+    (15, 0b000000000011101, 1, 10),
+    (15, 0b000000000011100, 1, 11),
+    (15, 0b000000000011011, 1, 12),
+    (15, 0b000000000011010, 1, 13),
+    (15, 0b000000000011001, 1, 14),
+    (16, 0b0000000000010011, 1, 15),
+    (16, 0b0000000000010010, 1, 16),
+    (16, 0b0000000000010001, 1, 17),
+    (16, 0b0000000000010000, 1, 18),
+    (16, 0b0000000000010100, 6, 3),
+    (16, 0b0000000000011010, 11, 2),
+    (16, 0b0000000000011001, 12, 2),
+    (16, 0b0000000000011000, 13, 2),
+    (16, 0b0000000000010111, 14, 2),
+    (16, 0b0000000000010110, 15, 2),
+    (16, 0b0000000000010101, 16, 2),
+    (16, 0b0000000000011111, 27, 1),
+    (16, 0b0000000000011110, 28, 1),
+    (16, 0b0000000000011101, 29, 1),
+    (16, 0b0000000000011100, 30, 1),
+    (16, 0b0000000000011011, 31, 1),
+];
+
+#[test]
+fn decode_flat_frame() {
+    // Pack a list of (length, value) fields into a bitstream
+    let pack = |fields: &[(u8, u32)]| {
+        let mut bits = Vec::new();
+
+        for &(len, v) in fields {
+            for i in (0..len).rev() {
+                bits.push((v >> i) & 1);
+            }
+        }
+
+        let mut data = Vec::new();
+
+        for halfword in bits.chunks(16) {
+            let h = halfword.iter()
+                .enumerate()
+                .fold(0u16, |h, (i, &b)| h | ((b as u16) << (15 - i)));
+
+            data.push(h as u8);
+            data.push((h >> 8) as u8);
+        }
+
+        data
+    };
+
+    // Cr and Cb blocks: DC 0, then a single run 0 level -1 AC
+    // coefficient, then end of block. Luma blocks: DC 20 and end of
+    // block.
+    let mut fields = Vec::new();
+
+    for _ in 0..2 {
+        fields.extend_from_slice(&[(10, 0), (2, 0b11), (1, 1), (2, 0b10)]);
+    }
+
+    for _ in 0..4 {
+        fields.extend_from_slice(&[(10, 20), (2, 0b10)]);
+    }
+
+    // Header: version 2, q_scale 1
+    let mut data = vec![0, 0, 0x00, 0x38, 1, 0, 2, 0];
+
+    data.extend(pack(&fields));
+
+    let rle = expand_bitstream(&data, 1).unwrap();
+
+    assert!(rle[0] == [0x0400, 0x03ff, 0xfe00,
+                       0x0400, 0x03ff, 0xfe00,
+                       0x0414, 0xfe00,
+                       0x0414, 0xfe00,
+                       0x0414, 0xfe00,
+                       0x0414, 0xfe00]);
+
+    // Same thing without the chroma AC coefficients, we should get a
+    // flat gray frame
+    let mut fields = Vec::new();
+
+    for &dc in &[0, 0, 20, 20, 20, 20] {
+        fields.extend_from_slice(&[(10, dc), (2, 0b10)]);
+    }
+
+    let mut data = vec![0, 0, 0x00, 0x38, 1, 0, 2, 0];
+
+    data.extend(pack(&fields));
+
+    let frame = CompressedFrame {
+        number: 1,
+        width: 16,
+        height: 16,
+        data: data,
+    };
+
+    let mut decoder = Decoder::new();
+
+    let frame = decoder.decode(&frame).unwrap();
+
+    assert!(frame.pixels.len() == 16 * 16 * 3);
+
+    // DC 20 with a quantizer of 2 gives 5 for every pixel, plus 128
+    // for unsigned output
+    assert!(frame.pixels.iter().all(|&p| p == 133));
+
+    let mut data = vec![0, 0, 0x00, 0x38, 1, 0, 3, 0];
+
+    data.extend(pack(&fields));
+
+    match expand_bitstream(&data, 1) {
+        Err(Error::UnsupportedVersion(3)) => (),
+        _ => panic!("Version 3 frame should be rejected"),
+    }
+}
@@ -12,11 +12,13 @@ use timekeeper::Peripheral;
 use gpu::Gpu;
 use gpu::renderer::Renderer;
 use spu::Spu;
+use spu::sink::AudioSink;
 use cdrom::CdRom;
 use cdrom::disc::Disc;
 use padmemcard::PadMemCard;
 use mdec::MDec;
 use parallel_io::ParallelIo;
+use sio1::Sio1;
 use debug_uart::DebugUart;
 use tracer::module_tracer;
 
@@ -52,6 +54,8 @@ pub struct Interconnect {
     mem_control: [u32; 9],
     /// Parallel I/O
     parallel_io: ParallelIo,
+    /// Serial port
+    sio1: Sio1,
     /// Debug UART
     debug_uart: DebugUart,
 }
@@ -75,6 +79,7 @@ impl Interconnect {
             ram_size: 0,
             mem_control: [0; 9],
             parallel_io: ParallelIo::disconnected(),
+            sio1: Sio1::new(),
             debug_uart: DebugUart::new(),
         }
     }
@@ -93,6 +98,22 @@ impl Interconnect {
         if shared.tk().needs_sync(Peripheral::CdRom) {
             self.cdrom.sync(shared);
         }
+
+        if shared.tk().needs_sync(Peripheral::Spu) {
+            self.spu.sync(shared, &mut self.cdrom);
+        }
+
+        if shared.tk().needs_sync(Peripheral::Sio1) {
+            self.sio1.sync(shared);
+        }
+    }
+
+    /// Send the audio samples generated since the last call to
+    /// `sink`
+    pub fn flush_audio(&mut self,
+                       shared: &mut SharedState,
+                       sink: &mut AudioSink) {
+        self.spu.flush_samples(shared, &mut self.cdrom, sink);
     }
 
     pub fn cache_control(&self) -> CacheControl {
@@ -145,6 +166,11 @@ impl Interconnect {
         &mut self.parallel_io
     }
 
+    /// Return a mutable reference to the serial port
+    pub fn sio1_mut(&mut self) -> &mut Sio1 {
+        &mut self.sio1
+    }
+
     /// Interconnect: load instruction at `PC`. Only the RAM and BIOS
     /// are supported, would it make sense to fetch instructions from
     /// anything else?
@@ -226,13 +252,17 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::SPU.contains(abs_addr) {
-            return self.spu.load::<A>(offset);
+            return self.spu.load::<A>(shared, &mut self.cdrom, offset);
         }
 
         if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
             return self.pad_memcard.load::<A>(shared, offset);
         }
 
+        if let Some(offset) = map::SIO1.contains(abs_addr) {
+            return self.sio1.load::<A>(shared, offset);
+        }
+
         if let Some(offset) = map::EXPANSION_1.contains(abs_addr) {
             return self.parallel_io.load::<A>(shared, offset);
         }
@@ -325,16 +355,24 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::MDEC.contains(abs_addr) {
-            return self.mdec.store::<A>(shared, offset, val);
+            self.mdec.store::<A>(shared, offset, val);
+            // The MDEC might be ready to send or receive data now
+            self.run_pending_mdec_dma(shared, renderer);
+            return;
         }
 
         if let Some(offset) = map::SPU.contains(abs_addr) {
-            self.spu.store::<A>(offset, val);
+            self.spu.store::<A>(shared, &mut self.cdrom, offset, val);
             return;
         }
 
         if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
-            self.pad_memcard.store::<A>(shared, offset, val);
+            self.pad_memcard.store::<A>(shared, &self.gpu, offset, val);
+            return;
+        }
+
+        if let Some(offset) = map::SIO1.contains(abs_addr) {
+            self.sio1.store::<A>(shared, offset, val);
             return;
         }
 
@@ -498,6 +536,12 @@ impl Interconnect {
         // process everything in one pass (i.e. no
         // chopping or priority handling)
 
+        if !self.dma_port_ready(port) {
+            // The device isn't ready yet, the transfer will take
+            // place later
+            return;
+        }
+
         let sync = self.dma.channel(port).sync();
 
         module_tracer("DMA", |m| {
@@ -524,6 +568,36 @@ impl Interconnect {
         }
 
         self.dma.done(shared, port);
+
+        if port == Port::MDecIn {
+            // The MDEC may have output data for channel 1
+            self.run_pending_mdec_dma(shared, renderer);
+        }
+    }
+
+    /// Return true if the device on `port` is ready to process the
+    /// whole DMA transfer. Only the MDEC can stall transfers for now.
+    fn dma_port_ready(&self, port: Port) -> bool {
+        let channel = self.dma.channel(port);
+
+        let words = channel.transfer_size().unwrap_or(0);
+
+        match port {
+            Port::MDecIn => self.mdec.dma_in_ready(),
+            Port::MDecOut => self.mdec.dma_out_ready(words),
+            _ => true,
+        }
+    }
+
+    /// Start the MDEC DMA transfers which were waiting for the MDEC
+    fn run_pending_mdec_dma(&mut self,
+                            shared: &mut SharedState,
+                            renderer: &mut Renderer) {
+        for &port in &[Port::MDecIn, Port::MDecOut] {
+            if self.dma.channel(port).active() && self.dma_port_ready(port) {
+                self.do_dma(shared, renderer, port);
+            }
+        }
     }
 
     /// Emulate DMA transfer for linked list synchronization mode.
@@ -612,8 +686,7 @@ impl Interconnect {
                     match port {
                         Port::Gpu => self.gpu.gp0(renderer, src_word),
                         Port::MDecIn => self.mdec.command(shared, src_word),
-                        // XXX ignre transfers to the SPU for now
-                        Port::Spu => (),
+                        Port::Spu => self.spu.dma_write(shared, src_word),
                         _ => panic!("Unhandled DMA destination port {:?}",
                                     port),
                     }
@@ -628,13 +701,10 @@ impl Interconnect {
                             // Pointer to the previous entry
                             _ => addr.wrapping_sub(4) & 0x1fffff,
                         },
-                        Port::Gpu => {
-                            // XXX to be implemented
-                            debug!("DMA GPU READ");
-                            0
-                        }
+                        Port::Gpu => self.gpu.dma_read_word(),
                         Port::CdRom => self.cdrom.dma_read_word(),
-                        Port::MDecOut => 0,
+                        Port::Spu => self.spu.dma_read(shared),
+                        Port::MDecOut => self.mdec.dma_read_word(),
                         _ => panic!("Unhandled DMA source port {:?}", port),
                     };
 
@@ -750,7 +820,10 @@ pub mod map {
     pub const MEM_CONTROL: Range = Range(0x1f801000, 36);
 
     /// Gamepad and memory card controller
-    pub const PAD_MEMCARD: Range = Range(0x1f801040, 32);
+    pub const PAD_MEMCARD: Range = Range(0x1f801040, 16);
+
+    /// Serial port
+    pub const SIO1: Range = Range(0x1f801050, 16);
 
     /// Register that has something to do with RAM configuration,
     /// configured by the BIOS
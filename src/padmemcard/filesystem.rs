@@ -0,0 +1,757 @@
+//! Memory card filesystem.
+//!
+//! The card is divided in 16 blocks of 8KB. The first block contains
+//! the card header and the directory, the 15 others contain the
+//! saves. Each block is made of 64 frames (sectors) of 128 bytes.
+//!
+//! Block 0 layout:
+//!
+//! * Frame 0: header, starts with "MC"
+//! * Frames 1-15: directory entries, one per data block
+//! * Frames 16-35: broken sector list
+//! * Frames 36-55: broken sector replacement data
+//! * Frame 63: write test frame, copy of the header
+//!
+//! Each of the header, directory and broken sector list frames ends
+//! with a checksum byte: the XOR of the 127 other bytes.
+//!
+//! A save can span several blocks linked together through their
+//! directory entries. The first frame of the first block contains
+//! the title of the save, the icon palette and display flag, the
+//! following frames contain the icon bitmaps.
+
+use super::memcard::{MEMCARD_SIZE, SECTOR_SIZE};
+
+/// Size of a block in bytes
+pub const BLOCK_SIZE: usize = 8 * 1024;
+
+/// Number of data blocks (usable for saves) on a card
+pub const DATA_BLOCKS: u8 = 15;
+
+/// Number of frames in the broken sector list
+const BROKEN_SECTOR_FRAMES: usize = 20;
+
+/// In-memory image of a memory card
+pub struct CardImage {
+    data: Vec<u8>,
+}
+
+impl CardImage {
+    /// Parse the raw 128KB image `image`, validating the header and
+    /// the checksum of every directory frame.
+    pub fn new(image: &[u8]) -> Result<CardImage, Error> {
+        if image.len() != MEMCARD_SIZE {
+            return Err(Error::BadSize(image.len()));
+        }
+
+        let card = CardImage {
+            data: image.to_vec(),
+        };
+
+        if &card.frame(0)[0..2] != b"MC" {
+            return Err(Error::BadMagic);
+        }
+
+        for f in 0..(1 + DATA_BLOCKS as usize + BROKEN_SECTOR_FRAMES) {
+            let frame = card.frame(f);
+
+            if checksum(frame) != frame[SECTOR_SIZE - 1] {
+                return Err(Error::BadChecksum(f as u8));
+            }
+        }
+
+        Ok(card)
+    }
+
+    /// Create a freshly formatted card
+    pub fn formatted() -> CardImage {
+        let mut card = CardImage {
+            data: vec![0; MEMCARD_SIZE],
+        };
+
+        card.format();
+
+        card
+    }
+
+    /// Format the card, erasing all the saves
+    pub fn format(&mut self) {
+        for b in self.data.iter_mut() {
+            *b = 0;
+        }
+
+        {
+            let header = self.frame_mut(0);
+
+            header[0] = b'M';
+            header[1] = b'C';
+        }
+        self.update_checksum(0);
+
+        for block in 1..(DATA_BLOCKS + 1) {
+            self.write_entry(&DirEntry::free(block));
+        }
+
+        for f in 0..BROKEN_SECTOR_FRAMES {
+            let f = 1 + DATA_BLOCKS as usize + f;
+
+            {
+                let frame = self.frame_mut(f);
+
+                // No broken sector
+                write_u32(&mut frame[0..4], 0xffffffff);
+                frame[8] = 0xff;
+                frame[9] = 0xff;
+            }
+
+            self.update_checksum(f);
+        }
+
+        // Write test frame
+        let header = self.frame(0).to_vec();
+
+        self.frame_mut(63).copy_from_slice(&header);
+    }
+
+    /// Raw contents of the card
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return the directory entries of the 15 data blocks
+    pub fn directory(&self) -> Vec<DirEntry> {
+        (1..(DATA_BLOCKS + 1)).map(|b| self.entry(b)).collect()
+    }
+
+    /// Return the saves found on the card
+    pub fn saves(&self) -> Vec<Save> {
+        self.directory()
+            .iter()
+            .filter(|e| e.state == BlockState::First)
+            .filter_map(|e| self.save(e.block).ok())
+            .collect()
+    }
+
+    /// Return the save starting at block `first_block`
+    pub fn save(&self, first_block: u8) -> Result<Save, Error> {
+        let blocks = try!(self.chain(first_block));
+
+        let entry = self.entry(first_block);
+
+        let title_frame = &self.block(first_block)[0..SECTOR_SIZE];
+
+        if &title_frame[0..2] != b"SC" {
+            return Err(Error::BadMagic);
+        }
+
+        let title = &title_frame[4..0x44];
+        // NUL terminated, made of 2 byte characters
+        let title_len = (0..title.len() / 2)
+            .map(|i| i * 2)
+            .find(|&i| title[i] == 0)
+            .unwrap_or(title.len());
+
+        let mut palette = [0; 16];
+
+        for (i, p) in palette.iter_mut().enumerate() {
+            let off = 0x60 + i * 2;
+
+            *p = (title_frame[off] as u16) |
+                ((title_frame[off + 1] as u16) << 8);
+        }
+
+        // Display flag: 0x11, 0x12 or 0x13 for 1, 2 or 3 frames
+        let frame_count =
+            match title_frame[2] {
+                0x11 => 1,
+                0x12 => 2,
+                0x13 => 3,
+                _ => 1,
+            };
+
+        let frames = (0..frame_count)
+            .map(|f| {
+                let off = (1 + f) * SECTOR_SIZE;
+                let bitmap = &self.block(first_block)[off..off + SECTOR_SIZE];
+
+                // 4bpp, the low nibble is the leftmost pixel
+                bitmap.iter()
+                    .flat_map(|&b| vec![b & 0xf, b >> 4])
+                    .collect()
+            })
+            .collect();
+
+        Ok(Save {
+            filename: entry.filename,
+            blocks: blocks,
+            size: entry.size,
+            title: title[0..title_len].to_vec(),
+            icon: Icon {
+                palette: palette,
+                frames: frames,
+            },
+        })
+    }
+
+    /// Export the save starting at `first_block` in the single save
+    /// `.mcs` format: the 128 byte directory frame followed by the
+    /// contents of the blocks.
+    pub fn export_mcs(&self, first_block: u8) -> Result<Vec<u8>, Error> {
+        let blocks = try!(self.chain(first_block));
+
+        let mut mcs = Vec::with_capacity(SECTOR_SIZE +
+                                         blocks.len() * BLOCK_SIZE);
+
+        let mut entry = self.entry(first_block);
+
+        // The exported save is standalone
+        entry.next = None;
+        entry.size = (blocks.len() * BLOCK_SIZE) as u32;
+
+        mcs.extend_from_slice(&entry.to_frame());
+
+        for &b in &blocks {
+            mcs.extend_from_slice(self.block(b));
+        }
+
+        Ok(mcs)
+    }
+
+    /// Import the single save `.mcs` file `mcs`. Returns the first
+    /// block of the new save.
+    pub fn import_mcs(&mut self, mcs: &[u8]) -> Result<u8, Error> {
+        if mcs.len() < SECTOR_SIZE + BLOCK_SIZE ||
+            (mcs.len() - SECTOR_SIZE) % BLOCK_SIZE != 0 {
+            return Err(Error::BadSize(mcs.len()));
+        }
+
+        let header = &mcs[0..SECTOR_SIZE];
+
+        if checksum(header) != header[SECTOR_SIZE - 1] {
+            return Err(Error::BadChecksum(0));
+        }
+
+        let imported = DirEntry::from_frame(0, header);
+
+        if imported.state != BlockState::First {
+            return Err(Error::BadMagic);
+        }
+
+        let nblocks = (mcs.len() - SECTOR_SIZE) / BLOCK_SIZE;
+
+        let exists =
+            self.saves().iter().any(|s| s.filename == imported.filename);
+
+        if exists {
+            return Err(Error::FileExists);
+        }
+
+        let free: Vec<u8> =
+            self.directory()
+            .iter()
+            .filter(|e| e.state.is_free())
+            .map(|e| e.block)
+            .take(nblocks)
+            .collect();
+
+        if free.len() < nblocks {
+            return Err(Error::NoSpace);
+        }
+
+        for (i, &block) in free.iter().enumerate() {
+            let last = i == nblocks - 1;
+
+            let entry = DirEntry {
+                block: block,
+                state:
+                if i == 0 {
+                    BlockState::First
+                } else if last {
+                    BlockState::Last
+                } else {
+                    BlockState::Middle
+                },
+                size:
+                if i == 0 {
+                    (nblocks * BLOCK_SIZE) as u32
+                } else {
+                    0
+                },
+                next:
+                if last {
+                    None
+                } else {
+                    Some(free[i + 1])
+                },
+                filename:
+                if i == 0 {
+                    imported.filename.clone()
+                } else {
+                    Vec::new()
+                },
+            };
+
+            self.write_entry(&entry);
+
+            let src = SECTOR_SIZE + i * BLOCK_SIZE;
+
+            self.block_mut(block)
+                .copy_from_slice(&mcs[src..src + BLOCK_SIZE]);
+        }
+
+        Ok(free[0])
+    }
+
+    /// Delete the save starting at `first_block`. Like the BIOS we
+    /// only mark the blocks as deleted in the directory, the data is
+    /// left untouched.
+    pub fn delete(&mut self, first_block: u8) -> Result<(), Error> {
+        let blocks = try!(self.chain(first_block));
+
+        for &b in &blocks {
+            let mut entry = self.entry(b);
+
+            entry.state =
+                match entry.state {
+                    BlockState::First => BlockState::DeletedFirst,
+                    BlockState::Middle => BlockState::DeletedMiddle,
+                    _ => BlockState::DeletedLast,
+                };
+
+            self.write_entry(&entry);
+        }
+
+        Ok(())
+    }
+
+    /// Follow the chain of blocks starting at `first_block`
+    fn chain(&self, first_block: u8) -> Result<Vec<u8>, Error> {
+        if first_block < 1 || first_block > DATA_BLOCKS {
+            return Err(Error::BadBlock(first_block));
+        }
+
+        let mut entry = self.entry(first_block);
+
+        if entry.state != BlockState::First {
+            return Err(Error::NotFirstBlock(first_block));
+        }
+
+        let mut blocks = vec![first_block];
+
+        while let Some(next) = entry.next {
+            // Guard against loops in the chain
+            if blocks.len() >= DATA_BLOCKS as usize || blocks.contains(&next) {
+                return Err(Error::BrokenChain(first_block));
+            }
+
+            entry = self.entry(next);
+
+            match entry.state {
+                BlockState::Middle | BlockState::Last => (),
+                _ => return Err(Error::BrokenChain(first_block)),
+            }
+
+            blocks.push(next);
+        }
+
+        Ok(blocks)
+    }
+
+    /// Directory entry for data block `block` (1-15)
+    fn entry(&self, block: u8) -> DirEntry {
+        DirEntry::from_frame(block, self.frame(block as usize))
+    }
+
+    fn write_entry(&mut self, entry: &DirEntry) {
+        let f = entry.block as usize;
+
+        self.frame_mut(f).copy_from_slice(&entry.to_frame());
+    }
+
+    /// Frame `f` of the header block
+    fn frame(&self, f: usize) -> &[u8] {
+        &self.data[f * SECTOR_SIZE..(f + 1) * SECTOR_SIZE]
+    }
+
+    fn frame_mut(&mut self, f: usize) -> &mut [u8] {
+        &mut self.data[f * SECTOR_SIZE..(f + 1) * SECTOR_SIZE]
+    }
+
+    fn update_checksum(&mut self, f: usize) {
+        let frame = self.frame_mut(f);
+
+        frame[SECTOR_SIZE - 1] = checksum(frame);
+    }
+
+    fn block(&self, b: u8) -> &[u8] {
+        let b = b as usize;
+
+        &self.data[b * BLOCK_SIZE..(b + 1) * BLOCK_SIZE]
+    }
+
+    fn block_mut(&mut self, b: u8) -> &mut [u8] {
+        let b = b as usize;
+
+        &mut self.data[b * BLOCK_SIZE..(b + 1) * BLOCK_SIZE]
+    }
+}
+
+/// State of a data block in the directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockState {
+    /// Never used
+    Free,
+    /// First block of a save
+    First,
+    /// Middle block of a save
+    Middle,
+    /// Last block of a multi-block save
+    Last,
+    /// Blocks of a deleted save, can be reused
+    DeletedFirst,
+    DeletedMiddle,
+    DeletedLast,
+    /// Unknown state value
+    Unknown(u32),
+}
+
+impl BlockState {
+    fn from_u32(v: u32) -> BlockState {
+        match v {
+            0xa0 => BlockState::Free,
+            0x51 => BlockState::First,
+            0x52 => BlockState::Middle,
+            0x53 => BlockState::Last,
+            0xa1 => BlockState::DeletedFirst,
+            0xa2 => BlockState::DeletedMiddle,
+            0xa3 => BlockState::DeletedLast,
+            v => BlockState::Unknown(v),
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            BlockState::Free => 0xa0,
+            BlockState::First => 0x51,
+            BlockState::Middle => 0x52,
+            BlockState::Last => 0x53,
+            BlockState::DeletedFirst => 0xa1,
+            BlockState::DeletedMiddle => 0xa2,
+            BlockState::DeletedLast => 0xa3,
+            BlockState::Unknown(v) => v,
+        }
+    }
+
+    /// True if the block can be used for a new save
+    pub fn is_free(self) -> bool {
+        match self {
+            BlockState::Free |
+            BlockState::DeletedFirst |
+            BlockState::DeletedMiddle |
+            BlockState::DeletedLast => true,
+            _ => false,
+        }
+    }
+}
+
+/// Directory entry describing one data block
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Data block number (1-15)
+    pub block: u8,
+    pub state: BlockState,
+    /// Size of the save in bytes, only set in the first block
+    pub size: u32,
+    /// Next block of the save
+    pub next: Option<u8>,
+    /// Raw save filename, only set in the first block. Usually
+    /// made of the region, the game serial and a game-specific
+    /// suffix. See `filename_lossy`.
+    pub filename: Vec<u8>,
+}
+
+impl DirEntry {
+    fn free(block: u8) -> DirEntry {
+        DirEntry {
+            block: block,
+            state: BlockState::Free,
+            size: 0,
+            next: None,
+            filename: Vec::new(),
+        }
+    }
+
+    fn from_frame(block: u8, frame: &[u8]) -> DirEntry {
+        let next = (frame[8] as u16) | ((frame[9] as u16) << 8);
+
+        let name = &frame[0x0a..0x1f];
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+
+        DirEntry {
+            block: block,
+            state: BlockState::from_u32(read_u32(&frame[0..4])),
+            size: read_u32(&frame[4..8]),
+            // The pointer is the index of the block minus 1
+            next:
+            if next < DATA_BLOCKS as u16 {
+                Some(next as u8 + 1)
+            } else {
+                None
+            },
+            filename: name[0..name_len].to_vec(),
+        }
+    }
+
+    fn to_frame(&self) -> [u8; SECTOR_SIZE] {
+        let mut frame = [0; SECTOR_SIZE];
+
+        write_u32(&mut frame[0..4], self.state.to_u32());
+        write_u32(&mut frame[4..8], self.size);
+
+        let next =
+            match self.next {
+                Some(n) => (n - 1) as u16,
+                None => 0xffff,
+            };
+
+        frame[8] = next as u8;
+        frame[9] = (next >> 8) as u8;
+
+        // The filename is 20 characters long at most, followed by a
+        // NUL
+        for (i, &b) in self.filename.iter().take(20).enumerate() {
+            frame[0x0a + i] = b;
+        }
+
+        frame[SECTOR_SIZE - 1] = checksum(&frame);
+
+        frame
+    }
+
+    /// Return the filename as a `String`, replacing invalid UTF-8
+    /// sequences with U+FFFD
+    pub fn filename_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.filename).into_owned()
+    }
+}
+
+/// Description of a save
+pub struct Save {
+    /// Raw save filename. See `filename_lossy`.
+    pub filename: Vec<u8>,
+    /// Blocks used by the save, in order
+    pub blocks: Vec<u8>,
+    /// Size in bytes
+    pub size: u32,
+    /// Title of the save, encoded in Shift-JIS. See `title_to_ascii`.
+    pub title: Vec<u8>,
+    pub icon: Icon,
+}
+
+impl Save {
+    /// Return the filename as a `String`, replacing invalid UTF-8
+    /// sequences with U+FFFD
+    pub fn filename_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.filename).into_owned()
+    }
+}
+
+/// Save icon
+pub struct Icon {
+    /// 16 color palette in the PlayStation 15bpp format
+    pub palette: [u16; 16],
+    /// Animation frames (1 to 3), 16x16 palette indexes each
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl Icon {
+    /// Convert the animation frame `frame` to 32bpp RGBA. Color 0 is
+    /// treated as transparent.
+    pub fn to_rgba(&self, frame: usize) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(16 * 16 * 4);
+
+        for &index in &self.frames[frame] {
+            let p = self.palette[index as usize];
+
+            let to_8bit = |c: u16| {
+                let c = (c & 0x1f) as u8;
+
+                (c << 3) | (c >> 2)
+            };
+
+            rgba.push(to_8bit(p));
+            rgba.push(to_8bit(p >> 5));
+            rgba.push(to_8bit(p >> 10));
+            rgba.push(if p == 0 { 0 } else { 0xff });
+        }
+
+        rgba
+    }
+}
+
+/// Convert a Shift-JIS save title to ASCII. Titles are usually
+/// written with the "full width" versions of the ASCII characters,
+/// those are converted. Other characters are replaced with '?'.
+pub fn title_to_ascii(title: &[u8]) -> String {
+    let mut s = String::new();
+
+    let mut i = 0;
+
+    while i < title.len() {
+        let b = title[i];
+
+        if b < 0x80 {
+            // Single byte ASCII
+            s.push(b as char);
+            i += 1;
+            continue;
+        }
+
+        if i + 1 >= title.len() {
+            s.push('?');
+            break;
+        }
+
+        let c = ((b as u16) << 8) | title[i + 1] as u16;
+
+        i += 2;
+
+        let ascii =
+            match c {
+                0x8140 => ' ',
+                0x824f...0x8258 => (b'0' + (c - 0x824f) as u8) as char,
+                0x8260...0x8279 => (b'A' + (c - 0x8260) as u8) as char,
+                0x8281...0x829a => (b'a' + (c - 0x8281) as u8) as char,
+                0x8143 => ',',
+                0x8144 => '.',
+                0x8146 => ':',
+                0x8147 => ';',
+                0x8148 => '?',
+                0x8149 => '!',
+                0x815e => '/',
+                0x8169 => '(',
+                0x816a => ')',
+                0x816d => '[',
+                0x816e => ']',
+                0x817b => '+',
+                0x817c => '-',
+                0x8181 => '=',
+                0x8193 => '%',
+                0x8194 => '#',
+                0x8195 => '&',
+                0x8196 => '*',
+                0x8197 => '@',
+                _ => '?',
+            };
+
+        s.push(ascii);
+    }
+
+    s
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The image doesn't have the expected size
+    BadSize(usize),
+    /// Missing "MC" or "SC" magic or invalid directory entry
+    BadMagic,
+    /// Checksum mismatch in the given frame
+    BadChecksum(u8),
+    /// Invalid data block number
+    BadBlock(u8),
+    /// The block isn't the first block of a save
+    NotFirstBlock(u8),
+    /// The chain of blocks of the save starting at the given block
+    /// is corrupted
+    BrokenChain(u8),
+    /// Not enough free blocks on the card
+    NoSpace,
+    /// A save with the same filename already exists on the card
+    FileExists,
+}
+
+/// Compute the checksum of a frame: XOR of all the bytes but the
+/// last one
+fn checksum(frame: &[u8]) -> u8 {
+    frame[0..SECTOR_SIZE - 1].iter().fold(0, |c, &b| c ^ b)
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    (b[0] as u32) |
+    ((b[1] as u32) << 8) |
+    ((b[2] as u32) << 16) |
+    ((b[3] as u32) << 24)
+}
+
+fn write_u32(b: &mut [u8], v: u32) {
+    for i in 0..4 {
+        b[i] = (v >> (i * 8)) as u8;
+    }
+}
+
+#[test]
+fn import_export_delete() {
+    let mut card = CardImage::formatted();
+
+    // Formatted cards must pass validation
+    assert!(CardImage::new(card.image()).is_ok());
+
+    // Build a two block save
+    let mut mcs = vec![0; SECTOR_SIZE + 2 * BLOCK_SIZE];
+
+    {
+        let mut entry = DirEntry::free(1);
+
+        entry.state = BlockState::First;
+        entry.size = (2 * BLOCK_SIZE) as u32;
+        entry.filename = b"BASLUS-00000TEST".to_vec();
+
+        mcs[0..SECTOR_SIZE].copy_from_slice(&entry.to_frame());
+
+        let title = &mut mcs[SECTOR_SIZE..];
+
+        title[0] = b'S';
+        title[1] = b'C';
+        title[2] = 0x11;
+        // Full width "Hi"
+        title[4..8].copy_from_slice(&[0x82, 0x67, 0x82, 0x89]);
+        // Palette entry 1 is white
+        title[0x62] = 0xff;
+        title[0x63] = 0x7f;
+        title[SECTOR_SIZE] = 0x10;
+    }
+
+    let first = card.import_mcs(&mcs).unwrap();
+
+    assert!(first == 1);
+
+    match card.import_mcs(&mcs) {
+        Err(Error::FileExists) => (),
+        _ => panic!("Duplicate save imported"),
+    }
+
+    let card = CardImage::new(card.image()).unwrap();
+
+    let saves = card.saves();
+
+    assert!(saves.len() == 1);
+    assert!(saves[0].filename == b"BASLUS-00000TEST");
+    assert!(saves[0].filename_lossy() == "BASLUS-00000TEST");
+    assert!(saves[0].blocks == [1, 2]);
+    assert!(title_to_ascii(&saves[0].title) == "Hi");
+    assert!(saves[0].icon.frames.len() == 1);
+    assert!(saves[0].icon.frames[0][0..2] == [0, 1]);
+    assert!(saves[0].icon.to_rgba(0)[0..8] == [0, 0, 0, 0,
+                                              0xff, 0xff, 0xff, 0xff]);
+
+    assert!(card.export_mcs(1).unwrap() == mcs);
+
+    let mut card = card;
+
+    card.delete(1).unwrap();
+
+    assert!(card.saves().is_empty());
+    assert!(card.directory().iter().all(|e| e.state.is_free()));
+    assert!(CardImage::new(card.image()).is_ok());
+}
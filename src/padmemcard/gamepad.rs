@@ -1,5 +1,7 @@
 use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
+use gpu::DisplayTiming;
+
 pub struct GamePad {
     /// Gamepad profile. *Not* stored in the savestate.
     profile: Box<Profile>,
@@ -8,6 +10,9 @@ pub struct GamePad {
     seq:    u8,
     /// False if the pad is done processing the current command
     active: bool,
+    /// Optional callback invoked when the pad is addressed. *Not*
+    /// stored in the savestate.
+    poll_hook: Option<Box<PollHook>>,
 }
 
 impl GamePad {
@@ -17,6 +22,7 @@ impl GamePad {
             seq: 0,
             active: true,
             profile: Box::new(DisconnectedProfile),
+            poll_hook: None,
         }
     }
 
@@ -35,6 +41,14 @@ impl GamePad {
             return (0xff, false);
         }
 
+        // The select line is shared with the memory card, the pad is
+        // only addressed if the first byte is 0x01
+        if self.seq == 0 && cmd == 0x01 {
+            if let Some(ref mut hook) = self.poll_hook {
+                hook.poll(&mut *self.profile);
+            }
+        }
+
         let (resp, dsr) = self.profile.handle_command(self.seq, cmd);
 
         // If we're not asserting DSR it either means that we've
@@ -56,6 +70,30 @@ impl GamePad {
     pub fn set_profile(&mut self, profile: Box<Profile>) {
         self.profile = profile
     }
+
+    /// Return the current state of the rumble motors, frontends can
+    /// poll it to drive force feedback.
+    pub fn rumble(&self) -> Rumble {
+        self.profile.rumble()
+    }
+
+    /// Install `hook`, it'll be called every time the game addresses
+    /// the pad, right before it reads the input state. This lets the
+    /// frontend update the profile with the latest input and detect
+    /// lag frames (frames where the game didn't poll the pad).
+    pub fn set_poll_hook(&mut self, hook: Box<PollHook>) {
+        self.poll_hook = Some(hook)
+    }
+
+    /// Remove the poll hook and return it
+    pub fn remove_poll_hook(&mut self) -> Option<Box<PollHook>> {
+        self.poll_hook.take()
+    }
+
+    /// Update the display timings used by light guns
+    pub fn set_display_timing(&mut self, timing: &DisplayTiming) {
+        self.profile.set_display_timing(timing)
+    }
 }
 
 impl Encodable for GamePad {
@@ -92,12 +130,34 @@ impl Decodable for GamePad {
     }
 }
 
-/// Digital buttons on a PlayStation controller. The value assigned to
-/// each button is the bit position in the 16bit word returned in the
-/// serial protocol
+/// Callback used by frontends to update the input state when the
+/// game polls the pad
+pub trait PollHook {
+    /// Called when the pad receives the 0x01 address byte, before it
+    /// replies to it
+    fn poll(&mut self, profile: &mut Profile);
+}
+
+impl<F> PollHook for F
+    where F: FnMut(&mut Profile) {
+    fn poll(&mut self, profile: &mut Profile) {
+        self(profile)
+    }
+}
+
+/// Digital buttons on PlayStation input devices. For the standard
+/// controller buttons (`Select` through `Square`) the value is the
+/// bit position in the 16bit button word returned in the serial
+/// protocol. Values 16 and above are device-specific buttons which
+/// aren't part of that word, the profiles handling them map them to
+/// their own reply format.
 #[derive(Clone,Copy,Debug)]
 pub enum Button {
     Select = 0,
+    /// Left stick button, only available on analog controllers
+    L3 = 1,
+    /// Right stick button, only available on analog controllers
+    R3 = 2,
     Start = 3,
     DUp = 4,
     DRight = 5,
@@ -111,6 +171,34 @@ pub enum Button {
     Circle = 13,
     Cross = 14,
     Square = 15,
+    /// "Analog" mode button on analog controllers, toggles the
+    /// analog mode instead of being reported
+    Analog = 16,
+    /// Mouse buttons, bits 11 and 10 of the mouse button word
+    MouseLeft = 17,
+    MouseRight = 18,
+    /// GunCon trigger and side buttons, bits 13, 3 and 14 of the
+    /// GunCon button word
+    GunTrigger = 19,
+    GunA = 20,
+    GunB = 21,
+}
+
+/// Update the active low button state word `buttons`. Buttons which
+/// are not part of the word are ignored.
+fn update_buttons(buttons: u16, button: Button, state: ButtonState) -> u16 {
+    let bit = button as usize;
+
+    if bit >= 16 {
+        return buttons;
+    }
+
+    let mask = 1 << bit;
+
+    match state {
+        ButtonState::Pressed  => buttons & !mask,
+        ButtonState::Released => buttons | mask,
+    }
 }
 
 #[derive(Clone,Copy,Debug)]
@@ -119,6 +207,57 @@ pub enum ButtonState {
     Released,
 }
 
+/// Rumble motor intensities
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Rumble {
+    /// Small motor, it can only be turned on (0xff) or off (0x00)
+    pub small: u8,
+    /// Large motor intensity, 0x00 (off) to 0xff
+    pub large: u8,
+}
+
+impl Rumble {
+    pub fn off() -> Rumble {
+        Rumble {
+            small: 0,
+            large: 0,
+        }
+    }
+}
+
+/// Absolute analog axes. The value of the stick axes goes from 0x00
+/// (left or up) to 0xff (right or down), 0x80 is the center
+/// position.
+#[derive(Clone,Copy,Debug)]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    /// NeGcon twist, 0x00 (left) to 0xff (right)
+    Twist,
+    /// NeGcon analog buttons, 0x00 (released) to 0xff (fully
+    /// pressed)
+    NegconI,
+    NegconII,
+    NegconL,
+}
+
+/// Analog input events
+#[derive(Clone,Copy,Debug)]
+pub enum AnalogInput {
+    /// Absolute position of an axis
+    Axis(Axis, u8),
+    /// Relative motion of a mouse (x, y), positive values go right
+    /// and down
+    Motion(i32, i32),
+    /// Position a light gun is aimed at within the displayed
+    /// picture, from (0, 0) for the top-left corner to (0xffff,
+    /// 0xffff) for the bottom-right corner. `None` if the gun points
+    /// away from the screen.
+    Aim(Option<(u16, u16)>),
+}
+
 /// Trait used to abstract away the various controller types.
 pub trait Profile {
     /// Handle a command byte sent by the console. `seq` is the byte
@@ -135,6 +274,22 @@ pub trait Profile {
     /// in a row with the same button and the same state, it should be
     /// idempotent.
     fn set_button_state(&mut self, button: Button, state: ButtonState);
+
+    /// Handle an analog input event. Profiles ignore the events they
+    /// don't support.
+    fn set_analog_input(&mut self, _input: AnalogInput) {
+    }
+
+    /// Update the display timings, needed by light guns to compute
+    /// the position of the beam
+    fn set_display_timing(&mut self, _timing: &DisplayTiming) {
+    }
+
+    /// Return the state of the rumble motors. Profiles without
+    /// motors always return `Rumble::off()`.
+    fn rumble(&self) -> Rumble {
+        Rumble::off()
+    }
 }
 
 /// Dummy profile emulating an empty pad slot
@@ -186,14 +341,670 @@ impl Profile for DigitalProfile {
     }
 
     fn set_button_state(&mut self, button: Button, state: ButtonState) {
-        let s = self.0;
+        match button {
+            // Not present on the digital pad
+            Button::L3 | Button::R3 => return,
+            _ => (),
+        }
+
+        self.0 = update_buttons(self.0, button, state);
+    }
+}
+
+/// SCPH-1200: DualShock analog controller.
+pub struct DualShockProfile {
+    /// Button state, one bit per button, active low
+    buttons: u16,
+    /// Right X, right Y, left X, left Y. Same order as in the
+    /// response to the read command.
+    axes: [u8; 4],
+    /// True if the controller is in analog mode (LED on)
+    analog: bool,
+    /// If true the analog mode can't be changed using the "Analog"
+    /// button
+    analog_locked: bool,
+    /// State of the "Analog" button, used to toggle the analog mode
+    /// on press
+    analog_pressed: bool,
+    /// True if the controller is in configuration mode
+    config: bool,
+    /// Command being processed
+    command: u8,
+    /// Number of bytes following the `0x5a` ID byte in the current
+    /// reply
+    reply_len: u8,
+    /// Parameter byte of the current command
+    param: u8,
+    /// Mapping of the bytes following the read command to the rumble
+    /// motors (0x00: small motor, 0x01: large motor, 0xff: unmapped)
+    motor_mapping: [u8; 6],
+    /// Current motor values
+    rumble: Rumble,
+}
+
+impl DualShockProfile {
+    pub fn new() -> DualShockProfile {
+        DualShockProfile {
+            buttons: 0xffff,
+            axes: [0x80; 4],
+            analog: false,
+            analog_locked: false,
+            analog_pressed: false,
+            config: false,
+            command: 0,
+            reply_len: 0,
+            param: 0,
+            motor_mapping: [0xff; 6],
+            rumble: Rumble::off(),
+        }
+    }
+
+    /// Return true if the controller is in analog mode (LED on)
+    pub fn analog_mode(&self) -> bool {
+        self.analog
+    }
+
+    /// Controller ID byte for the current mode
+    fn id(&self) -> u8 {
+        if self.config {
+            0xf3
+        } else if self.analog {
+            0x73
+        } else {
+            0x41
+        }
+    }
+
+    /// Button and stick state, `n` is the position in the reply
+    fn input_state(&self, n: u8) -> u8 {
+        match n {
+            0 => self.buttons as u8,
+            1 => (self.buttons >> 8) as u8,
+            n => self.axes[(n - 2) as usize],
+        }
+    }
+
+    /// Handle the read command (0x42), the command bytes control the
+    /// rumble motors
+    fn read(&mut self, n: u8, cmd: u8) -> u8 {
+        match self.motor_mapping[n as usize] {
+            // The small motor can only be turned on or off
+            0x00 => self.rumble.small = if cmd & 1 != 0 { 0xff } else { 0 },
+            0x01 => self.rumble.large = cmd,
+            _ => (),
+        }
+
+        self.input_state(n)
+    }
+
+    /// Return the reply byte `n` (following the 0x5a ID byte) for
+    /// the current command, `cmd` is the byte sent by the console.
+    fn reply(&mut self, n: u8, cmd: u8) -> u8 {
+        if n == 0 {
+            self.param = cmd;
+        }
+
+        match self.command {
+            0x42 => self.read(n, cmd),
+            // Enter/exit configuration mode. Outside of configuration
+            // mode it also returns the controller state.
+            0x43 => {
+                let r =
+                    if self.config {
+                        0x00
+                    } else {
+                        self.input_state(n)
+                    };
+
+                if n == 0 {
+                    self.config = cmd == 0x01;
+                }
+
+                r
+            }
+            // Set analog mode and lock
+            0x44 => {
+                match n {
+                    0 => match cmd {
+                        0x00 => self.analog = false,
+                        0x01 => self.analog = true,
+                        _ => (),
+                    },
+                    1 => self.analog_locked = cmd == 0x03,
+                    _ => (),
+                }
+
+                0x00
+            }
+            // Get controller type and LED state
+            0x45 => {
+                let led = self.analog as u8;
+
+                [0x01, 0x02, led, 0x02, 0x01, 0x00][n as usize]
+            }
+            // Unknown constant responses
+            0x46 =>
+                if self.param == 0 {
+                    [0x00, 0x00, 0x01, 0x02, 0x00, 0x0a][n as usize]
+                } else {
+                    [0x00, 0x00, 0x01, 0x01, 0x01, 0x14][n as usize]
+                },
+            0x47 => [0x00, 0x00, 0x02, 0x00, 0x01, 0x00][n as usize],
+            0x4c =>
+                match n {
+                    3 if self.param == 0 => 0x04,
+                    3 => 0x07,
+                    _ => 0x00,
+                },
+            // Rumble motor mapping, the old mapping is returned
+            0x4d => {
+                let n = n as usize;
+
+                let old = self.motor_mapping[n];
+
+                self.motor_mapping[n] = cmd;
+
+                old
+            }
+            _ => 0x00,
+        }
+    }
+}
+
+impl Profile for DualShockProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            // First byte should be 0x01 if the command targets
+            // the controller
+            0 => (0xff, (cmd == 0x01)),
+            1 => {
+                let valid =
+                    match cmd {
+                        0x42 | 0x43 => true,
+                        // The other commands are only available in
+                        // configuration mode
+                        0x40...0x4f => self.config,
+                        _ => false,
+                    };
+
+                if !valid {
+                    return (0xff, false);
+                }
+
+                self.command = cmd;
+
+                // The digital mode returns only the button state
+                self.reply_len =
+                    if self.config || self.analog {
+                        6
+                    } else {
+                        2
+                    };
+
+                (self.id(), true)
+            }
+            2 => (0x5a, true),
+            n => {
+                let n = n - 3;
+
+                if n >= self.reply_len {
+                    // Shouldn't be reached
+                    return (0xff, false);
+                }
+
+                let r = self.reply(n, cmd);
+
+                // We don't assert DSR for the last byte
+                (r, n + 1 < self.reply_len)
+            }
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        if let Button::Analog = button {
+            let pressed =
+                match state {
+                    ButtonState::Pressed => true,
+                    ButtonState::Released => false,
+                };
+
+            if pressed && !self.analog_pressed && !self.analog_locked {
+                self.analog = !self.analog;
+            }
+
+            self.analog_pressed = pressed;
+
+            return;
+        }
+
+        self.buttons = update_buttons(self.buttons, button, state);
+    }
+
+    fn rumble(&self) -> Rumble {
+        self.rumble
+    }
+
+    fn set_analog_input(&mut self, input: AnalogInput) {
+        let (index, value) =
+            match input {
+                AnalogInput::Axis(Axis::RightX, v) => (0, v),
+                AnalogInput::Axis(Axis::RightY, v) => (1, v),
+                AnalogInput::Axis(Axis::LeftX, v) => (2, v),
+                AnalogInput::Axis(Axis::LeftY, v) => (3, v),
+                _ => return,
+            };
+
+        self.axes[index] = value;
+    }
+}
+
+/// SCPH-1030: PlayStation Mouse
+pub struct MouseProfile {
+    /// Button state, active low
+    buttons: u16,
+    /// Motion accumulated since the last read
+    motion: (i32, i32),
+}
+
+impl MouseProfile {
+    pub fn new() -> MouseProfile {
+        MouseProfile {
+            buttons: 0xffff,
+            motion: (0, 0),
+        }
+    }
 
-        let mask = 1 << (button as usize);
+    /// Return the motion along one axis for the current read and
+    /// remove it from the accumulated motion. The mouse reports
+    /// signed 8bit values, the rest is kept for the next read.
+    fn take_motion(motion: &mut i32) -> u8 {
+        let m =
+            if *motion < -128 {
+                -128
+            } else if *motion > 127 {
+                127
+            } else {
+                *motion
+            };
+
+        *motion -= m;
 
-        self.0 =
+        m as i8 as u8
+    }
+}
+
+impl Profile for MouseProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // Mouse ID
+            1 => (0x12, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5 => (MouseProfile::take_motion(&mut self.motion.0), true),
+            6 => (MouseProfile::take_motion(&mut self.motion.1), false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        // Bits 10 and 11 of the button word
+        let mask =
+            match button {
+                Button::MouseRight => 1 << 10,
+                Button::MouseLeft => 1 << 11,
+                _ => return,
+            };
+
+        self.buttons =
             match state {
-                ButtonState::Pressed  => s & !mask,
-                ButtonState::Released => s | mask,
+                ButtonState::Pressed  => self.buttons & !mask,
+                ButtonState::Released => self.buttons | mask,
             };
     }
+
+    fn set_analog_input(&mut self, input: AnalogInput) {
+        if let AnalogInput::Motion(x, y) = input {
+            self.motion.0 = self.motion.0.saturating_add(x);
+            self.motion.1 = self.motion.1.saturating_add(y);
+        }
+    }
+}
+
+/// SLPH-00001: NeGcon "twist" controller. The A, B and R buttons
+/// are mapped to Circle, Triangle and R1.
+pub struct NegconProfile {
+    /// Button state, active low
+    buttons: u16,
+    /// Twist, I, II and L in the order of the reply
+    axes: [u8; 4],
+}
+
+impl NegconProfile {
+    pub fn new() -> NegconProfile {
+        NegconProfile {
+            buttons: 0xffff,
+            axes: [0x80, 0, 0, 0],
+        }
+    }
+}
+
+impl Profile for NegconProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // NeGcon ID
+            1 => (0x23, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5...7 => (self.axes[(seq - 5) as usize], true),
+            8 => (self.axes[3], false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        match button {
+            Button::DUp | Button::DRight | Button::DDown | Button::DLeft |
+            Button::Start | Button::Circle | Button::Triangle | Button::R1 =>
+                self.buttons = update_buttons(self.buttons, button, state),
+            _ => (),
+        }
+    }
+
+    fn set_analog_input(&mut self, input: AnalogInput) {
+        let (index, value) =
+            match input {
+                AnalogInput::Axis(Axis::Twist, v) => (0, v),
+                AnalogInput::Axis(Axis::NegconI, v) => (1, v),
+                AnalogInput::Axis(Axis::NegconII, v) => (2, v),
+                AnalogInput::Axis(Axis::NegconL, v) => (3, v),
+                _ => return,
+            };
+
+        self.axes[index] = value;
+    }
+}
+
+/// SLPH-00034: Namco GunCon light gun.
+///
+/// The GunCon reports the position of the beam when it passes in
+/// front of the gun: the X coordinate is the number of 8MHz clock
+/// ticks since HSYNC and the Y coordinate the number of lines since
+/// VSYNC. We compute them from the aim position and the display
+/// timings: the aim is converted to a dotclock tick within the
+/// displayed picture, then to the corresponding GPU clock tick.
+pub struct GunConProfile {
+    /// Button state, active low
+    buttons: u16,
+    /// Aim position within the displayed picture
+    aim: Option<(u16, u16)>,
+    timing: DisplayTiming,
+}
+
+impl GunConProfile {
+    pub fn new() -> GunConProfile {
+        GunConProfile {
+            buttons: 0xffff,
+            aim: None,
+            timing: DisplayTiming::new(),
+        }
+    }
+
+    /// Compute the coordinates reported by the gun
+    fn position(&self) -> (u16, u16) {
+        let (x, y) =
+            match self.aim {
+                Some(a) => a,
+                // No light detected
+                None => return (0x0001, 0x000a),
+            };
+
+        let t = &self.timing;
+
+        // Position of `pos` within `len` units
+        let scale = |len: u16, pos: u16| (len as u32 * pos as u32) >> 16;
+
+        let divider = t.dotclock_divider as u16;
+
+        let dots = t.horiz_end.saturating_sub(t.horiz_start) / divider;
+        let dot = scale(dots, x);
+
+        let tick = (t.horiz_start as u32 + dot * divider as u32) as u64;
+
+        // Convert GPU clock ticks to 8MHz ticks
+        let x = (tick * 8_000_000) / t.gpu_clock_hz as u64;
+
+        let lines = t.line_end.saturating_sub(t.line_start);
+        let y = t.line_start as u32 + scale(lines, y);
+
+        (x as u16, y as u16)
+    }
+}
+
+impl Profile for GunConProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        let (x, y) = self.position();
+
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            // GunCon ID
+            1 => (0x63, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5 => (x as u8, true),
+            6 => ((x >> 8) as u8, true),
+            7 => (y as u8, true),
+            8 => ((y >> 8) as u8, false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        let mask =
+            match button {
+                Button::GunA => 1 << 3,
+                Button::GunTrigger => 1 << 13,
+                Button::GunB => 1 << 14,
+                _ => return,
+            };
+
+        self.buttons =
+            match state {
+                ButtonState::Pressed  => self.buttons & !mask,
+                ButtonState::Released => self.buttons | mask,
+            };
+    }
+
+    fn set_analog_input(&mut self, input: AnalogInput) {
+        if let AnalogInput::Aim(aim) = input {
+            self.aim = aim;
+        }
+    }
+
+    fn set_display_timing(&mut self, timing: &DisplayTiming) {
+        self.timing = *timing;
+    }
+}
+
+/// Send the bytes of `cmd` to `profile` as a single transaction and
+/// return the replies
+#[cfg(test)]
+fn transfer(profile: &mut Profile, cmd: &[u8]) -> Vec<(u8, bool)> {
+    cmd.iter()
+        .enumerate()
+        .map(|(seq, &c)| profile.handle_command(seq as u8, c))
+        .collect()
+}
+
+#[test]
+fn dualshock_config_mode() {
+    let mut pad = DualShockProfile::new();
+
+    // Digital mode read
+    let r = transfer(&mut pad, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+    assert!(r[1] == (0x41, true));
+    assert!(r[4] == (0xff, false));
+
+    // Enter config mode
+    transfer(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+
+    // Switch to analog mode and map the motors
+    let r = transfer(&mut pad, &[0x01, 0x44, 0x00, 0x01, 0x03,
+                                 0x00, 0x00, 0x00, 0x00]);
+    assert!(r[1] == (0xf3, true));
+    assert!(r[8] == (0x00, false));
+
+    let r = transfer(&mut pad, &[0x01, 0x4d, 0x00, 0x00, 0x01,
+                                 0xff, 0xff, 0xff, 0xff]);
+    assert!(r[3] == (0xff, true));
+
+    let r = transfer(&mut pad, &[0x01, 0x45, 0x00, 0x00, 0x00,
+                                 0x00, 0x00, 0x00, 0x00]);
+    assert!(r[5] == (0x01, true));
+
+    // Exit config mode
+    let r = transfer(&mut pad, &[0x01, 0x43, 0x00, 0x00, 0x00,
+                                 0x00, 0x00, 0x00, 0x00]);
+    assert!(r[1] == (0xf3, true));
+
+    pad.set_analog_input(AnalogInput::Axis(Axis::LeftX, 0x12));
+    // The analog mode is locked
+    pad.set_button_state(Button::Analog, ButtonState::Pressed);
+
+    let r = transfer(&mut pad, &[0x01, 0x42, 0x00, 0xff, 0x80,
+                                 0x00, 0x00, 0x00, 0x00]);
+    assert!(r[1] == (0x73, true));
+    assert!(r[7] == (0x12, true));
+    assert!(r[8] == (0x80, false));
+    assert!(pad.rumble() == Rumble { small: 0xff, large: 0x80 });
+}
+
+#[test]
+fn mouse_motion() {
+    let mut mouse = MouseProfile::new();
+
+    mouse.set_button_state(Button::MouseLeft, ButtonState::Pressed);
+    mouse.set_analog_input(AnalogInput::Motion(200, -3));
+
+    let read = |mouse: &mut MouseProfile| {
+        transfer(mouse, &[0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00])
+    };
+
+    let r = read(&mut mouse);
+
+    assert!(r[1] == (0x12, true));
+    assert!(r[4] == (0xf7, true));
+    assert!(r[5] == (127, true));
+    assert!(r[6] == (-3i8 as u8, false));
+
+    // The rest of the motion is reported by the next read
+    let r = read(&mut mouse);
+
+    assert!(r[5] == (73, true));
+    assert!(r[6] == (0, false));
+}
+
+#[test]
+fn negcon_reply() {
+    let mut negcon = NegconProfile::new();
+
+    negcon.set_button_state(Button::Circle, ButtonState::Pressed);
+    // Not present on the NeGcon
+    negcon.set_button_state(Button::Cross, ButtonState::Pressed);
+    negcon.set_analog_input(AnalogInput::Axis(Axis::Twist, 0x10));
+    negcon.set_analog_input(AnalogInput::Axis(Axis::NegconI, 0x20));
+    negcon.set_analog_input(AnalogInput::Axis(Axis::NegconII, 0x30));
+    negcon.set_analog_input(AnalogInput::Axis(Axis::NegconL, 0x40));
+
+    let r = transfer(&mut negcon, &[0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]);
+
+    assert!(r[1] == (0x23, true));
+    assert!(r[2] == (0x5a, true));
+    assert!(r[3] == (0xff, true));
+    assert!(r[4] == (0xdf, true));
+    assert!(r[5] == (0x10, true));
+    assert!(r[6] == (0x20, true));
+    assert!(r[7] == (0x30, true));
+    assert!(r[8] == (0x40, false));
+}
+
+#[test]
+fn guncon_position() {
+    let mut gun = GunConProfile::new();
+
+    // No light detected
+    assert!(gun.position() == (0x0001, 0x000a));
+
+    // Top-left corner of the default 256x240 NTSC picture: first
+    // dot of the first line
+    gun.set_analog_input(AnalogInput::Aim(Some((0, 0))));
+    assert!(gun.position() == (0x004c, 0x0010));
+
+    // Bottom-right corner: dot 255 of line 239
+    gun.set_analog_input(AnalogInput::Aim(Some((0xffff, 0xffff))));
+    assert!(gun.position() == (0x01c8, 0x00ff));
+
+    gun.set_button_state(Button::GunTrigger, ButtonState::Pressed);
+
+    let r = transfer(&mut gun, &[0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]);
+
+    assert!(r[1] == (0x63, true));
+    assert!(r[4] == (0xdf, true));
+    assert!(r[5] == (0xc8, true));
+    assert!(r[6] == (0x01, true));
+    assert!(r[7] == (0xff, true));
+    assert!(r[8] == (0x00, false));
+
+    gun.set_analog_input(AnalogInput::Aim(None));
+    assert!(gun.position() == (0x0001, 0x000a));
+}
+
+#[test]
+fn poll_hook() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let polls = Rc::new(Cell::new(0));
+
+    let mut pad = GamePad::disconnected();
+
+    pad.set_profile(Box::new(DigitalProfile::new()));
+
+    {
+        let polls = polls.clone();
+
+        pad.set_poll_hook(Box::new(move |profile: &mut Profile| {
+            polls.set(polls.get() + 1);
+            profile.set_button_state(Button::Circle, ButtonState::Pressed);
+        }));
+    }
+
+    assert!(polls.get() == 0);
+
+    // Memory card access: the pad is selected but not addressed
+    pad.select();
+    pad.send_command(0x81);
+
+    assert!(polls.get() == 0);
+
+    pad.select();
+
+    let r: Vec<u8> = [0x01, 0x42, 0x00, 0x00, 0x00]
+        .iter()
+        .map(|&c| pad.send_command(c).0)
+        .collect();
+
+    assert!(polls.get() == 1);
+    assert!(r[4] == 0xdf);
+
+    assert!(pad.remove_poll_hook().is_some());
+
+    pad.select();
+    pad.send_command(0x01);
+
+    assert!(polls.get() == 1);
 }
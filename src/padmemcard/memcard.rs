@@ -0,0 +1,477 @@
+//! Memory card emulation.
+//!
+//! The memory card is addressed by sending `0x81` as the first byte
+//! of a transaction on the pad/memory card bus. The card contains
+//! 1024 sectors of 128 bytes.
+//!
+//! Timing is not modeled: the card's bytes are clocked like the
+//! gamepad's, `8 * baud_div` cycles each, and acknowledged with the
+//! same short DSR pulse. The real card is noticeably slower to
+//! acknowledge each byte than a controller, so software measuring
+//! the card's response time won't see accurate delays.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+/// Size of a memory card in bytes
+pub const MEMCARD_SIZE: usize = 128 * 1024;
+
+/// Size of a memory card sector in bytes
+pub const SECTOR_SIZE: usize = 128;
+
+/// Number of sectors on a memory card
+pub const SECTOR_COUNT: u16 = (MEMCARD_SIZE / SECTOR_SIZE) as u16;
+
+pub struct MemoryCard {
+    /// Contents of the card, `None` if no card is inserted. *Not*
+    /// stored in the savestate.
+    data: Option<Box<[u8; MEMCARD_SIZE]>>,
+    /// True if the card has been written to since it was inserted or
+    /// last flushed
+    dirty: bool,
+    /// Status flag returned at the start of every command. Bit 3 is
+    /// set when the card is inserted and cleared by the first
+    /// successful write.
+    flag: u8,
+    /// Counter keeping track of the current position in the reply
+    /// sequence
+    seq: u8,
+    /// False if the card is done processing the current command
+    active: bool,
+    /// Command being processed
+    command: Command,
+    /// Sector address of the current read or write command
+    address: u16,
+    /// Last byte received, some replies echo it back
+    last_byte: u8,
+    /// Running checksum of the current read or write
+    checksum: u8,
+    /// Data received during a write command
+    write_buffer: Vec<u8>,
+}
+
+impl MemoryCard {
+    /// Create an empty memory card slot
+    pub fn new() -> MemoryCard {
+        MemoryCard {
+            data: None,
+            dirty: false,
+            flag: 0x08,
+            seq: 0,
+            active: false,
+            command: Command::None,
+            address: 0,
+            last_byte: 0,
+            checksum: 0,
+            write_buffer: Vec::with_capacity(SECTOR_SIZE),
+        }
+    }
+
+    /// Insert a card using the raw 128KB `image`
+    pub fn insert(&mut self, image: &[u8]) -> io::Result<()> {
+        if image.len() != MEMCARD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Invalid memory card image size"));
+        }
+
+        let mut data = box_array![0; MEMCARD_SIZE];
+
+        data.copy_from_slice(image);
+
+        self.data = Some(data);
+        self.dirty = false;
+        // Tell the game that the card has been changed
+        self.flag = 0x08;
+
+        Ok(())
+    }
+
+    /// Remove the card from the slot and return its contents
+    pub fn remove(&mut self) -> Option<Box<[u8; MEMCARD_SIZE]>> {
+        self.dirty = false;
+
+        self.data.take()
+    }
+
+    pub fn is_inserted(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// Raw contents of the card, `None` if no card is inserted
+    pub fn image(&self) -> Option<&[u8]> {
+        self.data.as_ref().map(|d| &d[..])
+    }
+
+    /// Return true if the card has been modified since it was
+    /// inserted or last flushed
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Insert the card stored in the raw `.mcr` image at `path`
+    pub fn load_mcr<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut image = Vec::with_capacity(MEMCARD_SIZE);
+
+        let mut file = try!(File::open(path));
+
+        try!(file.read_to_end(&mut image));
+
+        self.insert(&image)
+    }
+
+    /// Write the contents of the card to the raw `.mcr` image at
+    /// `path`
+    pub fn flush_mcr<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        {
+            let data =
+                match self.data {
+                    Some(ref d) => d,
+                    None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                                      "No memory card")),
+                };
+
+            let mut file = try!(File::create(path));
+
+            try!(file.write_all(&data[..]));
+        }
+
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Called when the "select" line goes down.
+    pub fn select(&mut self) {
+        self.active = true;
+        self.seq = 0;
+        self.command = Command::None;
+    }
+
+    /// Handle a command byte. Returns the response byte and whether
+    /// the card issues a DSR pulse to request the next byte.
+    pub fn send_command(&mut self, cmd: u8) -> (u8, bool) {
+        if !self.active {
+            return (0xff, false);
+        }
+
+        let (resp, dsr) = self.handle_command(cmd);
+
+        self.active = dsr;
+        self.last_byte = cmd;
+        self.seq = self.seq.wrapping_add(1);
+
+        (resp, dsr)
+    }
+
+    fn handle_command(&mut self, cmd: u8) -> (u8, bool) {
+        if self.data.is_none() {
+            // No card, the bus is open
+            return (0xff, false);
+        }
+
+        match self.seq {
+            // Memory card address
+            0 => (0xff, cmd == 0x81),
+            1 => {
+                self.command =
+                    match cmd {
+                        0x52 => Command::Read,
+                        0x57 => Command::Write,
+                        0x53 => Command::GetId,
+                        _ => {
+                            warn!("Unhandled memory card command {:02x}",
+                                  cmd);
+                            return (self.flag, false);
+                        }
+                    };
+
+                (self.flag, true)
+            }
+            // Memory card ID
+            2 => (0x5a, true),
+            3 => (0x5d, true),
+            n => {
+                let n = n - 4;
+
+                match self.command {
+                    Command::Read => self.read(n, cmd),
+                    Command::Write => self.write(n, cmd),
+                    Command::GetId => self.get_id(n),
+                    Command::None => (0xff, false),
+                }
+            }
+        }
+    }
+
+    /// Handle the sector read command, `n` is the position in the
+    /// sequence after the ID bytes
+    fn read(&mut self, n: u8, cmd: u8) -> (u8, bool) {
+        let valid = self.address < SECTOR_COUNT;
+
+        match n {
+            // Address MSB
+            0 => {
+                self.address = (cmd as u16) << 8;
+                (0x00, true)
+            }
+            // Address LSB
+            1 => {
+                self.address |= cmd as u16;
+                (self.last_byte, true)
+            }
+            // Command acknowledge
+            2 => (0x5c, true),
+            3 => (0x5d, true),
+            // Confirmed address, 0xffff if the sector is invalid in
+            // which case the command is aborted
+            4 => {
+                if valid {
+                    let msb = (self.address >> 8) as u8;
+
+                    self.checksum = msb;
+
+                    (msb, true)
+                } else {
+                    (0xff, true)
+                }
+            }
+            5 => {
+                if valid {
+                    let lsb = self.address as u8;
+
+                    self.checksum ^= lsb;
+
+                    (lsb, true)
+                } else {
+                    (0xff, false)
+                }
+            }
+            // Sector data
+            6...133 => {
+                let offset = self.address as usize * SECTOR_SIZE
+                    + (n - 6) as usize;
+
+                let b = self.data.as_ref().unwrap()[offset];
+
+                self.checksum ^= b;
+
+                (b, true)
+            }
+            134 => (self.checksum, true),
+            // End byte: "G"ood
+            135 => (0x47, false),
+            _ => (0xff, false),
+        }
+    }
+
+    /// Handle the sector write command, `n` is the position in the
+    /// sequence after the ID bytes
+    fn write(&mut self, n: u8, cmd: u8) -> (u8, bool) {
+        match n {
+            // Address MSB
+            0 => {
+                self.address = (cmd as u16) << 8;
+                self.checksum = cmd;
+                self.write_buffer.clear();
+
+                (0x00, true)
+            }
+            // Address LSB
+            1 => {
+                self.address |= cmd as u16;
+                self.checksum ^= cmd;
+
+                (self.last_byte, true)
+            }
+            // Sector data
+            2...129 => {
+                self.write_buffer.push(cmd);
+                self.checksum ^= cmd;
+
+                (self.last_byte, true)
+            }
+            // Checksum
+            130 => {
+                // Store the result of the comparison in the checksum
+                // field, we'll need it for the end byte
+                self.checksum = (self.checksum == cmd) as u8;
+
+                (self.last_byte, true)
+            }
+            // Command acknowledge
+            131 => (0x5c, true),
+            132 => (0x5d, true),
+            // End byte
+            133 => {
+                let status =
+                    if self.address >= SECTOR_COUNT {
+                        // Bad sector
+                        0xff
+                    } else if self.checksum == 0 {
+                        // "N"ot good: bad checksum
+                        0x4e
+                    } else {
+                        let offset = self.address as usize * SECTOR_SIZE;
+
+                        let data = self.data.as_mut().unwrap();
+
+                        data[offset..offset + SECTOR_SIZE]
+                            .copy_from_slice(&self.write_buffer);
+
+                        self.dirty = true;
+                        self.flag &= !0x08;
+
+                        // "G"ood
+                        0x47
+                    };
+
+                (status, false)
+            }
+            _ => (0xff, false),
+        }
+    }
+
+    /// Handle the get ID command, `n` is the position in the sequence
+    /// after the ID bytes
+    fn get_id(&mut self, n: u8) -> (u8, bool) {
+        match n {
+            // Command acknowledge
+            0 => (0x5c, true),
+            1 => (0x5d, true),
+            // Card size information
+            2 => (0x04, true),
+            3 => (0x00, true),
+            4 => (0x00, true),
+            5 => (0x80, false),
+            _ => (0xff, false),
+        }
+    }
+}
+
+impl Encodable for MemoryCard {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+
+        // We don't store the card contents in the serialized data,
+        // we'll let the frontend insert it again
+        s.emit_struct("MemoryCard", 8, |s| {
+
+            try!(s.emit_struct_field("flag", 0,
+                                     |s| self.flag.encode(s)));
+            try!(s.emit_struct_field("seq", 1,
+                                     |s| self.seq.encode(s)));
+            try!(s.emit_struct_field("active", 2,
+                                     |s| self.active.encode(s)));
+            try!(s.emit_struct_field("command", 3,
+                                     |s| self.command.encode(s)));
+            try!(s.emit_struct_field("address", 4,
+                                     |s| self.address.encode(s)));
+            try!(s.emit_struct_field("last_byte", 5,
+                                     |s| self.last_byte.encode(s)));
+            try!(s.emit_struct_field("checksum", 6,
+                                     |s| self.checksum.encode(s)));
+            try!(s.emit_struct_field("write_buffer", 7,
+                                     |s| self.write_buffer.encode(s)));
+
+            Ok(())
+        })
+    }
+}
+
+impl Decodable for MemoryCard {
+    fn decode<D: Decoder>(d: &mut D) -> Result<MemoryCard, D::Error> {
+
+        d.read_struct("MemoryCard", 8, |d| {
+            let mut card = MemoryCard::new();
+
+            card.flag =
+                try!(d.read_struct_field("flag", 0, Decodable::decode));
+            card.seq =
+                try!(d.read_struct_field("seq", 1, Decodable::decode));
+            card.active =
+                try!(d.read_struct_field("active", 2, Decodable::decode));
+            card.command =
+                try!(d.read_struct_field("command", 3, Decodable::decode));
+            card.address =
+                try!(d.read_struct_field("address", 4, Decodable::decode));
+            card.last_byte =
+                try!(d.read_struct_field("last_byte", 5, Decodable::decode));
+            card.checksum =
+                try!(d.read_struct_field("checksum", 6, Decodable::decode));
+            card.write_buffer =
+                try!(d.read_struct_field("write_buffer",
+                                         7,
+                                         Decodable::decode));
+
+            Ok(card)
+        })
+    }
+}
+
+/// Memory card commands
+#[derive(Clone, Copy, PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
+enum Command {
+    /// No command received yet
+    None,
+    /// Read a sector
+    Read,
+    /// Write a sector
+    Write,
+    /// Get the card size information
+    GetId,
+}
+
+#[test]
+fn write_read_sector() {
+    let mut card = MemoryCard::new();
+
+    card.insert(&vec![0; MEMCARD_SIZE]).unwrap();
+
+    // Write sector 0x123
+    card.select();
+
+    let mut write = vec![0x81, 0x57, 0x00, 0x00, 0x01, 0x23];
+    let mut checksum = 0x01 ^ 0x23;
+
+    for i in 0..128 {
+        write.push(i as u8);
+        checksum ^= i as u8;
+    }
+
+    write.extend_from_slice(&[checksum, 0x00, 0x00, 0x00]);
+
+    let mut replies = Vec::new();
+
+    for &b in &write {
+        replies.push(card.send_command(b));
+    }
+
+    assert!(replies[1] == (0x08, true));
+    assert!(replies[2] == (0x5a, true));
+    assert!(replies[3] == (0x5d, true));
+    assert!(replies[write.len() - 1] == (0x47, false));
+    assert!(card.is_dirty());
+
+    // Read it back
+    card.select();
+
+    let mut read = vec![0x81, 0x52, 0x00, 0x00, 0x01, 0x23];
+
+    read.extend_from_slice(&[0; 134]);
+
+    let replies: Vec<_> = read.iter().map(|&b| card.send_command(b)).collect();
+
+    // The fresh card flag is cleared by the write
+    assert!(replies[1] == (0x00, true));
+    assert!(replies[8] == (0x01, true));
+    assert!(replies[9] == (0x23, true));
+
+    for i in 0..128 {
+        assert!(replies[10 + i] == (i as u8, true));
+    }
+
+    assert!(replies[138] == (checksum, true));
+    assert!(replies[139] == (0x47, false));
+}
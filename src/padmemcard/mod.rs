@@ -5,10 +5,17 @@ use interrupt::Interrupt;
 use timekeeper::{Peripheral, Cycles};
 use shared::SharedState;
 use tracer::module_tracer;
+use gpu::Gpu;
 
 use self::gamepad::GamePad;
+use self::memcard::MemoryCard;
+use self::multitap::Multitap;
 
 pub mod gamepad;
+pub mod memcard;
+pub mod filesystem;
+pub mod multitap;
+pub mod movie;
 
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct PadMemCard {
@@ -56,6 +63,16 @@ pub struct PadMemCard {
     pad1: GamePad,
     /// Gamepad in slot 2
     pad2: GamePad,
+    /// Memory card in slot 1
+    memcard1: MemoryCard,
+    /// Memory card in slot 2
+    memcard2: MemoryCard,
+    /// Multitap in slot 1. When present it replaces `pad1` and
+    /// `memcard1`.
+    multitap1: Option<Multitap>,
+    /// Multitap in slot 2. When present it replaces `pad2` and
+    /// `memcard2`.
+    multitap2: Option<Multitap>,
     /// Bus state machine
     bus: BusState,
 }
@@ -77,12 +94,19 @@ impl PadMemCard {
             rx_not_empty: false,
             pad1: GamePad::disconnected(),
             pad2: GamePad::disconnected(),
+            memcard1: MemoryCard::new(),
+            memcard2: MemoryCard::new(),
+            multitap1: None,
+            multitap2: None,
             bus: BusState::Idle,
         }
     }
 
+    /// Register write. `gpu` is used to retrieve the display
+    /// timings needed by light guns.
     pub fn store<T: Addressable>(&mut self,
                                  shared: &mut SharedState,
+                                 gpu: &Gpu,
                                  offset: u32,
                                  val: u32) {
 
@@ -111,7 +135,7 @@ impl PadMemCard {
                     // Byte access behaves like a halfword
                     panic!("Unhandled byte gamepad control access");
                 }
-                self.set_control(shared, val as u16);
+                self.set_control(shared, gpu, val as u16);
             }
             14 => self.baud_div = val as u16,
             _ => panic!("Unhandled write to gamepad register {} {:04x}",
@@ -245,6 +269,49 @@ impl PadMemCard {
         [ &mut self.pad1, &mut self.pad2 ]
     }
 
+    /// Return a mutable reference to the memory card slots
+    pub fn memcards_mut(&mut self) -> [&mut MemoryCard; 2] {
+        [ &mut self.memcard1, &mut self.memcard2 ]
+    }
+
+    /// Plug a multitap in `port` (0 or 1). The gamepad and memory
+    /// card connected directly to the port become unreachable until
+    /// the multitap is removed.
+    pub fn connect_multitap(&mut self, port: usize) {
+        *self.multitap_slot(port) = Some(Multitap::new());
+    }
+
+    /// Unplug the multitap in `port` (0 or 1) and return it
+    pub fn disconnect_multitap(&mut self, port: usize) -> Option<Multitap> {
+        self.multitap_slot(port).take()
+    }
+
+    /// Return a mutable reference to the multitap in `port` (0 or
+    /// 1), if any
+    pub fn multitap_mut(&mut self, port: usize) -> Option<&mut Multitap> {
+        self.multitap_slot(port).as_mut()
+    }
+
+    fn multitap_slot(&mut self, port: usize) -> &mut Option<Multitap> {
+        match port {
+            0 => &mut self.multitap1,
+            1 => &mut self.multitap2,
+            _ => panic!("Invalid controller port {}", port),
+        }
+    }
+
+    /// Return the devices connected to the targeted port
+    fn target_devices(&mut self) -> (&mut GamePad,
+                                     &mut MemoryCard,
+                                     &mut Option<Multitap>) {
+        match self.target {
+            Target::PadMemCard1 =>
+                (&mut self.pad1, &mut self.memcard1, &mut self.multitap1),
+            Target::PadMemCard2 =>
+                (&mut self.pad2, &mut self.memcard2, &mut self.multitap2),
+        }
+    }
+
     fn send_command(&mut self, shared: &mut SharedState, cmd: u8) {
         if !self.tx_en {
             // It should be stored in the FIFO and sent when tx_en is
@@ -259,9 +326,21 @@ impl PadMemCard {
 
         let (response, dsr) =
             if self.select {
-                match self.target {
-                    Target::PadMemCard1 => self.pad1.send_command(cmd),
-                    Target::PadMemCard2 => self.pad2.send_command(cmd),
+                let (pad, memcard, multitap) = self.target_devices();
+
+                match *multitap {
+                    Some(ref mut multitap) => multitap.send_command(cmd),
+                    None => {
+                        // The gamepad and memory card share the bus,
+                        // only the one addressed by the first byte of
+                        // the transaction should reply. The data line
+                        // is open drain so the responses are ANDed
+                        // together.
+                        let (pad_r, pad_dsr) = pad.send_command(cmd);
+                        let (card_r, card_dsr) = memcard.send_command(cmd);
+
+                        (pad_r & card_r, pad_dsr || card_dsr)
+                    }
                 }
             } else {
                 // No response
@@ -316,7 +395,10 @@ impl PadMemCard {
         ctrl
     }
 
-    fn set_control(&mut self, shared: &mut SharedState, ctrl: u16) {
+    fn set_control(&mut self,
+                   shared: &mut SharedState,
+                   gpu: &Gpu,
+                   ctrl: u16) {
         if ctrl & 0x40 != 0 {
             // Soft reset
             self.baud_div = 0;
@@ -382,10 +464,24 @@ impl PadMemCard {
             }
 
             if !prev_select && self.select {
-                // XXX Should probably also check self.target, not
-                // sure how it influences the select line. I assume
-                // only the targeted slot is selected?
-                self.pad1.select();
+                // XXX Not sure how self.target influences the select
+                // line. I assume only the targeted slot is selected?
+                // Light guns need the display timings to compute
+                // the beam position
+                let timing = gpu.display_timing();
+                let (pad, memcard, multitap) = self.target_devices();
+
+                match *multitap {
+                    Some(ref mut multitap) => {
+                        multitap.set_display_timing(&timing);
+                        multitap.select();
+                    }
+                    None => {
+                        pad.set_display_timing(&timing);
+                        pad.select();
+                        memcard.select();
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,897 @@
+//! Input movies: record the input state of each frame to replay it
+//! deterministically later.
+//!
+//! A movie starts either at power-on or from a savestate stored in
+//! the movie itself. The frontend must call `Recorder::end_frame` or
+//! `Player::play_frame` exactly once per emulated frame for the
+//! replay to stay in sync.
+//!
+//! Each frame stores the full state of every controller (buttons,
+//! analog axes, mouse motion and light gun aim) instead of the
+//! changes since the previous frame: the controller state isn't part
+//! of the savestates so a replay can't rely on what was held before
+//! the movie started or before a rerecord.
+//!
+//! File format (all integers are little endian):
+//!
+//! * `MAGIC`
+//! * Format version (u32)
+//! * SHA-256 of the BIOS checksum and disc serial number (32 bytes)
+//! * Rerecord counter (u32)
+//! * Frame count (u32)
+//! * Multitaps (u8): bit 0 set if a multitap is connected to port 1,
+//!   bit 1 for port 2
+//! * Start: 0 for power-on, 1 for savestate followed by its length
+//!   (u32) and contents
+//! * For each frame the state of each controller: port 1 then port
+//!   2, slots A to D when a multitap is connected. `INPUT_LEN` bytes
+//!   per controller:
+//!   * Pressed buttons (u32), bit `n` is set if `Button` `n` is
+//!     pressed
+//!   * The 8 axes in `Axis` order (u8 each)
+//!   * Mouse motion during the frame, x then y (i32 each)
+//!   * Light gun aim: 0 if the gun points away from the screen, 1
+//!     otherwise followed by x and y (u16 each)
+
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::Path;
+
+use shaman::digest::Digest;
+use shaman::sha2::Sha256;
+
+use bios::Bios;
+use cdrom::disc::Disc;
+
+use super::PadMemCard;
+use super::gamepad::{GamePad, Button, ButtonState, Axis, AnalogInput};
+
+/// Emulator state at the start of the movie
+pub enum Start {
+    /// The console is powered on when the movie starts
+    PowerOn,
+    /// The frontend must load this savestate before playing the movie
+    Savestate(Vec<u8>),
+}
+
+pub struct Movie {
+    /// Identifies the BIOS and game used to record the movie
+    checksum: [u8; 32],
+    /// Number of times part of the movie was recorded again
+    rerecords: u32,
+    /// True if a multitap is connected to the corresponding port
+    multitaps: [bool; 2],
+    start: Start,
+    /// State of each controller for each frame
+    frames: Vec<Vec<Input>>,
+}
+
+impl Movie {
+    /// Create an empty movie for the given BIOS and disc. `multitaps`
+    /// says whether a multitap is connected to each port, the
+    /// controllers in all the slots are then recorded.
+    pub fn new(start: Start,
+               bios: &Bios,
+               disc: Option<&Disc>,
+               multitaps: [bool; 2]) -> Movie {
+        Movie {
+            checksum: checksum(bios, disc),
+            rerecords: 0,
+            multitaps: multitaps,
+            start: start,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Load a movie file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, Error> {
+        let mut file = try!(File::open(path));
+
+        Movie::read(&mut file)
+    }
+
+    /// Write the movie to `path`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+
+        self.write(&mut file)
+    }
+
+    pub fn start(&self) -> &Start {
+        &self.start
+    }
+
+    /// Return which ports had a multitap connected during the
+    /// recording. The frontend must connect the same multitaps
+    /// before playing the movie.
+    pub fn multitaps(&self) -> [bool; 2] {
+        self.multitaps
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.frames.len() as u32
+    }
+
+    pub fn rerecords(&self) -> u32 {
+        self.rerecords
+    }
+
+    /// Return true if the movie was recorded with the same BIOS and
+    /// disc
+    pub fn matches(&self, bios: &Bios, disc: Option<&Disc>) -> bool {
+        self.checksum == checksum(bios, disc)
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<Movie, Error> {
+        let mut magic = [0; 8];
+
+        try!(r.read_exact(&mut magic));
+
+        if magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        let version = try!(read_u32(r));
+
+        if version != VERSION {
+            return Err(Error::BadVersion(version));
+        }
+
+        let mut checksum = [0; 32];
+
+        try!(r.read_exact(&mut checksum));
+
+        let rerecords = try!(read_u32(r));
+        let frame_count = try!(read_u32(r));
+
+        let mut b = [0; 2];
+
+        try!(r.read_exact(&mut b));
+
+        let (multitaps, start) = (b[0], b[1]);
+
+        if multitaps > 3 {
+            return Err(Error::BadMultitaps(multitaps));
+        }
+
+        let multitaps = [multitaps & 1 != 0, multitaps & 2 != 0];
+
+        let start =
+            match start {
+                0 => Start::PowerOn,
+                1 => {
+                    let len = try!(read_u32(r)) as u64;
+
+                    // Don't trust `len` to allocate the buffer, a
+                    // corrupt file could make us run out of memory
+                    let mut state = Vec::new();
+
+                    try!(r.by_ref().take(len).read_to_end(&mut state));
+
+                    if state.len() as u64 != len {
+                        return Err(Error::Truncated);
+                    }
+
+                    Start::Savestate(state)
+                }
+                s => return Err(Error::BadStart(s)),
+            };
+
+        let controllers = controllers(multitaps).len();
+
+        // Same thing for the frame count, the vector grows as the
+        // frames are actually read
+        let mut frames = Vec::new();
+
+        for frame in 0..frame_count {
+            let mut inputs = Vec::with_capacity(controllers);
+
+            for _ in 0..controllers {
+                let mut b = [0; INPUT_LEN];
+
+                try!(r.read_exact(&mut b));
+
+                match Input::from_bytes(&b) {
+                    Some(input) => inputs.push(input),
+                    None => return Err(Error::BadInput(frame)),
+                }
+            }
+
+            frames.push(inputs);
+        }
+
+        Ok(Movie {
+            checksum: checksum,
+            rerecords: rerecords,
+            multitaps: multitaps,
+            start: start,
+            frames: frames,
+        })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let multitaps =
+            (self.multitaps[0] as u8) | ((self.multitaps[1] as u8) << 1);
+
+        try!(w.write_all(&MAGIC));
+        try!(write_u32(w, VERSION));
+        try!(w.write_all(&self.checksum));
+        try!(write_u32(w, self.rerecords));
+        try!(write_u32(w, self.frame_count()));
+        try!(w.write_all(&[multitaps]));
+
+        match self.start {
+            Start::PowerOn => try!(w.write_all(&[0])),
+            Start::Savestate(ref state) => {
+                try!(w.write_all(&[1]));
+                try!(write_u32(w, state.len() as u32));
+                try!(w.write_all(state));
+            }
+        }
+
+        for inputs in &self.frames {
+            for input in inputs {
+                try!(w.write_all(&input.to_bytes()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the controllers recorded in the movie
+    fn controllers(&self) -> Vec<(usize, usize)> {
+        controllers(self.multitaps)
+    }
+
+    /// Return the position of the controller in `slot` of `port` in
+    /// the frame inputs
+    fn controller_index(&self,
+                        port: usize,
+                        slot: usize) -> Result<usize, Error> {
+        match self.controllers().iter().position(|&c| c == (port, slot)) {
+            Some(i) => Ok(i),
+            None => Err(Error::NotRecorded(port, slot)),
+        }
+    }
+
+    /// Make sure that the multitaps connected to `pad_memcard` match
+    /// the ones used by the movie
+    fn check_multitaps(&self,
+                       pad_memcard: &mut PadMemCard) -> Result<(), Error> {
+        for port in 0..2 {
+            let connected = pad_memcard.multitap_mut(port).is_some();
+
+            if connected != self.multitaps[port] {
+                return Err(Error::MultitapMismatch(port));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Record the inputs in a movie
+pub struct Recorder {
+    movie: Movie,
+    /// Current state of each controller
+    inputs: Vec<Input>,
+}
+
+impl Recorder {
+    /// Record new frames at the end of `movie`. The controllers are
+    /// put in the state of the last recorded frame (or at rest for an
+    /// empty movie) so that the game sees the recorded inputs.
+    pub fn new(movie: Movie,
+               pad_memcard: &mut PadMemCard) -> Result<Recorder, Error> {
+        try!(movie.check_multitaps(pad_memcard));
+
+        let mut recorder = Recorder {
+            movie: movie,
+            inputs: Vec::new(),
+        };
+
+        recorder.resync(pad_memcard);
+
+        Ok(recorder)
+    }
+
+    /// Change the state of `button` on the gamepad in `port` (0 or
+    /// 1) and record it in the current frame. `slot` is the multitap
+    /// slot (0 to 3), it must be 0 if there's no multitap in `port`.
+    pub fn set_button_state(&mut self,
+                            pad_memcard: &mut PadMemCard,
+                            port: usize,
+                            slot: usize,
+                            button: Button,
+                            state: ButtonState) -> Result<(), Error> {
+        let index = try!(self.movie.controller_index(port, slot));
+
+        try!(self.movie.check_multitaps(pad_memcard));
+
+        self.inputs[index].set_button_state(button, state);
+
+        with_gamepad(pad_memcard, port, slot, |pad| {
+            pad.profile_mut().set_button_state(button, state)
+        });
+
+        Ok(())
+    }
+
+    /// Send an analog input event to the gamepad in `port` and
+    /// `slot` and record it in the current frame
+    pub fn set_analog_input(&mut self,
+                            pad_memcard: &mut PadMemCard,
+                            port: usize,
+                            slot: usize,
+                            input: AnalogInput) -> Result<(), Error> {
+        let index = try!(self.movie.controller_index(port, slot));
+
+        try!(self.movie.check_multitaps(pad_memcard));
+
+        self.inputs[index].set_analog_input(input);
+
+        with_gamepad(pad_memcard, port, slot, |pad| {
+            pad.profile_mut().set_analog_input(input)
+        });
+
+        Ok(())
+    }
+
+    /// Close the current frame, must be called once per emulated
+    /// frame
+    pub fn end_frame(&mut self) {
+        self.movie.frames.push(self.inputs.clone());
+
+        // The mouse motion is relative to the previous frame
+        for input in &mut self.inputs {
+            input.motion = (0, 0);
+        }
+    }
+
+    /// Return the number of frames recorded so far
+    pub fn frame(&self) -> u32 {
+        self.movie.frame_count()
+    }
+
+    /// Discard the frames recorded after `frame` and resume recording
+    /// from there. Used when the frontend loads a savestate taken at
+    /// `frame` during the recording. The controllers are put back in
+    /// the state they had at that point.
+    pub fn rewind(&mut self,
+                  pad_memcard: &mut PadMemCard,
+                  frame: u32) -> Result<(), Error> {
+        if frame > self.movie.frame_count() {
+            return Err(Error::BadRewind(frame));
+        }
+
+        try!(self.movie.check_multitaps(pad_memcard));
+
+        self.movie.frames.truncate(frame as usize);
+        self.movie.rerecords = self.movie.rerecords.wrapping_add(1);
+
+        self.resync(pad_memcard);
+
+        Ok(())
+    }
+
+    /// Stop the recording and return the movie. The current frame is
+    /// discarded if `end_frame` hasn't been called.
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+
+    /// Reload the controller state from the last recorded frame and
+    /// apply it to the gamepads. The multitaps must have been checked
+    /// by the caller.
+    fn resync(&mut self, pad_memcard: &mut PadMemCard) {
+        let controllers = self.movie.controllers();
+
+        self.inputs =
+            match self.movie.frames.last() {
+                Some(inputs) => inputs.clone(),
+                None => vec![Input::idle(); controllers.len()],
+            };
+
+        for (input, &(port, slot)) in self.inputs.iter_mut()
+                                                 .zip(&controllers) {
+            // The motion of the last frame has already been sent
+            input.motion = (0, 0);
+
+            with_gamepad(pad_memcard, port, slot, |pad| input.apply(pad));
+        }
+    }
+}
+
+/// Replay the inputs of a movie
+pub struct Player {
+    movie: Movie,
+    /// Next frame to be played
+    frame: u32,
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Player {
+        Player {
+            movie: movie,
+            frame: 0,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Return the number of frames played so far
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Apply the inputs for the next frame, must be called once per
+    /// emulated frame before running it. Returns false once the end
+    /// of the movie has been reached.
+    pub fn play_frame(&mut self,
+                      pad_memcard: &mut PadMemCard) -> Result<bool, Error> {
+        try!(self.movie.check_multitaps(pad_memcard));
+
+        match self.movie.frames.get(self.frame as usize) {
+            Some(inputs) => {
+                let controllers = self.movie.controllers();
+
+                for (input, &(port, slot)) in inputs.iter()
+                                                    .zip(&controllers) {
+                    with_gamepad(pad_memcard, port, slot,
+                                 |pad| input.apply(pad));
+                }
+
+                self.frame += 1;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Full state of a controller during one frame
+#[derive(Clone, Copy)]
+struct Input {
+    /// Pressed buttons, bit `n` is set if `Button` `n` is pressed
+    buttons: u32,
+    /// Position of each `Axis`
+    axes: [u8; 8],
+    /// Mouse motion during the frame
+    motion: (i32, i32),
+    /// Light gun aim
+    aim: Option<(u16, u16)>,
+}
+
+impl Input {
+    /// Nothing pressed, sticks and twist centered
+    fn idle() -> Input {
+        Input {
+            buttons: 0,
+            axes: [0x80, 0x80, 0x80, 0x80, 0x80, 0x00, 0x00, 0x00],
+            motion: (0, 0),
+            aim: None,
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        let mask = 1 << (button as u32);
+
+        match state {
+            ButtonState::Pressed => self.buttons |= mask,
+            ButtonState::Released => self.buttons &= !mask,
+        }
+    }
+
+    fn set_analog_input(&mut self, input: AnalogInput) {
+        match input {
+            AnalogInput::Axis(axis, v) => self.axes[axis as usize] = v,
+            AnalogInput::Motion(x, y) => {
+                self.motion.0 = self.motion.0.saturating_add(x);
+                self.motion.1 = self.motion.1.saturating_add(y);
+            }
+            AnalogInput::Aim(aim) => self.aim = aim,
+        }
+    }
+
+    /// Put the profile of `pad` in this state
+    fn apply(&self, pad: &mut GamePad) {
+        let profile = pad.profile_mut();
+
+        for &button in BUTTONS.iter() {
+            let state =
+                if self.buttons & (1 << (button as u32)) != 0 {
+                    ButtonState::Pressed
+                } else {
+                    ButtonState::Released
+                };
+
+            profile.set_button_state(button, state);
+        }
+
+        for &axis in AXES.iter() {
+            let v = self.axes[axis as usize];
+
+            profile.set_analog_input(AnalogInput::Axis(axis, v));
+        }
+
+        profile.set_analog_input(AnalogInput::Aim(self.aim));
+
+        if self.motion != (0, 0) {
+            let (x, y) = self.motion;
+
+            profile.set_analog_input(AnalogInput::Motion(x, y));
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; INPUT_LEN] {
+        let mut b = [0; INPUT_LEN];
+
+        put_u32(&mut b[0..4], self.buttons);
+        b[4..12].copy_from_slice(&self.axes);
+        put_u32(&mut b[12..16], self.motion.0 as u32);
+        put_u32(&mut b[16..20], self.motion.1 as u32);
+
+        if let Some((x, y)) = self.aim {
+            b[20] = 1;
+            b[21] = x as u8;
+            b[22] = (x >> 8) as u8;
+            b[23] = y as u8;
+            b[24] = (y >> 8) as u8;
+        }
+
+        b
+    }
+
+    fn from_bytes(b: &[u8; INPUT_LEN]) -> Option<Input> {
+        let buttons = get_u32(&b[0..4]);
+
+        if buttons >> BUTTONS.len() != 0 {
+            return None;
+        }
+
+        let mut axes = [0; 8];
+
+        axes.copy_from_slice(&b[4..12]);
+
+        let motion = (get_u32(&b[12..16]) as i32,
+                      get_u32(&b[16..20]) as i32);
+
+        let aim =
+            match b[20] {
+                0 => None,
+                1 => Some((b[21] as u16 | ((b[22] as u16) << 8),
+                           b[23] as u16 | ((b[24] as u16) << 8))),
+                _ => return None,
+            };
+
+        Some(Input {
+            buttons: buttons,
+            axes: axes,
+            motion: motion,
+            aim: aim,
+        })
+    }
+}
+
+/// Return the (port, slot) pairs of the controllers recorded with
+/// this multitap configuration, in file order
+fn controllers(multitaps: [bool; 2]) -> Vec<(usize, usize)> {
+    let mut controllers = Vec::new();
+
+    for port in 0..2 {
+        let slots = if multitaps[port] { 4 } else { 1 };
+
+        for slot in 0..slots {
+            controllers.push((port, slot));
+        }
+    }
+
+    controllers
+}
+
+/// Call `f` with the gamepad in `slot` of the multitap connected to
+/// `port`. If there's no multitap the gamepad connected directly to
+/// `port` is used. The controller must be part of the movie and the
+/// multitaps must have been checked by the caller.
+fn with_gamepad<F>(pad_memcard: &mut PadMemCard,
+                   port: usize,
+                   slot: usize,
+                   f: F)
+    where F: FnOnce(&mut GamePad) {
+
+    if let Some(multitap) = pad_memcard.multitap_mut(port) {
+        f(&mut *multitap.gamepads_mut()[slot]);
+        return;
+    }
+
+    f(&mut *pad_memcard.gamepads_mut()[port]);
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error while reading the movie
+    IoError(io::Error),
+    /// The file doesn't start with `MAGIC`
+    BadMagic,
+    /// Unsupported format version
+    BadVersion(u32),
+    /// Invalid multitap configuration
+    BadMultitaps(u8),
+    /// Invalid start type
+    BadStart(u8),
+    /// The file ends in the middle of the savestate
+    Truncated,
+    /// Invalid controller state in the given frame
+    BadInput(u32),
+    /// The controller in (port, slot) isn't part of the movie
+    NotRecorded(usize, usize),
+    /// The multitap connected to this port (or its absence) doesn't
+    /// match the movie
+    MultitapMismatch(usize),
+    /// Attempted to rewind past the last recorded frame
+    BadRewind(u32),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+/// Compute the checksum identifying the BIOS and disc
+fn checksum(bios: &Bios, disc: Option<&Disc>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    hasher.input(&bios.metadata().sha256);
+
+    if let Some(disc) = disc {
+        hasher.input(disc.serial_number().to_string().as_bytes());
+    }
+
+    let mut sha256 = [0; 32];
+
+    hasher.result(&mut sha256);
+
+    sha256
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0; 4];
+
+    try!(r.read_exact(&mut b));
+
+    Ok(get_u32(&b))
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    let mut b = [0; 4];
+
+    put_u32(&mut b, v);
+
+    w.write_all(&b)
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+    b[0] as u32 |
+    ((b[1] as u32) << 8) |
+    ((b[2] as u32) << 16) |
+    ((b[3] as u32) << 24)
+}
+
+fn put_u32(b: &mut [u8], v: u32) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+    b[2] = (v >> 16) as u8;
+    b[3] = (v >> 24) as u8;
+}
+
+/// Movie file magic
+const MAGIC: [u8; 8] = *b"PSXINPUT";
+
+/// Movie format version
+const VERSION: u32 = 1;
+
+/// Length of the state of one controller in a frame
+const INPUT_LEN: usize = 25;
+
+/// All the buttons, in bit order
+const BUTTONS: [Button; 22] = [
+    Button::Select, Button::L3, Button::R3, Button::Start,
+    Button::DUp, Button::DRight, Button::DDown, Button::DLeft,
+    Button::L2, Button::R2, Button::L1, Button::R1,
+    Button::Triangle, Button::Circle, Button::Cross, Button::Square,
+    Button::Analog, Button::MouseLeft, Button::MouseRight,
+    Button::GunTrigger, Button::GunA, Button::GunB,
+    ];
+
+/// All the axes, in the order of `Input::axes`
+const AXES: [Axis; 8] = [
+    Axis::LeftX, Axis::LeftY, Axis::RightX, Axis::RightY,
+    Axis::Twist, Axis::NegconI, Axis::NegconII, Axis::NegconL,
+    ];
+
+#[test]
+fn record_replay() {
+    use super::gamepad::DigitalProfile;
+
+    let bios = Bios::dummy();
+
+    let new_pad_memcard = || {
+        let mut pad_memcard = PadMemCard::new();
+
+        pad_memcard.gamepads_mut()[1]
+            .set_profile(Box::new(DigitalProfile::new()));
+
+        pad_memcard
+    };
+
+    // Return the button state word of the pad in port 2
+    let buttons = |pad_memcard: &mut PadMemCard| {
+        let pad = &mut pad_memcard.gamepads_mut()[1];
+
+        pad.select();
+
+        let r: Vec<u8> = [0x01, 0x42, 0x00, 0x00, 0x00]
+            .iter()
+            .map(|&c| pad.send_command(c).0)
+            .collect();
+
+        r[3] as u16 | ((r[4] as u16) << 8)
+    };
+
+    let mut pad_memcard = new_pad_memcard();
+
+    let movie = Movie::new(Start::PowerOn, &bios, None, [false, false]);
+    let mut recorder = Recorder::new(movie, &mut pad_memcard).unwrap();
+
+    recorder.set_button_state(&mut pad_memcard, 1, 0,
+                              Button::Cross, ButtonState::Pressed).unwrap();
+    recorder.end_frame();
+    recorder.end_frame();
+
+    // Rerecord the second frame
+    recorder.rewind(&mut pad_memcard, 1).unwrap();
+    recorder.set_button_state(&mut pad_memcard, 1, 0,
+                              Button::Start, ButtonState::Pressed).unwrap();
+    recorder.end_frame();
+
+    // Frontend mistakes are reported as errors
+    assert!(recorder.set_button_state(&mut pad_memcard, 1, 1,
+                                      Button::Start, ButtonState::Pressed)
+            .is_err());
+    assert!(recorder.rewind(&mut pad_memcard, 3).is_err());
+
+    let mut file = Vec::new();
+
+    recorder.finish().write(&mut file).unwrap();
+
+    let movie = Movie::read(&mut &file[..]).unwrap();
+
+    assert!(movie.frame_count() == 2);
+    assert!(movie.rerecords() == 1);
+    assert!(movie.matches(&bios, None));
+
+    let mut pad_memcard = new_pad_memcard();
+    let mut player = Player::new(movie);
+
+    assert!(player.play_frame(&mut pad_memcard).unwrap());
+    assert!(buttons(&mut pad_memcard) == 0xbfff);
+    assert!(player.play_frame(&mut pad_memcard).unwrap());
+    assert!(buttons(&mut pad_memcard) == 0xbff7);
+    assert!(!player.play_frame(&mut pad_memcard).unwrap());
+}
+
+#[test]
+fn savestate_start() {
+    use super::gamepad::{NegconProfile, Profile};
+
+    let bios = Bios::dummy();
+    let savestate = vec![0x5a; 1000];
+
+    // NeGcon in slot C of a multitap in port 2, with a button and
+    // the twist held by the frontend before the movie starts
+    let new_pad_memcard = || {
+        let mut pad_memcard = PadMemCard::new();
+
+        pad_memcard.connect_multitap(1);
+
+        let mut negcon = NegconProfile::new();
+
+        negcon.set_button_state(Button::Circle, ButtonState::Pressed);
+        negcon.set_analog_input(AnalogInput::Axis(Axis::Twist, 0x10));
+
+        pad_memcard.multitap_mut(1).unwrap().gamepads_mut()[2]
+            .set_profile(Box::new(negcon));
+
+        pad_memcard
+    };
+
+    // Return the button word and axes reported by the NeGcon
+    let read = |pad_memcard: &mut PadMemCard| {
+        let multitap = pad_memcard.multitap_mut(1).unwrap();
+        let pad = &mut multitap.gamepads_mut()[2];
+
+        pad.select();
+
+        [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]
+            .iter()
+            .map(|&c| pad.send_command(c).0)
+            .skip(3)
+            .collect::<Vec<u8>>()
+    };
+
+    let mut pad_memcard = new_pad_memcard();
+
+    let movie = Movie::new(Start::Savestate(savestate.clone()),
+                           &bios, None, [false, true]);
+    let mut recorder = Recorder::new(movie, &mut pad_memcard).unwrap();
+
+    // The recorder releases everything the movie doesn't know about
+    assert!(read(&mut pad_memcard) == [0xff, 0xff, 0x80, 0, 0, 0]);
+
+    recorder.set_button_state(&mut pad_memcard, 1, 2,
+                              Button::Triangle, ButtonState::Pressed).unwrap();
+    recorder.set_analog_input(&mut pad_memcard, 1, 2,
+                              AnalogInput::Axis(Axis::NegconI, 0x40)).unwrap();
+    recorder.end_frame();
+
+    recorder.set_analog_input(&mut pad_memcard, 1, 2,
+                              AnalogInput::Axis(Axis::Twist, 0xc0)).unwrap();
+    recorder.end_frame();
+
+    // Rerecording the second frame puts the NeGcon back in the state
+    // of the first one
+    recorder.rewind(&mut pad_memcard, 1).unwrap();
+
+    assert!(read(&mut pad_memcard) == [0xff, 0xef, 0x80, 0x40, 0, 0]);
+
+    recorder.set_button_state(&mut pad_memcard, 1, 2,
+                              Button::Triangle, ButtonState::Released).unwrap();
+    recorder.set_analog_input(&mut pad_memcard, 1, 2,
+                              AnalogInput::Axis(Axis::Twist, 0x20)).unwrap();
+    recorder.end_frame();
+
+    let mut file = Vec::new();
+
+    recorder.finish().write(&mut file).unwrap();
+
+    let movie = Movie::read(&mut &file[..]).unwrap();
+
+    assert!(movie.frame_count() == 2);
+    assert!(movie.multitaps() == [false, true]);
+
+    match *movie.start() {
+        Start::Savestate(ref s) => assert!(*s == savestate),
+        Start::PowerOn => panic!("Bad movie start"),
+    }
+
+    let mut player = Player::new(movie);
+
+    // The movie needs a multitap in port 2
+    assert!(player.play_frame(&mut PadMemCard::new()).is_err());
+
+    let mut pad_memcard = new_pad_memcard();
+
+    assert!(player.play_frame(&mut pad_memcard).unwrap());
+    assert!(read(&mut pad_memcard) == [0xff, 0xef, 0x80, 0x40, 0, 0]);
+    assert!(player.play_frame(&mut pad_memcard).unwrap());
+    assert!(read(&mut pad_memcard) == [0xff, 0xff, 0x20, 0x40, 0, 0]);
+    assert!(!player.play_frame(&mut pad_memcard).unwrap());
+
+    // Truncated or corrupt files must return an error instead of
+    // trying to allocate huge buffers
+    assert!(Movie::read(&mut &file[..file.len() - 1]).is_err());
+
+    let mut corrupt = file.clone();
+
+    // Frame count
+    corrupt[48..52].copy_from_slice(&[0xff; 4]);
+    assert!(Movie::read(&mut &corrupt[..]).is_err());
+
+    // Savestate length
+    corrupt[54..58].copy_from_slice(&[0xff; 4]);
+    assert!(Movie::read(&mut &corrupt[..]).is_err());
+}
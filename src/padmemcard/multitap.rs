@@ -0,0 +1,275 @@
+//! Multitap emulation (SCPH-1070).
+//!
+//! The multitap connects up to four gamepads and four memory cards
+//! to a single controller port. Individual devices are addressed by
+//! the first byte of the transaction: 0x01 to 0x04 for the gamepads
+//! in slots A to D and 0x81 to 0x84 for the memory cards.
+//!
+//! If the third byte of a gamepad transaction is 0x01 the multitap
+//! switches to "multitap mode" for the following transactions: a
+//! read (0x42) or config (0x43) command addressed to 0x01 then
+//! returns the replies of the four gamepads at once, 8 bytes per
+//! slot. Each block of 8 command bytes is forwarded to the
+//! corresponding gamepad.
+
+use super::gamepad::GamePad;
+use super::memcard::MemoryCard;
+
+use gpu::DisplayTiming;
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Multitap {
+    /// Gamepads in slots A to D
+    pads: [GamePad; 4],
+    /// Memory cards in slots A to D
+    memcards: [MemoryCard; 4],
+    /// True if the multitap is in multitap mode
+    multitap_mode: bool,
+    /// Counter keeping track of the current position in the reply
+    /// sequence
+    seq: u8,
+    /// False if the multitap is done processing the current command
+    active: bool,
+    /// Device targeted by the current transaction
+    route: Route,
+    /// In multitap mode: true if the gamepad of the current slot is
+    /// still replying
+    slot_active: bool,
+}
+
+impl Multitap {
+    pub fn new() -> Multitap {
+        Multitap {
+            pads: [GamePad::disconnected(),
+                   GamePad::disconnected(),
+                   GamePad::disconnected(),
+                   GamePad::disconnected()],
+            memcards: [MemoryCard::new(),
+                       MemoryCard::new(),
+                       MemoryCard::new(),
+                       MemoryCard::new()],
+            multitap_mode: false,
+            seq: 0,
+            active: false,
+            route: Route::None,
+            slot_active: false,
+        }
+    }
+
+    /// Return a mutable reference to the gamepads in slots A to D
+    pub fn gamepads_mut(&mut self) -> [&mut GamePad; 4] {
+        let (ab, cd) = self.pads.split_at_mut(2);
+        let (a, b) = ab.split_at_mut(1);
+        let (c, d) = cd.split_at_mut(1);
+
+        [ &mut a[0], &mut b[0], &mut c[0], &mut d[0] ]
+    }
+
+    /// Return a mutable reference to the memory cards in slots A to D
+    pub fn memcards_mut(&mut self) -> [&mut MemoryCard; 4] {
+        let (ab, cd) = self.memcards.split_at_mut(2);
+        let (a, b) = ab.split_at_mut(1);
+        let (c, d) = cd.split_at_mut(1);
+
+        [ &mut a[0], &mut b[0], &mut c[0], &mut d[0] ]
+    }
+
+    /// Forward the display timings to the gamepads
+    pub fn set_display_timing(&mut self, timing: &DisplayTiming) {
+        for pad in self.pads.iter_mut() {
+            pad.set_display_timing(timing);
+        }
+    }
+
+    /// Called when the "select" line goes down.
+    pub fn select(&mut self) {
+        self.active = true;
+        self.seq = 0;
+        self.route = Route::None;
+    }
+
+    /// Handle a command byte. Returns the response byte and whether
+    /// the multitap issues a DSR pulse to request the next byte.
+    pub fn send_command(&mut self, cmd: u8) -> (u8, bool) {
+        if !self.active {
+            return (0xff, false);
+        }
+
+        let (resp, dsr) = self.handle_command(cmd);
+
+        self.active = dsr;
+        self.seq = self.seq.wrapping_add(1);
+
+        (resp, dsr)
+    }
+
+    fn handle_command(&mut self, cmd: u8) -> (u8, bool) {
+        if self.seq == 0 {
+            self.route =
+                match cmd {
+                    0x01 if self.multitap_mode => Route::AllPads,
+                    0x01...0x04 => Route::Pad(cmd - 0x01),
+                    0x81...0x84 => Route::MemoryCard(cmd - 0x81),
+                    _ => Route::None,
+                };
+        }
+
+        // The 3rd byte of a gamepad transaction addressed to 0x01
+        // sets the mode for the next transactions
+        if self.seq == 2 {
+            match self.route {
+                Route::AllPads | Route::Pad(0) =>
+                    self.multitap_mode = cmd == 0x01,
+                _ => (),
+            }
+        }
+
+        match self.route {
+            Route::None => (0xff, false),
+            Route::Pad(slot) => {
+                let pad = &mut self.pads[slot as usize];
+
+                if self.seq == 0 {
+                    pad.select();
+                }
+
+                // The devices behind the multitap always see 0x01 as
+                // the address byte
+                let cmd = if self.seq == 0 { 0x01 } else { cmd };
+
+                let (r, dsr) = pad.send_command(cmd);
+
+                // The multitap itself acknowledges the first bytes of
+                // slot A transactions, this way the mode byte is
+                // received even if slot A is empty.
+                let dsr = dsr || (slot == 0 && self.seq < 2);
+
+                (r, dsr)
+            }
+            Route::MemoryCard(slot) => {
+                let card = &mut self.memcards[slot as usize];
+
+                if self.seq == 0 {
+                    card.select();
+                }
+
+                let cmd = if self.seq == 0 { 0x81 } else { cmd };
+
+                card.send_command(cmd)
+            }
+            Route::AllPads => self.read_all_pads(cmd),
+        }
+    }
+
+    /// Handle a transaction in multitap mode
+    fn read_all_pads(&mut self, cmd: u8) -> (u8, bool) {
+        match self.seq {
+            0 => (0xff, true),
+            1 =>
+                match cmd {
+                    // Multitap ID
+                    0x42 | 0x43 => (0x80, true),
+                    _ => (0xff, false),
+                },
+            2 => (0x5a, true),
+            n => {
+                let n = n - 3;
+
+                if n >= 32 {
+                    return (0xff, false);
+                }
+
+                let pad = &mut self.pads[(n / 8) as usize];
+
+                if n % 8 == 0 {
+                    // New slot, start a transaction with the
+                    // gamepad
+                    pad.select();
+
+                    self.slot_active = pad.send_command(0x01).1;
+                }
+
+                let r =
+                    if self.slot_active {
+                        let (r, dsr) = pad.send_command(cmd);
+
+                        self.slot_active = dsr;
+
+                        r
+                    } else {
+                        0xff
+                    };
+
+                // We don't assert DSR for the last byte
+                (r, n < 31)
+            }
+        }
+    }
+}
+
+/// Device targeted by the current transaction
+#[derive(Clone, Copy, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+enum Route {
+    /// No device
+    None,
+    /// Gamepad in the given slot
+    Pad(u8),
+    /// Memory card in the given slot
+    MemoryCard(u8),
+    /// All gamepads at once (multitap mode)
+    AllPads,
+}
+
+/// Select `multitap` and send the bytes of `cmd` as a single
+/// transaction, returning the replies
+#[cfg(test)]
+fn transfer(multitap: &mut Multitap, cmd: &[u8]) -> Vec<(u8, bool)> {
+    multitap.select();
+
+    cmd.iter().map(|&c| multitap.send_command(c)).collect()
+}
+
+#[test]
+fn multitap_read() {
+    use super::gamepad::{DigitalProfile, Button, ButtonState, Profile};
+
+    let mut multitap = Multitap::new();
+
+    {
+        let pads = multitap.gamepads_mut();
+
+        let mut profile = DigitalProfile::new();
+        profile.set_button_state(Button::Cross, ButtonState::Pressed);
+
+        pads[2].set_profile(Box::new(profile));
+    }
+
+    // Direct access to slot C
+    let r = transfer(&mut multitap, &[0x03, 0x42, 0x00, 0x00, 0x00]);
+    assert!(r[1] == (0x41, true));
+    assert!(r[4] == (0xbf, false));
+
+    // Enable the multitap mode
+    let r = transfer(&mut multitap, &[0x01, 0x42, 0x01, 0x00, 0x00]);
+    assert!(r[1] == (0xff, true));
+    assert!(r[2] == (0xff, false));
+
+    let mut cmd = vec![0x01, 0x42, 0x01];
+
+    for _ in 0..4 {
+        cmd.extend_from_slice(&[0x42, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    let r = transfer(&mut multitap, &cmd);
+
+    assert!(r[1] == (0x80, true));
+    assert!(r[2] == (0x5a, true));
+    // Slot A is empty
+    assert!(r[3] == (0xff, true));
+    // Slot C
+    assert!(r[3 + 16] == (0x41, true));
+    assert!(r[3 + 17] == (0x5a, true));
+    assert!(r[3 + 19] == (0xbf, true));
+    assert!(r[3 + 20] == (0xff, true));
+    assert!(r[34] == (0xff, false));
+}
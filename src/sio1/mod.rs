@@ -0,0 +1,527 @@
+//! Emulation of the SIO1 serial port (the "Serial I/O" connector on
+//! the back of older PlayStation models, used by the link cable)
+//!
+//! The device on the other end of the cable is abstracted behind the
+//! `SerialLink` trait. The `socket` module contains an implementation
+//! bridging two emulator instances over a local socket.
+
+use std::collections::VecDeque;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use memory::Addressable;
+use interrupt::Interrupt;
+use timekeeper::{Peripheral, Cycles};
+use shared::SharedState;
+
+pub mod socket;
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Sio1 {
+    /// Device connected to the serial port
+    link: Link,
+    /// Mode register: baudrate factor, character length, parity and
+    /// stop bits
+    mode: u16,
+    /// Control register, without the write-only acknowledge and
+    /// reset bits
+    control: u16,
+    /// Unknown read/write register
+    misc: u16,
+    /// Baudrate timer reload value
+    baud: u16,
+    /// Byte written to the TX register and not yet being sent
+    tx_pending: Option<u8>,
+    /// Byte currently being sent and the number of CPU cycles until
+    /// the end of the transfer
+    tx_shift: Option<(u8, Cycles)>,
+    /// Received bytes
+    rx_fifo: VecDeque<u8>,
+    /// Number of CPU cycles since the last byte was received, used to
+    /// limit the reception to the configured baudrate
+    rx_delay: Cycles,
+    /// True if bytes were received during the last sync, the remote
+    /// end is probably sending more
+    rx_active: bool,
+    /// Set when a byte was received while the RX FIFO was full
+    rx_overrun: bool,
+    /// State of the DSR input
+    dsr: bool,
+    /// State of the CTS input
+    cts: bool,
+    /// Interrupt flag
+    interrupt: bool,
+}
+
+impl Sio1 {
+    pub fn new() -> Sio1 {
+        Sio1 {
+            link: Link(Box::new(Disconnected)),
+            mode: 0,
+            control: 0,
+            misc: 0,
+            baud: 0,
+            tx_pending: None,
+            tx_shift: None,
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_LEN),
+            rx_delay: 0,
+            rx_active: false,
+            rx_overrun: false,
+            dsr: false,
+            cts: false,
+            interrupt: false,
+        }
+    }
+
+    /// Connect `link` to the serial port. The link is not saved in
+    /// savestates, it has to be connected again after a load.
+    pub fn set_link(&mut self, link: Box<SerialLink>) {
+        self.link = Link(link);
+
+        let (dtr, rts) = (self.dtr(), self.rts());
+
+        self.link.0.set_outputs(dtr, rts);
+    }
+
+    /// Disconnect the current link
+    pub fn disconnect(&mut self) {
+        self.link = Link(Box::new(Disconnected));
+    }
+
+    pub fn load<T: Addressable>(&mut self,
+                                shared: &mut SharedState,
+                                offset: u32) -> u32 {
+        self.sync(shared);
+
+        let v =
+            match offset {
+                0 => self.rx_fifo.pop_front().unwrap_or(0) as u32,
+                4 => self.stat(),
+                8 => self.mode as u32,
+                10 => self.control as u32,
+                12 => self.misc as u32,
+                14 => self.baud as u32,
+                _ => panic!("Unhandled SIO1 read {:?} 0x{:x}",
+                            T::size(), offset),
+            };
+
+        self.update_interrupt(shared);
+        self.schedule_sync(shared);
+
+        v
+    }
+
+    pub fn store<T: Addressable>(&mut self,
+                                 shared: &mut SharedState,
+                                 offset: u32,
+                                 val: u32) {
+        self.sync(shared);
+
+        match offset {
+            0 => {
+                if self.tx_pending.is_some() {
+                    warn!("SIO1 TX overflow");
+                }
+
+                self.tx_pending = Some(val as u8);
+            }
+            8 => self.mode = val as u16,
+            10 => self.set_control(val as u16),
+            12 => self.misc = val as u16,
+            14 => self.baud = val as u16,
+            _ => panic!("Unhandled write to SIO1 register {} {:04x}",
+                        offset, val as u16),
+        }
+
+        self.start_tx();
+        self.update_interrupt(shared);
+        self.schedule_sync(shared);
+    }
+
+    pub fn sync(&mut self, shared: &mut SharedState) {
+        let delta = shared.tk().sync(Peripheral::Sio1);
+
+        if let Some((b, remaining)) = self.tx_shift {
+            if delta >= remaining {
+                self.link.0.send(b);
+                self.tx_shift = None;
+            } else {
+                self.tx_shift = Some((b, remaining - delta));
+            }
+        }
+
+        let (dsr, cts) = self.link.0.inputs();
+
+        self.dsr = dsr;
+        self.cts = cts;
+
+        self.start_tx();
+
+        self.rx_active = false;
+
+        if self.rx_en() {
+            let period = self.byte_cycles();
+
+            // Don't let the delay grow indefinitely when there's
+            // nothing to receive, the remote end can't send faster
+            // than the baudrate anyway.
+            self.rx_delay =
+                ::std::cmp::min(self.rx_delay + delta,
+                                period * RX_FIFO_LEN as Cycles);
+
+            while self.rx_delay >= period {
+                let b =
+                    match self.link.0.receive() {
+                        Some(b) => b,
+                        None => break,
+                    };
+
+                self.rx_delay -= period;
+                self.rx_active = true;
+
+                if self.rx_fifo.len() < RX_FIFO_LEN {
+                    self.rx_fifo.push_back(b);
+                } else {
+                    // The last byte in the FIFO is overwritten
+                    *self.rx_fifo.back_mut().unwrap() = b;
+                    self.rx_overrun = true;
+                }
+            }
+        }
+
+        self.update_interrupt(shared);
+        self.schedule_sync(shared);
+    }
+
+    /// Schedule the next sync: at the end of the current transfer or
+    /// one byte period later if we're receiving data. Otherwise, if
+    /// we're waiting for data or CTS, the link is polled every
+    /// `LINK_POLL_CYCLES`: polling a socket backend is a syscall so we
+    /// can't afford to do it every byte period. Reading the registers
+    /// also polls the link.
+    fn schedule_sync(&self, shared: &mut SharedState) {
+        let tk = shared.tk();
+
+        let waiting = self.rx_en() || self.tx_pending.is_some();
+
+        let rx_delta =
+            if self.rx_en() && self.rx_active {
+                Some(self.byte_cycles())
+            } else if waiting {
+                Some(::std::cmp::max(LINK_POLL_CYCLES, self.byte_cycles()))
+            } else {
+                None
+            };
+
+        let tx_delta = self.tx_shift.map(|(_, remaining)| remaining);
+
+        let delta =
+            match (tx_delta, rx_delta) {
+                (Some(t), Some(r)) => Some(::std::cmp::min(t, r)),
+                (t, r) => t.or(r),
+            };
+
+        match delta {
+            Some(d) => tk.set_next_sync_delta(Peripheral::Sio1, d),
+            None => tk.no_sync_needed(Peripheral::Sio1),
+        }
+    }
+
+    /// Start sending the pending TX byte if the port is ready
+    fn start_tx(&mut self) {
+        // The SIO1 uses hardware flow control: the transfer only
+        // starts once the remote end asserts CTS
+        if self.tx_shift.is_some() || !self.tx_en() || !self.cts {
+            return;
+        }
+
+        if let Some(b) = self.tx_pending.take() {
+            self.tx_shift = Some((b, self.byte_cycles()));
+        }
+    }
+
+    /// Assert the interrupt if one of the enabled conditions is met
+    /// and it's not already active
+    fn update_interrupt(&mut self, shared: &mut SharedState) {
+        if self.interrupt {
+            return;
+        }
+
+        let tx_it = self.control & (1 << 10) != 0;
+        let rx_it = self.control & (1 << 11) != 0;
+        let dsr_it = self.control & (1 << 12) != 0;
+
+        let rx_threshold = 1 << ((self.control >> 8) & 3);
+
+        let it =
+            (tx_it && self.tx_pending.is_none()) ||
+            (rx_it && self.rx_fifo.len() >= rx_threshold) ||
+            (dsr_it && self.dsr);
+
+        if it {
+            self.interrupt = true;
+            shared.irq_state_mut().assert(Interrupt::Sio);
+        }
+    }
+
+    fn stat(&self) -> u32 {
+        let mut stat = 0;
+
+        stat |= self.tx_pending.is_none() as u32;
+        stat |= (!self.rx_fifo.is_empty() as u32) << 1;
+        stat |= ((self.tx_pending.is_none() &&
+                  self.tx_shift.is_none()) as u32) << 2;
+        stat |= (self.rx_overrun as u32) << 4;
+        // RX input level, the line idles high
+        stat |= 1 << 6;
+        stat |= (self.dsr as u32) << 7;
+        stat |= (self.cts as u32) << 8;
+        stat |= (self.interrupt as u32) << 9;
+
+        stat
+    }
+
+    fn set_control(&mut self, ctrl: u16) {
+        if ctrl & 0x40 != 0 {
+            // Soft reset
+            self.mode = 0;
+            self.control = 0;
+            self.baud = 0;
+            self.tx_pending = None;
+            self.tx_shift = None;
+            self.rx_fifo.clear();
+            self.rx_delay = 0;
+            self.rx_active = false;
+            self.rx_overrun = false;
+            self.interrupt = false;
+        } else {
+            if ctrl & 0x10 != 0 {
+                // Interrupt acknowledge
+                self.interrupt = false;
+                self.rx_overrun = false;
+            }
+
+            self.control = ctrl & !0x50;
+        }
+
+        let (dtr, rts) = (self.dtr(), self.rts());
+
+        self.link.0.set_outputs(dtr, rts);
+    }
+
+    fn tx_en(&self) -> bool {
+        self.control & 1 != 0
+    }
+
+    fn dtr(&self) -> bool {
+        self.control & (1 << 1) != 0
+    }
+
+    fn rx_en(&self) -> bool {
+        self.control & (1 << 2) != 0
+    }
+
+    fn rts(&self) -> bool {
+        self.control & (1 << 5) != 0
+    }
+
+    /// Return the duration of a character transfer in CPU cycles
+    fn byte_cycles(&self) -> Cycles {
+        let factor =
+            match self.mode & 3 {
+                2 => 16,
+                3 => 64,
+                _ => 1,
+            };
+
+        let data_bits = 5 + ((self.mode >> 2) & 3) as Cycles;
+        let parity_bits = ((self.mode >> 4) & 1) as Cycles;
+        let stop_bits =
+            match (self.mode >> 6) & 3 {
+                0 | 1 => 1,
+                // 1.5 stop bits, rounded up
+                _ => 2,
+            };
+
+        // Start bit + data + parity + stop
+        let bits = 1 + data_bits + parity_bits + stop_bits;
+
+        let bit_cycles = ::std::cmp::max(self.baud as Cycles, 1) * factor;
+
+        bits * bit_cycles
+    }
+}
+
+/// Interface to the device connected to the other end of the serial
+/// cable
+pub trait SerialLink {
+    /// Send `byte` to the remote device
+    fn send(&mut self, byte: u8);
+
+    /// Return the next byte sent by the remote device, if any
+    fn receive(&mut self) -> Option<u8>;
+
+    /// Called when the state of the DTR and RTS outputs changes
+    fn set_outputs(&mut self, dtr: bool, rts: bool);
+
+    /// Return the state of the DSR and CTS inputs
+    fn inputs(&mut self) -> (bool, bool);
+}
+
+/// A dummy implementation of SerialLink when nothing is connected
+pub struct Disconnected;
+
+impl SerialLink for Disconnected {
+    fn send(&mut self, _: u8) {
+        // NOP
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn set_outputs(&mut self, _: bool, _: bool) {
+        // NOP
+    }
+
+    fn inputs(&mut self) -> (bool, bool) {
+        (false, false)
+    }
+}
+
+/// Wrapper around the link, which can't be serialized
+struct Link(Box<SerialLink>);
+
+impl Encodable for Link {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // The link usually wraps a connection to another process so
+        // we can't save it. The frontend has to reconnect it after
+        // loading the savestate.
+        s.emit_nil()
+    }
+}
+
+impl Decodable for Link {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Link, D::Error> {
+        try!(d.read_nil());
+
+        Ok(Link(Box::new(Disconnected)))
+    }
+}
+
+/// Depth of the RX FIFO
+const RX_FIFO_LEN: usize = 8;
+
+/// Interval between two polls of the link when no transfer is in
+/// progress, in CPU cycles. That's about one scanline.
+const LINK_POLL_CYCLES: Cycles = 2_150;
+
+#[test]
+fn sio1_loopback() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use memory::{Byte, HalfWord};
+
+    /// Link with TX connected to RX and RTS connected to CTS
+    struct Loopback(Rc<RefCell<(VecDeque<u8>, bool)>>);
+
+    impl SerialLink for Loopback {
+        fn send(&mut self, byte: u8) {
+            (self.0).borrow_mut().0.push_back(byte);
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            (self.0).borrow_mut().0.pop_front()
+        }
+
+        fn set_outputs(&mut self, _: bool, rts: bool) {
+            (self.0).borrow_mut().1 = rts;
+        }
+
+        fn inputs(&mut self) -> (bool, bool) {
+            (false, (self.0).borrow().1)
+        }
+    }
+
+    let mut shared = SharedState::new();
+    let mut sio1 = Sio1::new();
+
+    sio1.set_link(Box::new(Loopback(Rc::new(RefCell::new((VecDeque::new(),
+                                                          false))))));
+
+    // MUL1, 8bits, 1 stop bit
+    sio1.store::<HalfWord>(&mut shared, 8, 0x4d);
+    sio1.store::<HalfWord>(&mut shared, 14, 0x10);
+    // TX enable, RX enable, RTS and RX interrupt
+    sio1.store::<HalfWord>(&mut shared, 10, 0x825);
+
+    sio1.store::<Byte>(&mut shared, 0, 0x42);
+
+    assert!(sio1.load::<HalfWord>(&mut shared, 4) & 4 == 0);
+
+    // Wait for the end of the transfer and the reception
+    shared.tk().tick(1000);
+    sio1.sync(&mut shared);
+
+    let stat = sio1.load::<HalfWord>(&mut shared, 4);
+
+    // TX idle, RX not empty, CTS and interrupt
+    assert!(stat & 0x306 == 0x306);
+    assert!(shared.irq_state_mut().status() & (1 << 8) != 0);
+
+    assert!(sio1.load::<Byte>(&mut shared, 0) == 0x42);
+}
+
+#[test]
+fn sio1_idle_polling() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use memory::HalfWord;
+
+    /// Link counting how many times its inputs are polled
+    struct Counter(Rc<Cell<u32>>);
+
+    impl SerialLink for Counter {
+        fn send(&mut self, _: u8) {
+        }
+
+        fn receive(&mut self) -> Option<u8> {
+            None
+        }
+
+        fn set_outputs(&mut self, _: bool, _: bool) {
+        }
+
+        fn inputs(&mut self) -> (bool, bool) {
+            self.0.set(self.0.get() + 1);
+
+            (false, false)
+        }
+    }
+
+    let polls = Rc::new(Cell::new(0));
+
+    let mut shared = SharedState::new();
+    let mut sio1 = Sio1::new();
+
+    sio1.set_link(Box::new(Counter(polls.clone())));
+
+    // MUL1, 8bits, 1 stop bit, 160 cycles per byte
+    sio1.store::<HalfWord>(&mut shared, 8, 0x4d);
+    sio1.store::<HalfWord>(&mut shared, 14, 0x10);
+    // RX enable
+    sio1.store::<HalfWord>(&mut shared, 10, 0x4);
+
+    polls.set(0);
+
+    // Run for 100 polling periods with nothing on the line
+    for _ in 0..(100 * LINK_POLL_CYCLES / 10) {
+        shared.tk().tick(10);
+
+        if shared.tk().needs_sync(Peripheral::Sio1) {
+            sio1.sync(&mut shared);
+        }
+    }
+
+    assert!(polls.get() == 100);
+}
@@ -0,0 +1,269 @@
+//! Link cable emulation over a local socket
+//!
+//! Two emulator instances can be connected by having one of them
+//! listen on a socket and the other connect to it. Each event on the
+//! cable is sent as a two byte message: a tag and a value. The tag
+//! is either `MSG_DATA` for a data byte or `MSG_LINES` for a change
+//! of the DTR/RTS outputs, which are wired to the DSR/CTS inputs of
+//! the remote console.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::VecDeque;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use super::SerialLink;
+
+/// Serial link to a remote emulator instance
+pub struct SocketLink<S: Stream> {
+    /// Connection to the remote instance, `None` if it's been closed
+    stream: Option<S>,
+    /// Data received from the remote instance, not yet read by the
+    /// SIO1
+    rx: VecDeque<u8>,
+    /// Incomplete message received from the remote instance
+    partial: Option<u8>,
+    /// Messages which could not be sent yet
+    tx: Vec<u8>,
+    /// State of the remote DTR output, wired to our DSR input
+    dsr: bool,
+    /// State of the remote RTS output, wired to our CTS input
+    cts: bool,
+}
+
+impl SocketLink<TcpStream> {
+    /// Wait for a remote instance to connect to `addr`
+    pub fn listen_tcp<A>(addr: A) -> io::Result<SocketLink<TcpStream>>
+        where A: ToSocketAddrs {
+        let listener = try!(TcpListener::bind(addr));
+
+        let (stream, _) = try!(listener.accept());
+
+        SocketLink::new(stream)
+    }
+
+    /// Connect to a remote instance listening on `addr`
+    pub fn connect_tcp<A>(addr: A) -> io::Result<SocketLink<TcpStream>>
+        where A: ToSocketAddrs {
+        let stream = try!(TcpStream::connect(addr));
+
+        SocketLink::new(stream)
+    }
+}
+
+#[cfg(unix)]
+impl SocketLink<UnixStream> {
+    /// Wait for a remote instance to connect to the unix socket at
+    /// `path`
+    pub fn listen_unix<P>(path: P) -> io::Result<SocketLink<UnixStream>>
+        where P: AsRef<Path> {
+        let listener = try!(UnixListener::bind(path));
+
+        let (stream, _) = try!(listener.accept());
+
+        SocketLink::new(stream)
+    }
+
+    /// Connect to a remote instance listening on the unix socket at
+    /// `path`
+    pub fn connect_unix<P>(path: P) -> io::Result<SocketLink<UnixStream>>
+        where P: AsRef<Path> {
+        let stream = try!(UnixStream::connect(path));
+
+        SocketLink::new(stream)
+    }
+}
+
+impl<S: Stream> SocketLink<S> {
+    /// Build a link from an already connected stream
+    pub fn new(stream: S) -> io::Result<SocketLink<S>> {
+        // The emulator can't wait for the remote instance
+        try!(stream.set_nonblocking(true));
+
+        Ok(SocketLink {
+            stream: Some(stream),
+            rx: VecDeque::new(),
+            partial: None,
+            tx: Vec::new(),
+            dsr: false,
+            cts: false,
+        })
+    }
+
+    /// Return false if the connection has been closed
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Queue a message and attempt to send it
+    fn send_message(&mut self, tag: u8, val: u8) {
+        self.tx.push(tag);
+        self.tx.push(val);
+
+        self.flush();
+    }
+
+    /// Send as much of the queued messages as possible without
+    /// blocking
+    fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            let r =
+                match self.stream {
+                    Some(ref mut s) => s.write(&self.tx),
+                    None => {
+                        self.tx.clear();
+                        return;
+                    }
+                };
+
+            match r {
+                Ok(0) => self.close(),
+                Ok(n) => { self.tx.drain(..n); }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                    return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    warn!("SIO1 link write failed: {}", e);
+                    self.close();
+                }
+            }
+        }
+    }
+
+    /// Process the messages received from the remote instance
+    fn poll(&mut self) {
+        self.flush();
+
+        let mut buf = [0; 256];
+
+        loop {
+            let r =
+                match self.stream {
+                    Some(ref mut s) => s.read(&mut buf),
+                    None => return,
+                };
+
+            match r {
+                Ok(0) => {
+                    self.close();
+                    return;
+                }
+                Ok(n) => {
+                    for &b in &buf[..n] {
+                        match self.partial.take() {
+                            Some(tag) => self.handle_message(tag, b),
+                            None => self.partial = Some(b),
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                    return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    warn!("SIO1 link read failed: {}", e);
+                    self.close();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_message(&mut self, tag: u8, val: u8) {
+        match tag {
+            MSG_DATA => self.rx.push_back(val),
+            MSG_LINES => {
+                self.dsr = val & 1 != 0;
+                self.cts = val & 2 != 0;
+            }
+            _ => warn!("Unknown SIO1 link message {:02x} {:02x}", tag, val),
+        }
+    }
+
+    fn close(&mut self) {
+        warn!("SIO1 link closed");
+
+        self.stream = None;
+        self.tx.clear();
+        // The cable is unplugged
+        self.dsr = false;
+        self.cts = false;
+    }
+}
+
+impl<S: Stream> SerialLink for SocketLink<S> {
+    fn send(&mut self, byte: u8) {
+        self.send_message(MSG_DATA, byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        if self.rx.is_empty() {
+            self.poll();
+        }
+
+        self.rx.pop_front()
+    }
+
+    fn set_outputs(&mut self, dtr: bool, rts: bool) {
+        let lines = (dtr as u8) | ((rts as u8) << 1);
+
+        self.send_message(MSG_LINES, lines);
+    }
+
+    fn inputs(&mut self) -> (bool, bool) {
+        self.poll();
+
+        (self.dsr, self.cts)
+    }
+}
+
+/// Connected stream usable by a `SocketLink`
+pub trait Stream: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Data byte message
+const MSG_DATA: u8 = 0;
+/// DTR/RTS state message: bit 0 is DTR, bit 1 is RTS
+const MSG_LINES: u8 = 1;
+
+#[cfg(unix)]
+#[test]
+fn socket_link_pair() {
+    let (a, b) = UnixStream::pair().unwrap();
+
+    let mut a = SocketLink::new(a).unwrap();
+    let mut b = SocketLink::new(b).unwrap();
+
+    a.set_outputs(true, false);
+    a.send(0x42);
+    a.send(0x13);
+
+    assert!(b.inputs() == (true, false));
+    assert!(b.receive() == Some(0x42));
+    assert!(b.receive() == Some(0x13));
+    assert!(b.receive() == None);
+
+    assert!(a.inputs() == (false, false));
+
+    drop(b);
+
+    assert!(a.receive() == None);
+    assert!(!a.is_connected());
+}
@@ -0,0 +1,261 @@
+//! SPU volume envelopes: the ADSR envelope of each voice and the
+//! volume "sweeps" which use the same mechanism.
+
+/// Parameters of a single envelope phase
+#[derive(Clone, Copy)]
+struct EnvelopeParams {
+    /// True if the envelope changes exponentially, linearly otherwise
+    exponential: bool,
+    /// True if the level decreases
+    decreasing: bool,
+    /// Rate shift (0 ... 0x1f), the higher the slower
+    shift: u8,
+    /// Rate step: +7 ... +4 when increasing, -8 ... -5 when
+    /// decreasing
+    step: i32,
+}
+
+impl EnvelopeParams {
+    /// Build the parameters from the 2bit step value found in the
+    /// registers
+    fn new(exponential: bool,
+           decreasing: bool,
+           shift: u8,
+           step: u8) -> EnvelopeParams {
+        let step = step as i32;
+
+        EnvelopeParams {
+            exponential: exponential,
+            decreasing: decreasing,
+            shift: shift,
+            step:
+            if decreasing {
+                -8 + step
+            } else {
+                7 - step
+            },
+        }
+    }
+
+    /// Compute the number of cycles to wait and the level step for
+    /// the given current level (only matters for exponential
+    /// envelopes)
+    fn cycles_and_step(&self, level: i16) -> (u32, i32) {
+        let shift = self.shift as i32;
+
+        let mut cycles = 1u32 << ::std::cmp::max(0, shift - 11);
+        let mut step = self.step << ::std::cmp::max(0, 11 - shift);
+
+        if self.exponential {
+            if self.decreasing {
+                step = (step * level as i32) >> 15;
+            } else if level > 0x6000 {
+                // Exponential increase is faked by slowing down the
+                // envelope in the upper range
+                cycles *= 4;
+            }
+        }
+
+        (cycles, step)
+    }
+
+    /// Advance the envelope by one 44.1kHz tick. `counter` is used to
+    /// keep track of the number of cycles elapsed since the last
+    /// step.
+    fn tick(&self, level: i16, counter: &mut u32) -> i16 {
+        let (cycles, step) = self.cycles_and_step(level);
+
+        *counter += 1;
+
+        if *counter < cycles {
+            return level;
+        }
+
+        *counter = 0;
+
+        let level = level as i32 + step;
+
+        if level < 0 {
+            0
+        } else if level > 0x7fff {
+            0x7fff
+        } else {
+            level as i16
+        }
+    }
+}
+
+/// ADSR envelope phases
+#[derive(Clone, Copy, PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
+pub enum AdsrPhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    /// Release done, the voice is silent
+    Off,
+}
+
+/// ADSR (Attack Decay Sustain Release) envelope state
+#[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
+pub struct Adsr {
+    phase: AdsrPhase,
+    /// Current envelope level, between 0 and 0x7fff
+    level: i16,
+    /// Cycles elapsed since the last envelope step
+    counter: u32,
+}
+
+impl Adsr {
+    pub fn new() -> Adsr {
+        Adsr {
+            phase: AdsrPhase::Off,
+            level: 0,
+            counter: 0,
+        }
+    }
+
+    pub fn level(&self) -> i16 {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: i16) {
+        self.level = level;
+    }
+
+    /// Start the attack phase (key on)
+    pub fn attack(&mut self) {
+        self.phase = AdsrPhase::Attack;
+        self.level = 0;
+        self.counter = 0;
+    }
+
+    /// Start the release phase (key off)
+    pub fn release(&mut self) {
+        self.phase = AdsrPhase::Release;
+        self.counter = 0;
+    }
+
+    /// Stop the envelope immediately
+    pub fn stop(&mut self) {
+        self.phase = AdsrPhase::Release;
+        self.level = 0;
+        self.counter = 0;
+    }
+
+    /// Advance the envelope by one 44.1kHz tick using the ADSR
+    /// configuration `config` (the two ADSR voice registers, low
+    /// register in the 16 LSBs)
+    pub fn tick(&mut self, config: u32) {
+        let low = config as u16;
+        let high = (config >> 16) as u16;
+
+        let params =
+            match self.phase {
+                AdsrPhase::Attack =>
+                    EnvelopeParams::new(low & 0x8000 != 0,
+                                        false,
+                                        ((low >> 10) & 0x1f) as u8,
+                                        ((low >> 8) & 3) as u8),
+                AdsrPhase::Decay =>
+                    EnvelopeParams::new(true,
+                                        true,
+                                        ((low >> 4) & 0xf) as u8,
+                                        0),
+                AdsrPhase::Sustain =>
+                    EnvelopeParams::new(high & 0x8000 != 0,
+                                        high & 0x4000 != 0,
+                                        ((high >> 8) & 0x1f) as u8,
+                                        ((high >> 6) & 3) as u8),
+                AdsrPhase::Release =>
+                    EnvelopeParams::new(high & 0x20 != 0,
+                                        true,
+                                        (high & 0x1f) as u8,
+                                        0),
+                AdsrPhase::Off => return,
+            };
+
+        self.level = params.tick(self.level, &mut self.counter);
+
+        match self.phase {
+            AdsrPhase::Attack =>
+                if self.level == 0x7fff {
+                    self.phase = AdsrPhase::Decay;
+                    self.counter = 0;
+                },
+            AdsrPhase::Decay => {
+                let sustain_level = ((low & 0xf) as i32 + 1) * 0x800;
+
+                if self.level as i32 <= sustain_level {
+                    self.phase = AdsrPhase::Sustain;
+                    self.counter = 0;
+                }
+            }
+            AdsrPhase::Release =>
+                if self.level == 0 {
+                    self.phase = AdsrPhase::Off;
+                },
+            _ => (),
+        }
+    }
+}
+
+/// Volume register state. Volumes can either be fixed or "sweep" up
+/// or down over time using an envelope.
+#[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
+pub struct Volume {
+    /// Current volume level
+    level: i16,
+    /// Cycles elapsed since the last sweep step
+    counter: u32,
+}
+
+impl Volume {
+    pub fn new() -> Volume {
+        Volume {
+            level: 0,
+            counter: 0,
+        }
+    }
+
+    /// Current volume level
+    pub fn level(&self) -> i16 {
+        self.level
+    }
+
+    /// Advance by one 44.1kHz tick using the value of the volume
+    /// register `config`
+    pub fn tick(&mut self, config: u16) {
+        if config & 0x8000 == 0 {
+            // Fixed volume, the value is divided by two in the
+            // register
+            self.level = (config << 1) as i16;
+            self.counter = 0;
+        } else {
+            let params = EnvelopeParams::new(config & 0x4000 != 0,
+                                             config & 0x2000 != 0,
+                                             ((config >> 2) & 0x1f) as u8,
+                                             (config & 3) as u8);
+
+            // The sweep works on the absolute level, the "phase" bit
+            // says if the result is negated
+            let negative = config & 0x1000 != 0;
+
+            let level = (self.level as i32).abs() as i16;
+
+            let level = params.tick(level, &mut self.counter);
+
+            self.level =
+                if negative {
+                    -level
+                } else {
+                    level
+                };
+        }
+    }
+
+    /// Apply the volume to `sample`
+    pub fn apply(&self, sample: i32) -> i32 {
+        (sample * self.level as i32) >> 15
+    }
+}
@@ -1,6 +1,24 @@
 use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
 use memory::Addressable;
+use shared::SharedState;
+use timekeeper::{Peripheral, Cycles};
+use interrupt::Interrupt;
+use cdrom::CdRom;
+
+use self::voice::Voice;
+use self::envelope::Volume;
+use self::sink::AudioSink;
+use self::reverb::Reverb;
+use self::noise::Noise;
+
+pub mod sink;
+pub mod wav;
+
+mod envelope;
+mod voice;
+mod reverb;
+mod noise;
 
 /// Sound Processing Unit
 pub struct Spu {
@@ -14,6 +32,26 @@ pub struct Spu {
     ram: Box<[u16; 256 * 1024]>,
     /// Write pointer in the SPU RAM
     ram_index: u32,
+    /// The 24 voices
+    voices: [Voice; 24],
+    /// ENDX register: one bit per voice, set when the voice reaches
+    /// an ADPCM block with the "loop end" flag
+    endx: u32,
+    /// Main volume (left and right)
+    main_volume: [Volume; 2],
+    /// Reverb unit
+    reverb: Reverb,
+    /// Noise generator
+    noise: Noise,
+    /// True if the SPU interrupt has been triggered and not yet
+    /// acknowledged
+    irq: bool,
+    /// Write position in the capture buffers
+    capture_index: u32,
+    /// CPU cycles elapsed since the last generated sample
+    cycle_counter: u32,
+    /// Generated 44.1kHz samples, interleaved (left first)
+    output: Vec<i16>,
 }
 
 impl Spu {
@@ -22,20 +60,303 @@ impl Spu {
             shadow_registers: [0; 0x100],
             ram: box_array![0xbad; 256 * 1024],
             ram_index: 0,
+            voices: [Voice::new(); 24],
+            endx: 0,
+            main_volume: [Volume::new(); 2],
+            reverb: Reverb::new(),
+            noise: Noise::new(),
+            irq: false,
+            capture_index: 0,
+            cycle_counter: 0,
+            output: Vec::with_capacity(OUTPUT_BUFFER_MAX),
+        }
+    }
+
+    /// Run the SPU up to the current date, generating one stereo
+    /// sample every `CYCLES_PER_SAMPLE` CPU cycles. The SPU also
+    /// mixes the CD audio so we need the `cdrom`.
+    pub fn sync(&mut self, shared: &mut SharedState, cdrom: &mut CdRom) {
+        let delta = shared.tk().sync(Peripheral::Spu);
+
+        let mut cycles = self.cycle_counter as Cycles + delta;
+
+        while cycles >= CYCLES_PER_SAMPLE as Cycles {
+            cycles -= CYCLES_PER_SAMPLE as Cycles;
+
+            self.run_sample(shared, cdrom);
+        }
+
+        self.cycle_counter = cycles as u32;
+
+        // We don't need to be very precise here, samples are
+        // generated in batches. If the interrupt is enabled however
+        // we want to trigger it on time.
+        let samples =
+            if self.irq_enabled() && !self.irq {
+                1
+            } else {
+                SYNC_SAMPLES
+            };
+
+        let next_sync = samples * CYCLES_PER_SAMPLE - self.cycle_counter;
+
+        shared.tk().set_next_sync_delta(Peripheral::Spu, next_sync as Cycles);
+    }
+
+    /// Send all the samples generated so far to `sink`
+    pub fn flush_samples(&mut self,
+                         shared: &mut SharedState,
+                         cdrom: &mut CdRom,
+                         sink: &mut AudioSink) {
+        self.sync(shared, cdrom);
+
+        sink.push_samples(&self.output);
+
+        self.output.clear();
+    }
+
+    /// Generate one 44.1kHz stereo sample
+    fn run_sample(&mut self,
+                  shared: &mut SharedState,
+                  cdrom: &mut CdRom) {
+        let mut left = 0;
+        let mut right = 0;
+
+        // Sum of the voices sent to the reverb unit
+        let mut reverb_left = 0;
+        let mut reverb_right = 0;
+
+        let reverb_en = self.voice_mask(regmap::VOICE_REVERB_EN_LOW);
+        let noise_en = self.voice_mask(regmap::VOICE_NOISE_EN_LOW);
+        // Voice 0 can't be modulated
+        let pitch_mod_en = self.voice_mask(regmap::VOICE_PITCH_MOD_EN_LOW) & !1;
+
+        self.noise.run(self.control());
+
+        // Output of the previous voice, used for pitch modulation
+        let mut prev_sample = 0;
+
+        // Outputs of voices 1 and 3 which are written to the capture
+        // buffers
+        let mut captured_voices = [0; 2];
+
+        // True if one of the voices is reading the block containing
+        // the IRQ address
+        let mut irq_hit = false;
+
+        let irq_address = self.irq_address();
+
+        for (v, voice) in self.voices.iter_mut().enumerate() {
+            let regs = &self.shadow_registers[v * 8..v * 8 + 8];
+
+            let noise =
+                if noise_en & (1 << v) != 0 {
+                    Some(self.noise.level())
+                } else {
+                    None
+                };
+
+            let modulator =
+                if pitch_mod_en & (1 << v) != 0 {
+                    Some(prev_sample)
+                } else {
+                    None
+                };
+
+            let (l, r) = voice.run(&*self.ram,
+                                   regs,
+                                   noise,
+                                   modulator);
+
+            prev_sample = voice.last_sample();
+
+            match v {
+                1 => captured_voices[0] = prev_sample,
+                3 => captured_voices[1] = prev_sample,
+                _ => (),
+            }
+
+            let block = voice.block_address();
+
+            if irq_address >= block && irq_address < block + 8 {
+                irq_hit = true;
+            }
+
+            left += l;
+            right += r;
+
+            if reverb_en & (1 << v) != 0 {
+                reverb_left += l;
+                reverb_right += r;
+            }
+
+            if voice.take_loop_end() {
+                self.endx |= 1 << v;
+            }
+        }
+
+        if irq_hit {
+            self.trigger_irq(shared);
+        }
+
+        // We always consume the CD samples to keep the CD audio FIFO
+        // in sync even if the CD audio input is disabled.
+        let (cd_left, cd_right) = cdrom.next_audio_sample();
+
+        let (cd_left, cd_right) =
+            if self.control() & 1 != 0 {
+                let cd_volume = |sample: i16, vol: u16| {
+                    (sample as i32 * vol as i16 as i32) >> 15
+                };
+
+                let vol_left = self.shadow_registers[regmap::CD_VOLUME_LEFT];
+                let vol_right = self.shadow_registers[regmap::CD_VOLUME_RIGHT];
+
+                (cd_volume(cd_left, vol_left),
+                 cd_volume(cd_right, vol_right))
+            } else {
+                (0, 0)
+            };
+
+        if self.control() & 4 != 0 {
+            // CD audio reverb
+            reverb_left += cd_left;
+            reverb_right += cd_right;
+        }
+
+        // The reverb unit only writes to its work area if the reverb
+        // master enable is set
+        let reverb_write = self.control() & 0x80 != 0;
+
+        let (reverb_left, reverb_right) =
+            self.reverb.run(&self.shadow_registers,
+                            &mut *self.ram,
+                            (reverb_left, reverb_right),
+                            reverb_write,
+                            irq_address);
+
+        if self.reverb.take_irq() {
+            self.trigger_irq(shared);
+        }
+
+        self.capture(shared,
+                     [saturate(cd_left),
+                      saturate(cd_right),
+                      captured_voices[0],
+                      captured_voices[1]]);
+
+        let main_left = self.shadow_registers[regmap::MAIN_VOLUME_LEFT];
+        let main_right = self.shadow_registers[regmap::MAIN_VOLUME_RIGHT];
+
+        self.main_volume[0].tick(main_left);
+        self.main_volume[1].tick(main_right);
+
+        let (mut left, mut right) =
+            if self.control() & 0x4000 != 0 {
+                (self.main_volume[0].apply(saturate(left) as i32)
+                 + reverb_left,
+                 self.main_volume[1].apply(saturate(right) as i32)
+                 + reverb_right)
+            } else {
+                // SPU muted
+                (0, 0)
+            };
+
+        // The mute doesn't affect the CD audio
+        left += cd_left;
+        right += cd_right;
+
+        if self.output.len() < OUTPUT_BUFFER_MAX {
+            self.output.push(saturate(left));
+            self.output.push(saturate(right));
+        }
+    }
+
+    /// Write the CD audio (left and right) and voice 1 and 3 samples
+    /// to the capture buffers at the beginning of the SPU RAM
+    fn capture(&mut self, shared: &mut SharedState, samples: [i16; 4]) {
+        let irq_address = self.irq_address();
+
+        for (i, &s) in samples.iter().enumerate() {
+            let address = CAPTURE_BUFFER_LEN * i as u32 + self.capture_index;
+
+            self.ram[address as usize] = s as u16;
+
+            if address == irq_address {
+                self.trigger_irq(shared);
+            }
+        }
+
+        self.capture_index = (self.capture_index + 1) % CAPTURE_BUFFER_LEN;
+    }
+
+    /// Address of the SPU IRQ in halfwords
+    fn irq_address(&self) -> u32 {
+        (self.shadow_registers[regmap::IRQ_ADDRESS] as u32) << 2
+    }
+
+    fn irq_enabled(&self) -> bool {
+        self.control() & 0x40 != 0
+    }
+
+    /// Called when the IRQ address is accessed
+    fn trigger_irq(&mut self, shared: &mut SharedState) {
+        if self.irq_enabled() && !self.irq {
+            self.irq = true;
+            shared.irq_state_mut().assert(Interrupt::Spu);
+        }
+    }
+
+    /// Return the 24bit voice mask stored in register `low` and the
+    /// following one
+    fn voice_mask(&self, low: usize) -> u32 {
+        let lo = self.shadow_registers[low] as u32;
+        let hi = self.shadow_registers[low + 1] as u32;
+
+        (lo | (hi << 16)) & 0xffffff
+    }
+
+    /// Start the voices whose bit is set in `voices`
+    fn key_on(&mut self, voices: u32) {
+        for v in 0..24 {
+            if voices & (1 << v) != 0 {
+                let index = v * 8 + regmap::voice::ADPCM_START_INDEX;
+                let start = self.shadow_registers[index];
+
+                self.voices[v].key_on(&*self.ram, (start as u32) << 2);
+                self.endx &= !(1 << v);
+            }
+        }
+    }
+
+    /// Release the voices whose bit is set in `voices`
+    fn key_off(&mut self, voices: u32) {
+        for v in 0..24 {
+            if voices & (1 << v) != 0 {
+                self.voices[v].key_off();
+            }
         }
     }
 
-    pub fn store<T: Addressable>(&mut self, offset: u32, val: u32) {
+    pub fn store<T: Addressable>(&mut self,
+                                 shared: &mut SharedState,
+                                 cdrom: &mut CdRom,
+                                 offset: u32,
+                                 val: u32) {
         if T::size() != 2 {
             panic!("Unhandled SPU store ({})", T::size());
         }
 
+        self.sync(shared, cdrom);
+
         let val = val as u16;
 
         // Convert into a halfword index
         let index = (offset >> 1) as usize;
 
         if index < 0xc0 {
+            let voice = &mut self.voices[index >> 3];
+
             match index & 7 {
                 regmap::voice::VOLUME_LEFT => (),
                 regmap::voice::VOLUME_RIGHT => (),
@@ -43,9 +364,10 @@ impl Spu {
                 regmap::voice::ADPCM_START_INDEX => (),
                 regmap::voice::ADPCM_ADSR_LOW => (),
                 regmap::voice::ADPCM_ADSR_HIGH => (),
-                // XXX change current volume?
-                regmap::voice::CURRENT_ADSR_VOLUME => (),
-                regmap::voice::ADPCM_REPEAT_INDEX => (),
+                regmap::voice::CURRENT_ADSR_VOLUME =>
+                    voice.set_adsr_level(val as i16),
+                regmap::voice::ADPCM_REPEAT_INDEX =>
+                    voice.set_repeat_address((val as u32) << 2),
                 _ => unreachable!(),
             }
         } else {
@@ -54,14 +376,10 @@ impl Spu {
                 regmap::MAIN_VOLUME_RIGHT => (),
                 regmap::REVERB_VOLUME_LEFT => (),
                 regmap::REVERB_VOLUME_RIGHT => (),
-                regmap::VOICE_ON_LOW =>
-                    self.shadow_registers[regmap::VOICE_STATUS_LOW] |= val,
-                regmap::VOICE_ON_HIGH =>
-                    self.shadow_registers[regmap::VOICE_STATUS_HIGH] |= val,
-                regmap::VOICE_OFF_LOW =>
-                    self.shadow_registers[regmap::VOICE_STATUS_LOW] &= !val,
-                regmap::VOICE_OFF_HIGH =>
-                    self.shadow_registers[regmap::VOICE_STATUS_HIGH] &= !val,
+                regmap::VOICE_ON_LOW => self.key_on(val as u32),
+                regmap::VOICE_ON_HIGH => self.key_on((val as u32) << 16),
+                regmap::VOICE_OFF_LOW => self.key_off(val as u32),
+                regmap::VOICE_OFF_HIGH => self.key_off((val as u32) << 16),
                 regmap::VOICE_PITCH_MOD_EN_LOW => (),
                 regmap::VOICE_PITCH_MOD_EN_HIGH => (),
                 regmap::VOICE_NOISE_EN_LOW => (),
@@ -71,10 +389,11 @@ impl Spu {
                 regmap::VOICE_STATUS_LOW => (),
                 regmap::VOICE_STATUS_HIGH => (),
                 regmap::REVERB_BASE => (),
+                regmap::IRQ_ADDRESS => (),
                 regmap::TRANSFER_START_INDEX =>
                     self.ram_index = (val as u32) << 2,
                 regmap::TRANSFER_FIFO =>
-                    self.fifo_write(val),
+                    self.fifo_write(shared, val),
                 regmap::CONTROL =>
                     self.set_control(val),
                 regmap::TRANSFER_CONTROL =>
@@ -124,11 +443,16 @@ impl Spu {
         }
     }
 
-    pub fn load<T: Addressable>(&mut self, offset: u32) -> u32 {
+    pub fn load<T: Addressable>(&mut self,
+                                shared: &mut SharedState,
+                                cdrom: &mut CdRom,
+                                offset: u32) -> u32 {
         if T::size() != 2 {
             panic!("Unhandled SPU load ({})", T::size());
         }
 
+        self.sync(shared, cdrom);
+
         let index = (offset >> 1) as usize;
 
         if index >= 0x100 {
@@ -143,29 +467,35 @@ impl Spu {
         // are correctly implemented we can default to the shadow.
         let r =
             if index < 0xc0 {
+                let voice = &self.voices[index >> 3];
+
                 match index & 7 {
                     regmap::voice::CURRENT_ADSR_VOLUME =>
-                        // XXX return current volume
-                        shadow,
+                        voice.adsr_level() as u16,
                     regmap::voice::ADPCM_REPEAT_INDEX =>
-                        // XXX return current repeat index
-                        shadow,
+                        (voice.repeat_address() >> 2) as u16,
                     _ => shadow,
                 }
             } else {
                 match (offset >> 1) as usize {
                     regmap::MAIN_VOLUME_LEFT => shadow,
                     regmap::MAIN_VOLUME_RIGHT => shadow,
+                    regmap::REVERB_VOLUME_LEFT => shadow,
+                    regmap::REVERB_VOLUME_RIGHT => shadow,
                     regmap::VOICE_ON_LOW => shadow,
                     regmap::VOICE_ON_HIGH => shadow,
                     regmap::VOICE_OFF_LOW => shadow,
                     regmap::VOICE_OFF_HIGH => shadow,
+                    regmap::VOICE_PITCH_MOD_EN_LOW => shadow,
+                    regmap::VOICE_PITCH_MOD_EN_HIGH => shadow,
                     regmap::VOICE_NOISE_EN_HIGH => shadow,
                     regmap::VOICE_NOISE_EN_LOW => shadow,
                     regmap::VOICE_REVERB_EN_LOW => shadow,
                     regmap::VOICE_REVERB_EN_HIGH => shadow,
-                    regmap::VOICE_STATUS_LOW => shadow,
-                    regmap::VOICE_STATUS_HIGH => shadow,
+                    regmap::VOICE_STATUS_LOW => self.endx as u16,
+                    regmap::VOICE_STATUS_HIGH => (self.endx >> 16) as u16,
+                    regmap::REVERB_BASE => shadow,
+                    regmap::IRQ_ADDRESS => shadow,
                     regmap::TRANSFER_START_INDEX => shadow,
                     regmap::CONTROL => shadow,
                     regmap::TRANSFER_CONTROL => shadow,
@@ -175,11 +505,11 @@ impl Spu {
                     regmap::EXT_VOLUME_LEFT => shadow,
                     regmap::EXT_VOLUME_RIGHT => shadow,
                     regmap::CURRENT_VOLUME_LEFT =>
-                        // XXX return current value
-                        shadow,
+                        self.main_volume[0].level() as u16,
                     regmap::CURRENT_VOLUME_RIGHT =>
-                        // XXX return current value
-                        shadow,
+                        self.main_volume[1].level() as u16,
+                    regmap::REVERB_APF_OFFSET1...
+                        regmap::REVERB_INPUT_VOLUME_RIGHT => shadow,
                     _ => panic!("Unhandled SPU load {:x}", offset),
                 }
             };
@@ -192,15 +522,33 @@ impl Spu {
     }
 
     fn set_control(&mut self, ctrl: u16) {
-        // XXX if a game enables the SPU IRQ we're probably going to
-        // be in trouble
-        if ctrl & 0x40 != 0 {
-            panic!("Unhandled SPU IRQ");
+        // The interrupt is acknowledged by clearing the IRQ enable
+        // bit
+        if ctrl & 0x40 == 0 {
+            self.irq = false;
         }
     }
 
     fn status(&self) -> u16 {
-        self.control() & 0x3f
+        let ctrl = self.control();
+
+        // The 6 LSBs mirror the control register
+        let mut status = ctrl & 0x3f;
+
+        status |= (self.irq as u16) << 6;
+
+        // DMA request, the transfers complete instantly so the SPU
+        // is never busy (bit 10)
+        let transfer_mode = (ctrl >> 4) & 3;
+
+        status |= ((ctrl >> 5) & 1) << 7;
+        status |= ((transfer_mode == 2) as u16) << 8;
+        status |= ((transfer_mode == 3) as u16) << 9;
+
+        // Set when writing to the second half of the capture buffers
+        status |= ((self.capture_index >= CAPTURE_BUFFER_LEN / 2) as u16) << 11;
+
+        status
     }
 
     /// Set the SPU RAM access pattern
@@ -211,7 +559,7 @@ impl Spu {
         }
     }
 
-    fn fifo_write(&mut self, val: u16) {
+    fn fifo_write(&mut self, shared: &mut SharedState, val: u16) {
         // XXX handle FIFO overflow?
         let index = self.ram_index;
 
@@ -219,12 +567,44 @@ impl Spu {
 
         self.ram[index as usize] = val;
         self.ram_index = (index + 1) & 0x3ffff;
+
+        if index == self.irq_address() {
+            self.trigger_irq(shared);
+        }
+    }
+
+    fn fifo_read(&mut self, shared: &mut SharedState) -> u16 {
+        let index = self.ram_index;
+
+        let val = self.ram[index as usize];
+
+        self.ram_index = (index + 1) & 0x3ffff;
+
+        if index == self.irq_address() {
+            self.trigger_irq(shared);
+        }
+
+        val
+    }
+
+    /// DMA write to the SPU RAM at the current transfer address
+    pub fn dma_write(&mut self, shared: &mut SharedState, val: u32) {
+        self.fifo_write(shared, val as u16);
+        self.fifo_write(shared, (val >> 16) as u16);
+    }
+
+    /// DMA read from the SPU RAM at the current transfer address
+    pub fn dma_read(&mut self, shared: &mut SharedState) -> u32 {
+        let lo = self.fifo_read(shared) as u32;
+        let hi = self.fifo_read(shared) as u32;
+
+        lo | (hi << 16)
     }
 }
 
 impl Encodable for Spu {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
-        s.emit_struct("Spu", 3, |s| {
+        s.emit_struct("Spu", 11, |s| {
             try!(s.emit_struct_field(
                 "shadow_registers", 0,
                 |s| s.emit_seq(
@@ -255,6 +635,22 @@ impl Encodable for Spu {
 
             try!(s.emit_struct_field("ram_index", 2,
                                      |s| self.ram_index.encode(s)));
+            try!(s.emit_struct_field("voices", 3,
+                                     |s| self.voices.encode(s)));
+            try!(s.emit_struct_field("endx", 4,
+                                     |s| self.endx.encode(s)));
+            try!(s.emit_struct_field("main_volume", 5,
+                                     |s| self.main_volume.encode(s)));
+            try!(s.emit_struct_field("cycle_counter", 6,
+                                     |s| self.cycle_counter.encode(s)));
+            try!(s.emit_struct_field("reverb", 7,
+                                     |s| self.reverb.encode(s)));
+            try!(s.emit_struct_field("noise", 8,
+                                     |s| self.noise.encode(s)));
+            try!(s.emit_struct_field("irq", 9,
+                                     |s| self.irq.encode(s)));
+            try!(s.emit_struct_field("capture_index", 10,
+                                     |s| self.capture_index.encode(s)));
 
             Ok(())
         })
@@ -263,7 +659,7 @@ impl Encodable for Spu {
 
 impl Decodable for Spu {
     fn decode<D: Decoder>(d: &mut D) -> Result<Spu, D::Error> {
-        d.read_struct("Spu", 3, |d| {
+        d.read_struct("Spu", 11, |d| {
             let mut spu = Spu::new();
 
             try!(d.read_struct_field(
@@ -307,12 +703,69 @@ impl Decodable for Spu {
                 try!(d.read_struct_field("ram_index",
                                          2,
                                          Decodable::decode));
+            spu.voices =
+                try!(d.read_struct_field("voices",
+                                         3,
+                                         Decodable::decode));
+            spu.endx =
+                try!(d.read_struct_field("endx",
+                                         4,
+                                         Decodable::decode));
+            spu.main_volume =
+                try!(d.read_struct_field("main_volume",
+                                         5,
+                                         Decodable::decode));
+            spu.cycle_counter =
+                try!(d.read_struct_field("cycle_counter",
+                                         6,
+                                         Decodable::decode));
+            spu.reverb =
+                try!(d.read_struct_field("reverb",
+                                         7,
+                                         Decodable::decode));
+            spu.noise =
+                try!(d.read_struct_field("noise",
+                                         8,
+                                         Decodable::decode));
+            spu.irq =
+                try!(d.read_struct_field("irq",
+                                         9,
+                                         Decodable::decode));
+            spu.capture_index =
+                try!(d.read_struct_field("capture_index",
+                                         10,
+                                         Decodable::decode));
 
             Ok(spu)
         })
     }
 }
 
+/// Saturate a sample to 16bits
+fn saturate(sample: i32) -> i16 {
+    if sample < -0x8000 {
+        -0x8000
+    } else if sample > 0x7fff {
+        0x7fff
+    } else {
+        sample as i16
+    }
+}
+
+/// The SPU runs at 44.1kHz, that's exactly 768 CPU cycles per sample
+const CYCLES_PER_SAMPLE: u32 = 768;
+
+/// Number of samples generated between two forced syncs
+const SYNC_SAMPLES: u32 = 256;
+
+/// Maximum number of samples (left and right) kept in the output
+/// buffer, that's a bit more than 1 second of audio. If nobody
+/// consumes the samples the new ones are dropped.
+const OUTPUT_BUFFER_MAX: usize = 0x10000 * 2;
+
+/// Length of each of the 4 capture buffers in halfwords
+const CAPTURE_BUFFER_LEN: u32 = 0x200;
+
 mod regmap {
     //! SPU register map: offset from the base in number of
     //! *halfwords*
@@ -347,6 +800,7 @@ mod regmap {
     pub const VOICE_STATUS_HIGH:          usize = 0xcf;
 
     pub const REVERB_BASE:                usize = 0xd1;
+    pub const IRQ_ADDRESS:                usize = 0xd2;
     pub const TRANSFER_START_INDEX:       usize = 0xd3;
     pub const TRANSFER_FIFO:              usize = 0xd4;
     pub const CONTROL:                    usize = 0xd5;
@@ -392,3 +846,58 @@ mod regmap {
     pub const REVERB_INPUT_VOLUME_LEFT:   usize = 0xfe;
     pub const REVERB_INPUT_VOLUME_RIGHT:  usize = 0xff;
 }
+
+#[test]
+fn transfer_irq() {
+    let mut shared = SharedState::new();
+    let mut spu = Spu::new();
+
+    // IRQ at halfword 0x40, IRQ enabled
+    spu.shadow_registers[regmap::IRQ_ADDRESS] = 0x10;
+    spu.shadow_registers[regmap::CONTROL] = 0x40;
+
+    spu.ram_index = 0x3e;
+
+    spu.dma_write(&mut shared, 0x12345678);
+
+    assert!(spu.ram[0x3e] == 0x5678);
+    assert!(spu.ram[0x3f] == 0x1234);
+    assert!(spu.status() & 0x40 == 0);
+
+    spu.dma_write(&mut shared, 0);
+
+    assert!(spu.status() & 0x40 != 0);
+    assert!(shared.irq_state().status() & (1 << 9) != 0);
+
+    // Acknowledge
+    spu.set_control(0);
+
+    assert!(spu.status() & 0x40 == 0);
+}
+
+#[test]
+fn reverb_register_readback() {
+    use memory::HalfWord;
+
+    let mut shared = SharedState::new();
+    let mut cdrom = CdRom::new(None);
+    let mut spu = Spu::new();
+
+    let regs = [regmap::REVERB_VOLUME_LEFT,
+                regmap::REVERB_VOLUME_RIGHT,
+                regmap::REVERB_APF_OFFSET1,
+                regmap::REVERB_COMB_VOLUME3,
+                regmap::REVERB_INPUT_VOLUME_RIGHT];
+
+    for (i, &r) in regs.iter().enumerate() {
+        let v = 0x1000 + i as u32;
+
+        spu.store::<HalfWord>(&mut shared, &mut cdrom, (r << 1) as u32, v);
+    }
+
+    for (i, &r) in regs.iter().enumerate() {
+        let v = spu.load::<HalfWord>(&mut shared, &mut cdrom, (r << 1) as u32);
+
+        assert!(v == 0x1000 + i as u32);
+    }
+}
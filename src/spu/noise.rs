@@ -0,0 +1,86 @@
+//! SPU noise generator: a 16bit LFSR clocked at a rate configured in
+//! the SPU control register. Voices with noise enabled output the
+//! LFSR value instead of their ADPCM samples.
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Noise {
+    /// Current noise level
+    level: u16,
+    /// Countdown to the next LFSR step
+    timer: i32,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            level: 0,
+            timer: 0,
+        }
+    }
+
+    /// Current noise sample
+    pub fn level(&self) -> i16 {
+        self.level as i16
+    }
+
+    /// Run the noise generator for one 44.1kHz cycle using the
+    /// configuration in SPU control register `control`
+    pub fn run(&mut self, control: u16) {
+        let step = ((control >> 8) & 3) as i32 + 4;
+        let shift = (control >> 10) & 0xf;
+
+        self.timer -= step;
+
+        if self.timer < 0 {
+            let l = self.level;
+
+            let parity = (l >> 15) ^ (l >> 12) ^ (l >> 11) ^ (l >> 10) ^ 1;
+
+            self.level = (l << 1) | (parity & 1);
+
+            // The period is added twice if the timer is still
+            // negative, that's how the hardware behaves with the
+            // highest frequencies according to No$
+            let period = 0x20000 >> shift;
+
+            self.timer += period;
+
+            if self.timer < 0 {
+                self.timer += period;
+            }
+        }
+    }
+}
+
+#[test]
+fn noise_lfsr() {
+    let mut noise = Noise::new();
+
+    // Fastest rate: one step per cycle
+    let control = 0x3f00;
+
+    let expected = [0x0001, 0x0003, 0x0007, 0x000f,
+                    0x001f, 0x003f, 0x007f, 0x00ff,
+                    0x01ff, 0x03ff, 0x07ff, 0x0ffe];
+
+    for &e in &expected {
+        noise.run(control);
+
+        assert!(noise.level() == e);
+    }
+
+    // Slowest rate: one step every 0x8000 cycles
+    let mut noise = Noise::new();
+
+    noise.run(0);
+    assert!(noise.level() == 1);
+
+    for _ in 0..0x7fff {
+        noise.run(0);
+    }
+
+    assert!(noise.level() == 1);
+
+    noise.run(0);
+    assert!(noise.level() == 3);
+}
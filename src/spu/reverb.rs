@@ -0,0 +1,245 @@
+//! SPU reverb unit
+//!
+//! The reverb uses a work area at the end of the SPU RAM (starting
+//! at `REVERB_BASE`) as a set of delay lines. All the addresses in
+//! the reverb registers are relative to the current position in the
+//! work area which moves forward every time the reverb runs and wraps
+//! around within the work area.
+
+use super::regmap;
+use super::saturate;
+
+/// Size of the SPU RAM in halfwords
+const RAM_SIZE: u32 = 256 * 1024;
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Reverb {
+    /// Current position in the work area (in halfwords)
+    buffer_address: u32,
+    /// Output of the reverb, the reverb runs at 22.05kHz so we output
+    /// every sample twice
+    output: (i32, i32),
+    /// True if the reverb runs during the next 44.1kHz cycle
+    active_cycle: bool,
+    /// SPU IRQ address, only valid while the reverb is running
+    irq_address: u32,
+    /// Set if the IRQ address was accessed by the reverb
+    irq: bool,
+}
+
+impl Reverb {
+    pub fn new() -> Reverb {
+        Reverb {
+            buffer_address: 0,
+            output: (0, 0),
+            active_cycle: true,
+            irq_address: 0,
+            irq: false,
+        }
+    }
+
+    /// Run the reverb for one 44.1kHz cycle. `input` is the sum of
+    /// the voices with reverb enabled. If `write_enable` is false the
+    /// work area isn't modified. `irq_address` is the SPU IRQ
+    /// address, see `take_irq`.
+    pub fn run(&mut self,
+               regs: &[u16; 0x100],
+               ram: &mut [u16],
+               input: (i32, i32),
+               write_enable: bool,
+               irq_address: u32) -> (i32, i32) {
+        self.active_cycle = !self.active_cycle;
+
+        if self.active_cycle {
+            return self.output;
+        }
+
+        self.irq_address = irq_address;
+
+        // Register accessor, the values are signed
+        let reg = |r: usize| regs[r] as i16 as i32;
+        // Address registers are in multiples of 8 bytes (4 halfwords)
+        let addr = |r: usize| (regs[r] as u32) << 2;
+
+        let base = addr(regmap::REVERB_BASE);
+
+        let mul = |a: i32, b: i32| (a * b) >> 15;
+
+        let (left_in, right_in) = input;
+
+        let left_in = mul(saturate(left_in) as i32,
+                          reg(regmap::REVERB_INPUT_VOLUME_LEFT));
+        let right_in = mul(saturate(right_in) as i32,
+                           reg(regmap::REVERB_INPUT_VOLUME_RIGHT));
+
+        let wall = reg(regmap::REVERB_REFLECT_VOLUME2);
+        let iir = reg(regmap::REVERB_REFLECT_VOLUME1);
+
+        // Same side and different side reflections
+        let reflections = [
+            (regmap::REVERB_REFLECT_SAME_LEFT1,
+             regmap::REVERB_REFLECT_SAME_LEFT2,
+             left_in),
+            (regmap::REVERB_REFLECT_SAME_RIGHT1,
+             regmap::REVERB_REFLECT_SAME_RIGHT2,
+             right_in),
+            (regmap::REVERB_REFLECT_DIFF_LEFT1,
+             regmap::REVERB_REFLECT_DIFF_RIGHT2,
+             left_in),
+            (regmap::REVERB_REFLECT_DIFF_RIGHT1,
+             regmap::REVERB_REFLECT_DIFF_LEFT2,
+             right_in),
+        ];
+
+        for &(dst, src, input) in &reflections {
+            let dst = addr(dst);
+            let src = addr(src);
+
+            let prev = self.read(ram, base, dst, -1);
+            let reflected = mul(self.read(ram, base, src, 0), wall);
+
+            let v = mul(input + reflected - prev, iir) + prev;
+
+            if write_enable {
+                self.write(ram, base, dst, v);
+            }
+        }
+
+        // Early echo (comb filter)
+        let combs = [
+            (regmap::REVERB_COMB_LEFT1,
+             regmap::REVERB_COMB_RIGHT1,
+             regmap::REVERB_COMB_VOLUME1),
+            (regmap::REVERB_COMB_LEFT2,
+             regmap::REVERB_COMB_RIGHT2,
+             regmap::REVERB_COMB_VOLUME2),
+            (regmap::REVERB_COMB_LEFT3,
+             regmap::REVERB_COMB_RIGHT3,
+             regmap::REVERB_COMB_VOLUME3),
+            (regmap::REVERB_COMB_LEFT4,
+             regmap::REVERB_COMB_RIGHT4,
+             regmap::REVERB_COMB_VOLUME4),
+        ];
+
+        let mut left = 0;
+        let mut right = 0;
+
+        for &(l, r, vol) in &combs {
+            let vol = reg(vol);
+
+            left += mul(self.read(ram, base, addr(l), 0), vol);
+            right += mul(self.read(ram, base, addr(r), 0), vol);
+        }
+
+        // Late reverb: two all pass filters in series
+        let apfs = [
+            (regmap::REVERB_APF_LEFT1,
+             regmap::REVERB_APF_RIGHT1,
+             regmap::REVERB_APF_OFFSET1,
+             regmap::REVERB_APF_VOLUME1),
+            (regmap::REVERB_APF_LEFT2,
+             regmap::REVERB_APF_RIGHT2,
+             regmap::REVERB_APF_OFFSET2,
+             regmap::REVERB_APF_VOLUME2),
+        ];
+
+        for &(l, r, offset, vol) in &apfs {
+            let offset = -(addr(offset) as i32);
+            let vol = reg(vol);
+
+            let l = addr(l);
+            let r = addr(r);
+
+            let delayed_left = self.read(ram, base, l, offset);
+            let delayed_right = self.read(ram, base, r, offset);
+
+            let left_filtered = left - mul(delayed_left, vol);
+            let right_filtered = right - mul(delayed_right, vol);
+
+            let left_filtered = saturate(left_filtered) as i32;
+            let right_filtered = saturate(right_filtered) as i32;
+
+            if write_enable {
+                self.write(ram, base, l, left_filtered);
+                self.write(ram, base, r, right_filtered);
+            }
+
+            left = mul(left_filtered, vol) + delayed_left;
+            right = mul(right_filtered, vol) + delayed_right;
+        }
+
+        self.output =
+            (mul(saturate(left) as i32, reg(regmap::REVERB_VOLUME_LEFT)),
+             mul(saturate(right) as i32, reg(regmap::REVERB_VOLUME_RIGHT)));
+
+        // Move on to the next position in the work area
+        let next = (self.buffer_address + 1) & (RAM_SIZE - 1);
+
+        self.buffer_address = ::std::cmp::max(base, next);
+
+        self.output
+    }
+
+    /// Compute the absolute halfword address of `address + offset`
+    /// relative to the current position, wrapping around within the
+    /// work area starting at `base`
+    fn address(&self, base: u32, address: u32, offset: i32) -> usize {
+        let size = (RAM_SIZE - base) as i64;
+
+        // The current position may be outside of the work area if the
+        // base was just changed
+        let pos = self.buffer_address.saturating_sub(base) as i64;
+
+        let rel = (pos + address as i64 + offset as i64) % size;
+
+        let rel =
+            if rel < 0 {
+                rel + size
+            } else {
+                rel
+            };
+
+        (base as i64 + rel) as usize
+    }
+
+    /// Return true if the reverb accessed the SPU IRQ address since
+    /// the last call
+    pub fn take_irq(&mut self) -> bool {
+        let irq = self.irq;
+
+        self.irq = false;
+
+        irq
+    }
+
+    fn read(&mut self,
+            ram: &[u16],
+            base: u32,
+            address: u32,
+            offset: i32) -> i32 {
+        let address = self.address(base, address, offset);
+
+        self.irq |= address as u32 == self.irq_address;
+
+        ram[address] as i16 as i32
+    }
+
+    fn write(&mut self, ram: &mut [u16], base: u32, address: u32, val: i32) {
+        let address = self.address(base, address, 0);
+
+        self.irq |= address as u32 == self.irq_address;
+
+        ram[address] = saturate(val) as u16;
+    }
+}
+
+#[test]
+fn work_area_wrap() {
+    let mut reverb = Reverb::new();
+
+    reverb.buffer_address = 0x3fffe;
+
+    assert!(reverb.address(0x3f000, 0, 0) == 0x3fffe);
+    assert!(reverb.address(0x3f000, 4, 0) == 0x3f002);
+    assert!(reverb.address(0x3f000, 0, -0xfff) == 0x3ffff);
+}
@@ -0,0 +1,14 @@
+/// Interface used by the emulator to output the audio generated by
+/// the SPU.
+pub trait AudioSink {
+    /// Called with a batch of 44.1kHz stereo samples. The samples
+    /// are interleaved, left channel first.
+    fn push_samples(&mut self, samples: &[i16]);
+}
+
+/// Sink discarding all samples, for frontends which don't care about
+/// audio
+impl AudioSink for () {
+    fn push_samples(&mut self, _: &[i16]) {
+    }
+}
@@ -0,0 +1,434 @@
+//! SPU voice emulation: SPU-ADPCM decoding, pitch counter with
+//! gaussian interpolation and ADSR envelope.
+//!
+//! SPU-ADPCM samples are stored in SPU RAM in 16 byte blocks. The
+//! first byte contains the shift and filter, the second the loop
+//! flags and the remaining 14 bytes contain 28 4bit samples.
+
+use super::envelope::{Adsr, Volume};
+
+/// Size of the SPU RAM in halfwords
+const RAM_SIZE: u32 = 256 * 1024;
+
+/// State of one of the 24 SPU voices
+#[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
+pub struct Voice {
+    /// Address of the current ADPCM block in SPU RAM (in halfwords)
+    address: u32,
+    /// Address the voice jumps to when it reaches the end of a block
+    /// with the "loop end" flag set
+    repeat_address: u32,
+    /// Position in the current block: the 12 LSBs are the fractional
+    /// part used for interpolation, the rest is the sample index.
+    counter: u32,
+    /// Loop flags of the current ADPCM block
+    block_flags: u8,
+    /// The last 3 samples of the previous block followed by the 28
+    /// samples of the current block. We need the previous samples
+    /// for the interpolation.
+    samples: [i16; 31],
+    /// Last two decoded samples, used by the ADPCM prediction filters
+    history: (i32, i32),
+    /// Set when the voice reaches a block with the "loop end" flag,
+    /// cleared when the SPU latches it in the ENDX register
+    loop_end: bool,
+    /// ADSR envelope
+    adsr: Adsr,
+    /// Left and right volumes
+    volume: [Volume; 2],
+    /// Last sample output by the voice (after the ADSR envelope but
+    /// before the volume), used to modulate the pitch of the next
+    /// voice
+    last_sample: i16,
+}
+
+impl Voice {
+    pub fn new() -> Voice {
+        Voice {
+            address: 0,
+            repeat_address: 0,
+            counter: 0,
+            block_flags: 0,
+            samples: [0; 31],
+            history: (0, 0),
+            loop_end: false,
+            adsr: Adsr::new(),
+            volume: [Volume::new(); 2],
+            last_sample: 0,
+        }
+    }
+
+    /// Start the voice at `start_address` (in halfwords)
+    pub fn key_on(&mut self, ram: &[u16], start_address: u32) {
+        self.address = start_address & (RAM_SIZE - 1);
+        self.counter = 0;
+        self.samples = [0; 31];
+        self.history = (0, 0);
+        self.loop_end = false;
+        self.adsr.attack();
+
+        self.decode_block(ram);
+    }
+
+    /// Start the release phase of the envelope
+    pub fn key_off(&mut self) {
+        self.adsr.release();
+    }
+
+    /// Address of the ADPCM block currently being played
+    pub fn block_address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn repeat_address(&self) -> u32 {
+        self.repeat_address
+    }
+
+    pub fn set_repeat_address(&mut self, address: u32) {
+        self.repeat_address = address & (RAM_SIZE - 1);
+    }
+
+    pub fn adsr_level(&self) -> i16 {
+        self.adsr.level()
+    }
+
+    pub fn set_adsr_level(&mut self, level: i16) {
+        self.adsr.set_level(level);
+    }
+
+    /// Return true if the voice reached a block with the "loop end"
+    /// flag since the last call
+    pub fn take_loop_end(&mut self) -> bool {
+        let end = self.loop_end;
+
+        self.loop_end = false;
+
+        end
+    }
+
+    pub fn last_sample(&self) -> i16 {
+        self.last_sample
+    }
+
+    /// Run the voice for one 44.1kHz cycle and return the stereo
+    /// output. `regs` are the 8 registers of this voice. If `noise`
+    /// is not `None` it's output instead of the ADPCM samples. If
+    /// `modulator` is not `None` it's used to modulate the pitch.
+    pub fn run(&mut self,
+               ram: &[u16],
+               regs: &[u16],
+               noise: Option<i16>,
+               modulator: Option<i16>) -> (i32, i32) {
+        let sample =
+            match noise {
+                Some(n) => n as i32,
+                None => self.interpolate(),
+            };
+
+        let sample = (sample * self.adsr.level() as i32) >> 15;
+
+        self.last_sample = sample as i16;
+
+        let adsr_config = (regs[4] as u32) | ((regs[5] as u32) << 16);
+
+        self.adsr.tick(adsr_config);
+
+        self.volume[0].tick(regs[0]);
+        self.volume[1].tick(regs[1]);
+
+        let left = self.volume[0].apply(sample);
+        let right = self.volume[1].apply(sample);
+
+        let mut step = regs[2] as u32;
+
+        if let Some(m) = modulator {
+            // The factor goes from 0 to ~2. The pitch is treated as
+            // a signed value during the multiplication.
+            let factor = m as i32 + 0x8000;
+
+            let s = (step as i16 as i32 * factor) >> 15;
+
+            step = (s as u32) & 0xffff;
+        }
+
+        // 0x1000 is 44.1kHz, the pitch saturates at 0x4000
+        // (176.4kHz)
+        let step = ::std::cmp::min(step, 0x4000);
+
+        self.counter += step;
+
+        if (self.counter >> 12) >= 28 {
+            self.counter -= 28 << 12;
+
+            self.next_block(ram);
+        }
+
+        (left, right)
+    }
+
+    /// Interpolate the current sample from the four surrounding
+    /// samples using the gaussian table
+    fn interpolate(&self) -> i32 {
+        // Sample index, offset to skip the previous block samples
+        let n = (self.counter >> 12) as usize + 3;
+        let i = ((self.counter >> 4) & 0xff) as usize;
+
+        let s = &self.samples;
+
+        let g = |i: usize| GAUSS_TABLE[i] as i32;
+
+        let mut out = (g(0xff - i) * s[n - 3] as i32) >> 15;
+        out += (g(0x1ff - i) * s[n - 2] as i32) >> 15;
+        out += (g(0x100 + i) * s[n - 1] as i32) >> 15;
+        out += (g(i) * s[n] as i32) >> 15;
+
+        out
+    }
+
+    /// Handle the loop flags of the block we just finished and move
+    /// on to the next one
+    fn next_block(&mut self, ram: &[u16]) {
+        if self.block_flags & 1 != 0 {
+            // Loop end
+            self.loop_end = true;
+            self.address = self.repeat_address;
+
+            if self.block_flags & 2 == 0 {
+                // No repeat: the voice is stopped (but it keeps
+                // running silently from the repeat address)
+                self.adsr.stop();
+            }
+        } else {
+            self.address = (self.address + 8) & (RAM_SIZE - 1);
+        }
+
+        self.decode_block(ram);
+    }
+
+    /// Decode the ADPCM block at the current address
+    fn decode_block(&mut self, ram: &[u16]) {
+        let header = ram[self.address as usize];
+
+        let shift =
+            match header & 0xf {
+                // Values above 12 behave like 9 according to No$
+                s @ 0...12 => s,
+                _ => 9,
+            };
+
+        let filter = ::std::cmp::min((header >> 4) & 7, 4) as usize;
+
+        self.block_flags = (header >> 8) as u8;
+
+        if self.block_flags & 4 != 0 {
+            // Loop start
+            self.repeat_address = self.address;
+        }
+
+        // Keep the last 3 samples of the previous block for the
+        // interpolation
+        for i in 0..3 {
+            self.samples[i] = self.samples[28 + i];
+        }
+
+        let (pos, neg) = ADPCM_FILTERS[filter];
+
+        let (mut old, mut older) = self.history;
+
+        for i in 0..28 {
+            let addr = (self.address + 1 + (i as u32 / 4)) & (RAM_SIZE - 1);
+
+            let word = ram[addr as usize];
+
+            let nibble = (word >> ((i & 3) * 4)) & 0xf;
+
+            // Put the nibble in the high bits and shift it right to
+            // sign-extend it
+            let sample = (((nibble << 12) as i16) >> shift) as i32;
+
+            let sample = sample + ((old * pos + older * neg + 32) >> 6);
+
+            let sample =
+                if sample < -0x8000 {
+                    -0x8000
+                } else if sample > 0x7fff {
+                    0x7fff
+                } else {
+                    sample
+                };
+
+            older = old;
+            old = sample;
+
+            self.samples[3 + i] = sample as i16;
+        }
+
+        self.history = (old, older);
+    }
+}
+
+/// SPU-ADPCM prediction filter coefficients (positive and
+/// negative). The SPU has one more filter than the CD-ROM XA decoder.
+const ADPCM_FILTERS: [(i32, i32); 5] = [
+    (0, 0),
+    (60, 0),
+    (115, -52),
+    (98, -55),
+    (122, -60),
+];
+
+/// Gaussian interpolation table stored in the SPU ROM (as dumped by
+/// No$ and mednafen). For the fractional position `i` the newest
+/// sample's weight is `GAUSS_TABLE[i]`, the three others
+/// `GAUSS_TABLE[0x100 + i]`, `GAUSS_TABLE[0x1ff - i]` and
+/// `GAUSS_TABLE[0xff - i]`. The four weights always sum to about
+/// 0x7f80.
+const GAUSS_TABLE: [i16; 512] = [
+    -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001,
+    -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001,
+     0x0000,  0x0000,  0x0000,  0x0000,  0x0000,  0x0000,  0x0000,  0x0001,
+     0x0001,  0x0001,  0x0001,  0x0002,  0x0002,  0x0002,  0x0003,  0x0003,
+     0x0003,  0x0004,  0x0004,  0x0005,  0x0005,  0x0006,  0x0007,  0x0007,
+     0x0008,  0x0009,  0x0009,  0x000a,  0x000b,  0x000c,  0x000d,  0x000e,
+     0x000f,  0x0010,  0x0011,  0x0012,  0x0013,  0x0015,  0x0016,  0x0018,
+     0x0019,  0x001b,  0x001c,  0x001e,  0x0020,  0x0021,  0x0023,  0x0025,
+     0x0027,  0x0029,  0x002c,  0x002e,  0x0030,  0x0033,  0x0035,  0x0038,
+     0x003a,  0x003d,  0x0040,  0x0043,  0x0046,  0x0049,  0x004d,  0x0050,
+     0x0054,  0x0057,  0x005b,  0x005f,  0x0063,  0x0067,  0x006b,  0x006f,
+     0x0074,  0x0078,  0x007d,  0x0082,  0x0087,  0x008c,  0x0091,  0x0096,
+     0x009c,  0x00a1,  0x00a7,  0x00ad,  0x00b3,  0x00ba,  0x00c0,  0x00c7,
+     0x00cd,  0x00d4,  0x00db,  0x00e3,  0x00ea,  0x00f2,  0x00fa,  0x0101,
+     0x010a,  0x0112,  0x011b,  0x0123,  0x012c,  0x0135,  0x013f,  0x0148,
+     0x0152,  0x015c,  0x0166,  0x0171,  0x017b,  0x0186,  0x0191,  0x019c,
+     0x01a8,  0x01b4,  0x01c0,  0x01cc,  0x01d9,  0x01e5,  0x01f2,  0x0200,
+     0x020d,  0x021b,  0x0229,  0x0237,  0x0246,  0x0255,  0x0264,  0x0273,
+     0x0283,  0x0293,  0x02a3,  0x02b4,  0x02c4,  0x02d6,  0x02e7,  0x02f9,
+     0x030b,  0x031d,  0x0330,  0x0343,  0x0356,  0x036a,  0x037e,  0x0392,
+     0x03a7,  0x03bc,  0x03d1,  0x03e7,  0x03fc,  0x0413,  0x042a,  0x0441,
+     0x0458,  0x0470,  0x0488,  0x04a0,  0x04b9,  0x04d2,  0x04ec,  0x0506,
+     0x0520,  0x053b,  0x0556,  0x0572,  0x058e,  0x05aa,  0x05c7,  0x05e4,
+     0x0601,  0x061f,  0x063e,  0x065c,  0x067c,  0x069b,  0x06bb,  0x06dc,
+     0x06fd,  0x071e,  0x0740,  0x0762,  0x0784,  0x07a7,  0x07cb,  0x07ef,
+     0x0813,  0x0838,  0x085d,  0x0883,  0x08a9,  0x08d0,  0x08f7,  0x091e,
+     0x0946,  0x096f,  0x0998,  0x09c1,  0x09eb,  0x0a16,  0x0a40,  0x0a6c,
+     0x0a98,  0x0ac4,  0x0af1,  0x0b1e,  0x0b4c,  0x0b7a,  0x0ba9,  0x0bd8,
+     0x0c07,  0x0c38,  0x0c68,  0x0c99,  0x0ccb,  0x0cfd,  0x0d30,  0x0d63,
+     0x0d97,  0x0dcb,  0x0e00,  0x0e35,  0x0e6b,  0x0ea1,  0x0ed7,  0x0f0f,
+     0x0f46,  0x0f7f,  0x0fb7,  0x0ff1,  0x102a,  0x1065,  0x109f,  0x10db,
+     0x1116,  0x1153,  0x118f,  0x11cd,  0x120b,  0x1249,  0x1288,  0x12c7,
+     0x1307,  0x1347,  0x1388,  0x13c9,  0x140b,  0x144d,  0x1490,  0x14d4,
+     0x1517,  0x155c,  0x15a0,  0x15e6,  0x162c,  0x1672,  0x16b9,  0x1700,
+     0x1747,  0x1790,  0x17d8,  0x1821,  0x186b,  0x18b5,  0x1900,  0x194b,
+     0x1996,  0x19e2,  0x1a2e,  0x1a7b,  0x1ac8,  0x1b16,  0x1b64,  0x1bb3,
+     0x1c02,  0x1c51,  0x1ca1,  0x1cf1,  0x1d42,  0x1d93,  0x1de5,  0x1e37,
+     0x1e89,  0x1edc,  0x1f2f,  0x1f82,  0x1fd6,  0x202a,  0x207f,  0x20d4,
+     0x2129,  0x217f,  0x21d5,  0x222c,  0x2282,  0x22da,  0x2331,  0x2389,
+     0x23e1,  0x2439,  0x2492,  0x24eb,  0x2545,  0x259e,  0x25f8,  0x2653,
+     0x26ad,  0x2708,  0x2763,  0x27be,  0x281a,  0x2876,  0x28d2,  0x292e,
+     0x298b,  0x29e7,  0x2a44,  0x2aa1,  0x2aff,  0x2b5c,  0x2bba,  0x2c18,
+     0x2c76,  0x2cd4,  0x2d33,  0x2d91,  0x2df0,  0x2e4f,  0x2eae,  0x2f0d,
+     0x2f6c,  0x2fcc,  0x302b,  0x308b,  0x30ea,  0x314a,  0x31aa,  0x3209,
+     0x3269,  0x32c9,  0x3329,  0x3389,  0x33e9,  0x3449,  0x34a9,  0x3509,
+     0x3569,  0x35c9,  0x3629,  0x3689,  0x36e8,  0x3748,  0x37a8,  0x3807,
+     0x3867,  0x38c6,  0x3926,  0x3985,  0x39e4,  0x3a43,  0x3aa2,  0x3b00,
+     0x3b5f,  0x3bbd,  0x3c1b,  0x3c79,  0x3cd7,  0x3d35,  0x3d92,  0x3def,
+     0x3e4c,  0x3ea9,  0x3f05,  0x3f62,  0x3fbd,  0x4019,  0x4074,  0x40d0,
+     0x412a,  0x4185,  0x41df,  0x4239,  0x4292,  0x42eb,  0x4344,  0x439c,
+     0x43f4,  0x444c,  0x44a3,  0x44fa,  0x4550,  0x45a6,  0x45fc,  0x4651,
+     0x46a6,  0x46fa,  0x474e,  0x47a1,  0x47f4,  0x4846,  0x4898,  0x48e9,
+     0x493a,  0x498a,  0x49d9,  0x4a29,  0x4a77,  0x4ac5,  0x4b13,  0x4b5f,
+     0x4bac,  0x4bf7,  0x4c42,  0x4c8d,  0x4cd7,  0x4d20,  0x4d68,  0x4db0,
+     0x4df7,  0x4e3e,  0x4e84,  0x4ec9,  0x4f0e,  0x4f52,  0x4f95,  0x4fd7,
+     0x5019,  0x505a,  0x509a,  0x50da,  0x5118,  0x5156,  0x5194,  0x51d0,
+     0x520c,  0x5247,  0x5281,  0x52ba,  0x52f3,  0x532a,  0x5361,  0x5397,
+     0x53cc,  0x5401,  0x5434,  0x5467,  0x5499,  0x54ca,  0x54fa,  0x5529,
+     0x5558,  0x5585,  0x55b2,  0x55de,  0x5609,  0x5632,  0x565b,  0x5684,
+     0x56ab,  0x56d1,  0x56f6,  0x571b,  0x573e,  0x5761,  0x5782,  0x57a3,
+     0x57c3,  0x57e2,  0x57ff,  0x581c,  0x5838,  0x5853,  0x586d,  0x5886,
+     0x589e,  0x58b5,  0x58cb,  0x58e0,  0x58f4,  0x5907,  0x5919,  0x592a,
+     0x593a,  0x5949,  0x5958,  0x5965,  0x5971,  0x597c,  0x5986,  0x598f,
+     0x5997,  0x599e,  0x59a4,  0x59a9,  0x59ad,  0x59b0,  0x59b2,  0x59b3,
+];
+
+#[test]
+fn adpcm_loop() {
+    let mut ram = vec![0u16; RAM_SIZE as usize];
+
+    // Block at 0x100: shift 12, filter 0, loop start + loop end +
+    // repeat. All samples set to 1.
+    ram[0x100] = 0x070c;
+    for i in 1..8 {
+        ram[0x100 + i] = 0x1111;
+    }
+
+    // 44.1kHz, default ADSR and volumes
+    let regs = [0, 0, 0x1000, 0x100, 0, 0, 0, 0];
+
+    let mut voice = Voice::new();
+
+    voice.key_on(&ram, 0x100);
+
+    assert!(voice.repeat_address() == 0x100);
+    assert!(voice.samples[3..].iter().all(|&s| s == 1));
+
+    for _ in 0..27 {
+        voice.run(&ram, &regs, None, None);
+    }
+
+    assert!(!voice.take_loop_end());
+
+    voice.run(&ram, &regs, None, None);
+
+    assert!(voice.take_loop_end());
+    assert!(voice.address == 0x100);
+    assert!(voice.adsr_level() > 0);
+}
+
+#[test]
+fn pitch_modulation() {
+    let mut ram = vec![0u16; RAM_SIZE as usize];
+
+    // Looping block at 0x100
+    ram[0x100] = 0x070c;
+
+    // 44.1kHz, default ADSR and volumes
+    let regs = [0, 0, 0x1000, 0x100, 0, 0, 0, 0];
+
+    let mut voice = Voice::new();
+
+    voice.key_on(&ram, 0x100);
+
+    // The lowest modulator value stops the voice
+    for _ in 0..100 {
+        voice.run(&ram, &regs, None, Some(-0x8000));
+    }
+
+    assert!(voice.counter == 0);
+    assert!(!voice.take_loop_end());
+
+    // 0x4000 multiplies the pitch by 1.5: the 28 samples of the
+    // block are played in 19 cycles instead of 28
+    for _ in 0..18 {
+        voice.run(&ram, &regs, None, Some(0x4000));
+    }
+
+    assert!(voice.counter == 18 * 0x1800);
+    assert!(!voice.take_loop_end());
+
+    voice.run(&ram, &regs, None, Some(0x4000));
+
+    assert!(voice.take_loop_end());
+}
+
+#[test]
+fn gauss_table_weights() {
+    for i in 0..0x100 {
+        let sum =
+            GAUSS_TABLE[0xff - i] as i32 +
+            GAUSS_TABLE[0x1ff - i] as i32 +
+            GAUSS_TABLE[0x100 + i] as i32 +
+            GAUSS_TABLE[i] as i32;
+
+        assert!(sum >= 0x7f7f && sum <= 0x7f81);
+    }
+}
@@ -0,0 +1,178 @@
+//! `AudioSink` implementation writing the samples to a WAV file,
+//! useful to capture the audio output of headless runs.
+
+use std::io::{self, Write, Seek, SeekFrom};
+use std::fs::File;
+use std::path::Path;
+
+use super::sink::AudioSink;
+
+/// Sink writing 16bit stereo 44.1kHz PCM WAV data to `W`. The sizes
+/// in the header are only filled when `finish` is called. The WAV
+/// format can't describe more than 4GiB of data (about 6h45 of
+/// audio), the samples past that limit are dropped.
+pub struct WavSink<W: Write + Seek> {
+    writer: W,
+    /// Length of the sample data written so far, in bytes
+    data_len: u32,
+    /// True if the file has reached `MAX_DATA_LEN`
+    full: bool,
+    /// First write error encountered, if any. `push_samples` can't
+    /// return an error so we keep it for `finish`.
+    error: Option<io::Error>,
+}
+
+impl WavSink<io::BufWriter<File>> {
+    /// Create a new WAV file at `path`
+    pub fn create<P: AsRef<Path>>(path: P)
+                                  -> io::Result<WavSink<io::BufWriter<File>>> {
+        let file = try!(File::create(path));
+
+        WavSink::new(io::BufWriter::new(file))
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    /// Write the WAV header to `writer` and return the sink
+    pub fn new(mut writer: W) -> io::Result<WavSink<W>> {
+        // The sizes are patched by `finish`
+        try!(write_header(&mut writer, 0));
+
+        Ok(WavSink {
+            writer: writer,
+            data_len: 0,
+            full: false,
+            error: None,
+        })
+    }
+
+    /// Fill the sizes in the WAV header and return the writer. Also
+    /// returns the first error encountered while writing the samples,
+    /// if any.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        try!(self.writer.seek(SeekFrom::Start(0)));
+        try!(write_header(&mut self.writer, self.data_len));
+        try!(self.writer.seek(SeekFrom::End(0)));
+        try!(self.writer.flush());
+
+        Ok(self.writer)
+    }
+
+    fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let data_len =
+            match self.data_len.checked_add(samples.len() as u32 * 2) {
+                Some(l) if l <= MAX_DATA_LEN => l,
+                _ => {
+                    if !self.full {
+                        warn!("WAV size limit reached, dropping samples");
+                        self.full = true;
+                    }
+
+                    return Ok(());
+                }
+            };
+
+        let mut buf = Vec::with_capacity(samples.len() * 2);
+
+        for &s in samples {
+            buf.push(s as u8);
+            buf.push((s >> 8) as u8);
+        }
+
+        try!(self.writer.write_all(&buf));
+
+        self.data_len = data_len;
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn push_samples(&mut self, samples: &[i16]) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(e) = self.write_samples(samples) {
+            error!("Couldn't write WAV samples: {}", e);
+            self.error = Some(e);
+        }
+    }
+}
+
+/// Maximum length of the sample data: the RIFF chunk size is a
+/// 32bit value which also includes the 36 bytes of the header after
+/// it. Rounded down to a whole number of stereo frames.
+const MAX_DATA_LEN: u32 = (0xffff_ffff - 36) & !3;
+
+/// Write the 44 byte WAV header for `data_len` bytes of sample data
+fn write_header(w: &mut Write, data_len: u32) -> io::Result<()> {
+    let channels = 2;
+    let sample_rate = 44100;
+    let bytes_per_frame = channels * 2;
+
+    try!(w.write_all(b"RIFF"));
+    try!(write_u32(w, 36 + data_len));
+    try!(w.write_all(b"WAVE"));
+
+    try!(w.write_all(b"fmt "));
+    try!(write_u32(w, 16));
+    // PCM
+    try!(write_u16(w, 1));
+    try!(write_u16(w, channels as u16));
+    try!(write_u32(w, sample_rate));
+    try!(write_u32(w, sample_rate * bytes_per_frame));
+    try!(write_u16(w, bytes_per_frame as u16));
+    // Bits per sample
+    try!(write_u16(w, 16));
+
+    try!(w.write_all(b"data"));
+    write_u32(w, data_len)
+}
+
+fn write_u16(w: &mut Write, v: u16) -> io::Result<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8])
+}
+
+fn write_u32(w: &mut Write, v: u32) -> io::Result<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8])
+}
+
+#[test]
+fn wav_header() {
+    let mut sink = WavSink::new(io::Cursor::new(Vec::new())).unwrap();
+
+    sink.push_samples(&[1, -1, 0x1234, 0]);
+
+    let wav = sink.finish().unwrap().into_inner();
+
+    assert!(wav.len() == 44 + 8);
+    assert!(&wav[0..4] == b"RIFF");
+    assert!(&wav[4..8] == &[44, 0, 0, 0]);
+    assert!(&wav[40..44] == &[8, 0, 0, 0]);
+    assert!(&wav[44..] == &[1, 0, 0xff, 0xff, 0x34, 0x12, 0, 0]);
+}
+
+#[test]
+fn wav_size_limit() {
+    let mut sink = WavSink::new(io::Cursor::new(Vec::new())).unwrap();
+
+    sink.push_samples(&[1, 2]);
+
+    // Pretend we've been recording for a while
+    sink.data_len = MAX_DATA_LEN - 4;
+
+    sink.push_samples(&[3, 4, 5, 6]);
+
+    assert!(sink.full);
+    assert!(sink.data_len == MAX_DATA_LEN - 4);
+
+    let wav = sink.finish().unwrap().into_inner();
+
+    assert!(wav.len() == 44 + 4);
+    assert!(&wav[4..8] == &[0xf8, 0xff, 0xff, 0xff]);
+}
@@ -19,6 +19,10 @@ pub enum Peripheral {
     PadMemCard,
     /// CD-ROM controller
     CdRom,
+    /// Sound Processing Unit
+    Spu,
+    /// Serial port
+    Sio1,
 }
 
 
@@ -31,7 +35,7 @@ pub struct TimeKeeper {
     /// Next time a peripheral needs an update
     next_sync: Cycles,
     /// Time sheets for keeping track of the various peripherals
-    timesheets: [TimeSheet; 6],
+    timesheets: [TimeSheet; 8],
 }
 
 impl TimeKeeper {
@@ -40,7 +44,7 @@ impl TimeKeeper {
             now: 0,
             // Force a sync at the start to initialize evrything
             next_sync: 0,
-            timesheets: [TimeSheet::new(); 6],
+            timesheets: [TimeSheet::new(); 8],
         }
     }
 